@@ -0,0 +1,72 @@
+// Compares the ways of running the same programs: the stack VM one step() at a time (how
+// the debugger runs it), the stack VM's fast loop (VM::run) and the register VM. It shows how
+// many instructions each VM runs and how long each takes. Run it with "cargo bench".
+use c4::regvm::{RegProgram, RegisterVM};
+use c4::{compile_with, CompileOptions, HostFunctions, Program, RunConfig, VmState};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const PROGRAMS: &[(&str, &str)] = &[
+    ("fib", "int fib(int n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+             int main() { return fib(24) & 255; }"),
+    ("loops", "int main() { int i; int j; int s; s = 0; i = 0;
+               while (i < 300) { j = 0; while (j < 300) { s = s + (i ^ j) * 3; j++; } i++; }
+               return s & 255; }"),
+    ("sieve", "int main() { char *p; int i; int j; int n; int count;
+               n = 100000; p = malloc(n); memset(p, 1, n); count = 0; i = 2;
+               while (i < n) { if (p[i]) { count++; j = i + i; while (j < n) { p[j] = 0; j = j + i; } } i++; }
+               return count & 255; }"),
+];
+
+// The fastest of a few runs, which is the one least disturbed by everything else the machine does
+fn time(mut run: impl FnMut() -> (i64, u64)) -> (i64, u64, Duration) {
+    let mut best = Duration::MAX;
+    let mut result = (0, 0);
+    for _ in 0..5 {
+        let start = Instant::now();
+        result = black_box(run());
+        best = best.min(start.elapsed());
+    }
+    (result.0, result.1, best)
+}
+
+fn main() {
+    println!(
+        "{:<8} {:>12} {:>12} {:>9} {:>9} {:>9} {:>8} {:>8}",
+        "program", "stack cycles", "reg cycles", "step ms", "fast ms", "reg ms", "fast", "reg"
+    );
+    for (name, source) in PROGRAMS {
+        let program: Program = compile_with(source, &HostFunctions::new(), CompileOptions { optimize: true }).unwrap();
+        let code = RegProgram::from_program(&program, true).unwrap();
+
+        let (step_exit, stack_cycles, step_time) = time(|| {
+            let mut vm = program.vm(RunConfig::default()).unwrap();
+            let VmState::Halted(code) = vm.run_until(|_| false) else { panic!("{} didn't finish", name) };
+            (code, vm.cycle)
+        });
+        let (fast_exit, fast_cycles, fast_time) = time(|| {
+            let status = program.run(RunConfig::default()).unwrap();
+            (status.code, status.cycles)
+        });
+        let (reg_exit, reg_cycles, reg_time) = time(|| {
+            let mut vm = RegisterVM::new(&program, code.clone(), RunConfig::default()).unwrap();
+            (vm.run().unwrap(), vm.cycle)
+        });
+        assert_eq!((step_exit, stack_cycles), (fast_exit, fast_cycles), "{}: the fast loop did something else", name);
+        assert_eq!(fast_exit, reg_exit, "{}: the register VM gave a different result", name);
+
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let speedup = |d: Duration| step_time.as_secs_f64() / d.as_secs_f64();
+        println!(
+            "{:<8} {:>12} {:>12} {:>9.2} {:>9.2} {:>9.2} {:>7.1}x {:>7.1}x",
+            name,
+            stack_cycles,
+            reg_cycles,
+            ms(step_time),
+            ms(fast_time),
+            ms(reg_time),
+            speedup(fast_time),
+            speedup(reg_time)
+        );
+    }
+}
@@ -0,0 +1,589 @@
+// C4 assembly: a text form of VM programs. The assembler turns it into a Program that can
+// be run, and the disassembler turns any Program (like a compiled one) back into it, so
+// that assembling the disassembly gives back the same program.
+//
+// One statement per line, and everything after a ';' is a comment:
+//
+//     .entry main             ; where the program starts (the label main if not given)
+//     .import twice 39        ; a host function and its instruction number
+//     .text                   ; what follows is code (the default)
+//     sq:                     ; a label, names the place where the next statement goes
+//         ENT  0              ; instructions are written with their C4 names
+//         LEA  2              ; operands are numbers (-5, 0x10) or labels
+//         JMP  .L1            ; a code label stands for its place in the text
+//         IMM  .S1            ; a data label stands for its address
+//         twice               ; an imported host function is used like an instruction
+//         .word 7             ; a raw word of text
+//     .data                   ; what follows is data, laid out from the start of the data segment
+//     count:  .zero 8         ; 8 zero bytes
+//     .S1:    .asciz "hi\n"   ; a string and a zero byte after it (.ascii leaves the zero off)
+//             .byte 1, 2, 255 ; bytes
+//             .word .S1       ; an 8-byte word, a number or a label
+//
+// Labels that start with a '.' are only for jumping to. Other labels in the text are
+// functions, and other labels in the data are global variables.
+use crate::diagnostic::{Diagnostic, Diagnostics};
+use crate::lexer::Span;
+use crate::memory::DATA_BASE;
+use crate::opcode::Op;
+use crate::Program;
+use std::collections::HashMap;
+
+// Turn a program into assembly
+pub fn disassemble(program: &Program) -> String {
+    let text = &program.text;
+    let data_end = DATA_BASE + program.data.len() as i64;
+
+    // Functions and globals keep their names
+    let mut text_labels: HashMap<usize, String> = HashMap::new();
+    for (name, range) in &program.functions {
+        text_labels.insert(range.start, name.clone());
+    }
+    let mut data_labels: HashMap<i64, String> = HashMap::new();
+    for (name, address) in &program.globals {
+        data_labels.insert(*address, name.clone());
+    }
+
+    // Other jump targets get .L1, .L2, ... and other data addresses (the strings) .S1, .S2, ...
+    let mut targets = vec![program.entry as i64];
+    let mut addresses = Vec::new();
+    let mut pc = 0;
+    while pc < text.len() {
+        let (op, operand) = match Op::from_code(text[pc]) {
+            Some(op) if op.has_operand() && pc + 1 < text.len() => (op, text[pc + 1]),
+            _ => {
+                pc += 1;
+                continue;
+            }
+        };
+        match op {
+            Op::Jmp | Op::Jsr | Op::Bz | Op::Bnz if (0..=text.len() as i64).contains(&operand) => targets.push(operand),
+            Op::Imm if (DATA_BASE..data_end).contains(&operand) => addresses.push(operand),
+            _ => {}
+        }
+        pc += 2;
+    }
+    targets.sort();
+    targets.dedup();
+    let mut next = 1;
+    for target in targets {
+        text_labels.entry(target as usize).or_insert_with(|| {
+            next += 1;
+            format!(".L{}", next - 1)
+        });
+    }
+    addresses.sort();
+    addresses.dedup();
+    let mut next = 1;
+    for address in addresses {
+        data_labels.entry(address).or_insert_with(|| {
+            next += 1;
+            format!(".S{}", next - 1)
+        });
+    }
+
+    let mut out = format!(".entry {}\n", text_labels[&program.entry]);
+    for (name, code) in &program.imports {
+        out += &format!(".import {} {}\n", name, code);
+    }
+
+    out += "\n.text\n";
+    let mut pc = 0;
+    while pc <= text.len() {
+        if let Some(label) = text_labels.get(&pc) {
+            out += &format!("{}:\n", label);
+        }
+        if pc == text.len() {
+            break;
+        }
+        let code = text[pc];
+        let import = program.imports.iter().find(|(_, c)| *c == code);
+        match Op::from_code(code) {
+            Some(op) if op.has_operand() && pc + 1 < text.len() => {
+                let operand = text[pc + 1];
+                let label = match op {
+                    Op::Jmp | Op::Jsr | Op::Bz | Op::Bnz => usize::try_from(operand).ok().and_then(|t| text_labels.get(&t)),
+                    Op::Imm => data_labels.get(&operand),
+                    _ => None,
+                };
+                match label {
+                    Some(label) => out += &format!("    {:<4} {}\n", op, label),
+                    None => out += &format!("    {:<4} {}\n", op, operand),
+                }
+                pc += 2;
+                continue;
+            }
+            Some(op) if !op.has_operand() => out += &format!("    {}\n", op),
+            _ => match import {
+                Some((name, _)) => out += &format!("    {}\n", name),
+                None => out += &format!("    .word {}\n", code),
+            },
+        }
+        pc += 1;
+    }
+
+    if !program.data.is_empty() {
+        out += "\n.data\n";
+        let mut starts: Vec<usize> = data_labels.keys().map(|&address| (address - DATA_BASE) as usize).collect();
+        starts.push(0);
+        starts.sort();
+        starts.dedup();
+        for (i, &start) in starts.iter().enumerate() {
+            if let Some(label) = data_labels.get(&(DATA_BASE + start as i64)) {
+                out += &format!("{}:\n", label);
+            }
+            let end = starts.get(i + 1).copied().unwrap_or(program.data.len());
+            disassemble_bytes(&program.data[start..end], &mut out);
+        }
+    }
+    out
+}
+
+// Bytes that can go in a string as they are (or with a simple escape)
+fn is_text(byte: u8) -> bool {
+    byte == b'\n' || byte == b'\t' || (0x20..0x7f).contains(&byte)
+}
+
+fn quote(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &byte in bytes {
+        match byte {
+            b'\n' => out += "\\n",
+            b'\t' => out += "\\t",
+            b'"' => out += "\\\"",
+            b'\\' => out += "\\\\",
+            _ => out.push(byte as char),
+        }
+    }
+    out + "\""
+}
+
+// Write data as .zero for runs of zeros, .asciz/.ascii for text and .byte for the rest
+fn disassemble_bytes(bytes: &[u8], out: &mut String) {
+    let mut i = 0;
+    while i < bytes.len() {
+        let zeros = bytes[i..].iter().take_while(|&&b| b == 0).count();
+        if zeros > 0 {
+            *out += &format!("    .zero {}\n", zeros);
+            i += zeros;
+            continue;
+        }
+        let text = bytes[i..].iter().take_while(|&&b| is_text(b)).count();
+        if text > 0 && bytes.get(i + text) == Some(&0) {
+            *out += &format!("    .asciz {}\n", quote(&bytes[i..i + text]));
+            i += text + 1;
+        } else if text >= 4 {
+            *out += &format!("    .ascii {}\n", quote(&bytes[i..i + text]));
+            i += text;
+        } else {
+            let n = if text > 0 { text } else { bytes[i..].iter().take(16).take_while(|&&b| b != 0 && !is_text(b)).count() };
+            let list: Vec<String> = bytes[i..i + n].iter().map(|b| b.to_string()).collect();
+            *out += &format!("    .byte {}\n", list.join(", "));
+            i += n;
+        }
+    }
+}
+
+// A statement from the first pass, waiting for its labels to be known
+enum Statement {
+    Instruction(Op, Option<(String, Span)>), // An instruction and its operand
+    Host(String),                            // A host function used as an instruction
+    Word(String),                            // .word
+    Bytes(Vec<u8>),                          // .byte, .ascii, .asciz and .zero
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Section {
+    Text,
+    Data,
+}
+
+// Where a label is: an index in the text or an offset in the data
+#[derive(Clone, Copy)]
+enum Place {
+    Text(usize),
+    Data(usize),
+}
+
+// The column (1-based) where part starts in line. part must be a slice of line.
+fn col(line: &str, part: &str) -> usize {
+    part.as_ptr() as usize - line.as_ptr() as usize + 1
+}
+
+// Cut off the comment, if there is one (a ';' that isn't inside a string)
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn is_label_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+fn parse_number(s: &str) -> Option<i64> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let value = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i64>().ok()?,
+    };
+    Some(if negative { value.wrapping_neg() } else { value })
+}
+
+// The bytes of a quoted string, with its escapes turned into the bytes they stand for
+fn parse_string(s: &str) -> Result<Vec<u8>, String> {
+    let Some(inner) = s.strip_prefix('"').and_then(|s| s.strip_suffix('"')).filter(|_| s.len() >= 2) else {
+        return Err("string expected, in double quotes".to_string());
+    };
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('r') => bytes.push(b'\r'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('"') => bytes.push(b'"'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16).map_err(|_| format!("bad escape \\x{}", hex))?;
+                bytes.push(byte);
+            }
+            other => return Err(format!("bad escape \\{}", other.map_or(String::new(), String::from))),
+        }
+    }
+    Ok(bytes)
+}
+
+// Turn assembly into a program. Gives back every error found if it can't.
+pub fn assemble(source: &str) -> Result<Program, Diagnostics> {
+    let mut errors = Vec::new();
+    let mut statements: Vec<(Section, Span, Statement)> = Vec::new();
+    let mut labels: HashMap<String, Place> = HashMap::new();
+    let mut label_order: Vec<String> = Vec::new();
+    let mut imports: Vec<(String, i64)> = Vec::new();
+    let mut entry: Option<(String, Span)> = None;
+    let mut section = Section::Text;
+    let (mut text_len, mut data_len) = (0, 0);
+
+    // First pass: read every line and find out where each label is
+    for (number, line) in source.lines().enumerate() {
+        let code = strip_comment(line);
+        let mut rest = code.trim();
+        let mut span = Span { line: number + 1, col: col(line, rest) };
+
+        let name_len = rest.find(|c: char| !is_label_char(c)).unwrap_or(rest.len());
+        if name_len > 0 && rest[name_len..].starts_with(':') {
+            let name = rest[..name_len].to_string();
+            let place = match section {
+                Section::Text => Place::Text(text_len),
+                Section::Data => Place::Data(data_len),
+            };
+            if labels.insert(name.clone(), place).is_some() {
+                errors.push(Diagnostic::error(format!("label '{}' is defined twice", name), span));
+            }
+            label_order.push(name);
+            rest = rest[name_len + 1..].trim_start();
+            span.col = col(line, rest);
+        }
+        if rest.is_empty() {
+            continue;
+        }
+
+        let (word, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let args = args.trim();
+        let args_span = Span { line: span.line, col: if args.is_empty() { span.col } else { col(line, args) } };
+        let mut error = |message: String| errors.push(Diagnostic::error(message, span));
+        let statement = match word {
+            ".text" => {
+                section = Section::Text;
+                continue;
+            }
+            ".data" => {
+                section = Section::Data;
+                continue;
+            }
+            ".entry" if !args.is_empty() => {
+                entry = Some((args.to_string(), args_span));
+                continue;
+            }
+            ".import" => {
+                match args.split_whitespace().collect::<Vec<_>>()[..] {
+                    [name, code] if parse_number(code).is_some() => imports.push((name.to_string(), parse_number(code).unwrap())),
+                    _ => error(".import needs a name and an instruction number".to_string()),
+                }
+                continue;
+            }
+            ".word" if !args.is_empty() => Statement::Word(args.to_string()),
+            ".zero" if section == Section::Data => match args.parse::<usize>() {
+                Ok(n) => Statement::Bytes(vec![0; n]),
+                Err(_) => {
+                    error(".zero needs a number of bytes".to_string());
+                    continue;
+                }
+            },
+            ".byte" if section == Section::Data => {
+                let mut bytes = Vec::new();
+                for arg in args.split(|c: char| c == ',' || c.is_whitespace()).filter(|a| !a.is_empty()) {
+                    match parse_number(arg).filter(|n| (-128..=255).contains(n)) {
+                        Some(n) => bytes.push(n as u8),
+                        None => error(format!("'{}' is not a byte", arg)),
+                    }
+                }
+                Statement::Bytes(bytes)
+            }
+            ".ascii" | ".asciz" if section == Section::Data => match parse_string(args) {
+                Ok(mut bytes) => {
+                    if word == ".asciz" {
+                        bytes.push(0);
+                    }
+                    Statement::Bytes(bytes)
+                }
+                Err(message) => {
+                    error(message);
+                    continue;
+                }
+            },
+            _ if word.starts_with('.') => {
+                error(format!("unknown directive '{}' here", word));
+                continue;
+            }
+            _ if section == Section::Data => {
+                error(format!("'{}' is code, but this is the .data section", word));
+                continue;
+            }
+            _ => match Op::from_name(word) {
+                Some(op) if op.has_operand() => {
+                    if args.is_empty() {
+                        error(format!("{} needs an operand", op));
+                        continue;
+                    }
+                    Statement::Instruction(op, Some((args.to_string(), args_span)))
+                }
+                Some(op) if !args.is_empty() => {
+                    error(format!("{} takes no operand", op));
+                    continue;
+                }
+                Some(op) => Statement::Instruction(op, None),
+                None => Statement::Host(word.to_string()),
+            },
+        };
+
+        match &statement {
+            Statement::Instruction(op, _) => text_len += if op.has_operand() { 2 } else { 1 },
+            Statement::Host(_) => text_len += 1,
+            Statement::Word(_) if section == Section::Text => text_len += 1,
+            Statement::Word(_) => data_len += 8,
+            Statement::Bytes(bytes) => data_len += bytes.len(),
+        }
+        statements.push((section, span, statement));
+    }
+
+    // Second pass: now every label is known, make the text and data
+    let value = |operand: &str, span: Span, errors: &mut Vec<Diagnostic>| -> i64 {
+        if let Some(n) = parse_number(operand) {
+            return n;
+        }
+        match labels.get(operand) {
+            Some(Place::Text(index)) => *index as i64,
+            Some(Place::Data(offset)) => DATA_BASE + *offset as i64,
+            None => {
+                errors.push(Diagnostic::error(format!("unknown label '{}'", operand), span));
+                0
+            }
+        }
+    };
+    let mut program = Program::default();
+    for (section, span, statement) in &statements {
+        match statement {
+            Statement::Instruction(op, operand) => {
+                program.text.push(op.code());
+                if let Some((operand, operand_span)) = operand {
+                    program.text.push(value(operand, *operand_span, &mut errors));
+                }
+            }
+            Statement::Host(name) => match imports.iter().find(|(n, _)| n == name) {
+                Some((_, code)) => program.text.push(*code),
+                None => {
+                    errors.push(Diagnostic::error(format!("unknown instruction '{}'", name), *span));
+                    program.text.push(0);
+                }
+            },
+            Statement::Word(operand) => {
+                let word = value(operand, *span, &mut errors);
+                match section {
+                    Section::Text => program.text.push(word),
+                    Section::Data => program.data.extend_from_slice(&word.to_le_bytes()),
+                }
+            }
+            Statement::Bytes(bytes) => program.data.extend_from_slice(bytes),
+        }
+        program.spans.resize(program.text.len(), *span);
+    }
+
+    // Labels without a dot are the program's functions and globals
+    let mut function_starts = Vec::new();
+    for name in label_order.iter().filter(|name| !name.starts_with('.')) {
+        match labels[name] {
+            Place::Text(index) => function_starts.push((index, name.clone())),
+            Place::Data(offset) => program.globals.push((name.clone(), DATA_BASE + offset as i64)),
+        }
+    }
+    function_starts.sort();
+    for (i, (start, name)) in function_starts.iter().enumerate() {
+        let end = function_starts.get(i + 1).map_or(program.text.len(), |(next, _)| *next);
+        program.functions.push((name.clone(), *start..end));
+    }
+    program.globals.sort_by_key(|&(_, address)| address);
+    imports.sort_by_key(|&(_, code)| code);
+    program.imports = imports;
+
+    let (entry, entry_span) = entry.unwrap_or(("main".to_string(), Span { line: 1, col: 1 }));
+    match parse_number(&entry).map(|n| Place::Text(n as usize)).or_else(|| labels.get(&entry).copied()) {
+        Some(Place::Text(index)) => program.entry = index,
+        _ => errors.push(Diagnostic::error(format!("entry point '{}' is not a code label", entry), entry_span)),
+    }
+
+    if errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(Diagnostics { list: errors })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile;
+    use crate::host::SharedBuffer;
+    use crate::RunConfig;
+
+    #[test]
+    fn test_assemble_and_run() {
+        let source = r#"
+            ; print a greeting and return 3 * 4
+            .text
+            main:
+                ENT  0
+                IMM  .hello    ; printf(hello, 3)
+                PSH
+                IMM  3
+                PSH
+                PRTF
+                ADJ  2
+                IMM  3
+                PSH
+                IMM  4
+                MUL
+                LEV
+            .data
+            .hello: .asciz "hi %d; bye\n"
+        "#;
+        let program = assemble(source).unwrap();
+        assert_eq!(program.functions, vec![("main".to_string(), 0..18)]);
+        assert_eq!(program.spans[2].line, 6);
+
+        let output = SharedBuffer::new();
+        let status = program.run(RunConfig { output: Some(Box::new(output.clone())), ..RunConfig::default() }).unwrap();
+        assert_eq!(status.code, 12);
+        assert_eq!(output.text(), "hi 3; bye\n");
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let program = compile("char *s; int main() { s = \"ok\"; while (*s) s = s + 1; return 0; }").unwrap();
+        let expected = "\
+.entry main
+
+.text
+main:
+    ENT  0
+    IMM  s
+    PSH
+    IMM  .S1
+    SI
+.L1:
+    IMM  s
+    LI
+    LC
+    BZ   .L2
+    IMM  s
+    PSH
+    IMM  s
+    LI
+    PSH
+    IMM  1
+    ADD
+    SI
+    JMP  .L1
+.L2:
+    IMM  0
+    LEV
+    LEV
+
+.data
+s:
+    .zero 8
+.S1:
+    .asciz \"ok\"
+    .zero 5
+";
+        assert_eq!(disassemble(&program), expected);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let source = "
+            enum { A = 5 };
+            int count; char *name;
+            int sq(int x) { return x * x; }
+            int main(int argc, char **argv) {
+                int i;
+                name = \"tab\\there \\\"quoted\\\"\\n\";
+                i = 0;
+                while (i < A) { if (i & 1) count = count + sq(i); i++; }
+                printf(\"%s%d\\n\", name, count);
+                return count > 9 ? count : -1;
+            }";
+        let program = compile(source).unwrap();
+        let text = disassemble(&program);
+        let assembled = assemble(&text).unwrap();
+        assert_eq!((&assembled.text, &assembled.data, assembled.entry), (&program.text, &program.data, program.entry));
+        assert_eq!((&assembled.functions, &assembled.globals), (&program.functions, &program.globals));
+        assert_eq!(disassemble(&assembled), text);
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let errors = assemble("main:\n  IMM nowhere\n  JMP\n  FLY\n.data\n  .byte 300\n").unwrap_err();
+        let messages: Vec<String> = errors.list.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Syntax Error: JMP needs an operand at line 3, col 3",
+                "Syntax Error: '300' is not a byte at line 6, col 3",
+                "Syntax Error: unknown label 'nowhere' at line 2, col 7",
+                "Syntax Error: unknown instruction 'FLY' at line 4, col 3",
+            ]
+        );
+        assert!(assemble("start: LEV").is_err()); // no main and no .entry
+    }
+}
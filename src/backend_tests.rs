@@ -0,0 +1,87 @@
+// Test helpers for the backends that turn a program into something the host runs (native.rs
+// and csource.rs): build it, run it, and check it prints and exits like the VM.
+use crate::{compile_with, CompileOptions, HostFunctions, RunConfig, SharedBuffer};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Programs every backend must run the same way as the VM. They are run with the argument "arg".
+pub(crate) const SAME_AS_VM: [&str; 7] = [
+    "int fib(int n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+     int main() { printf(\"%d\\n\", fib(20)); return fib(10); }",
+    "int main(int argc, char **argv) { while (argc--) printf(\"[%s]\", *argv++); printf(\"\\n\"); return argc; }",
+    "int main() {
+       printf(\"%5d|%-5d|%05d|%+d|% d|%x|%#X|%o|%#o|%p|%c|%.2s|%8.3s|%-4s|%%|%u|%.0d|%.4d|%q\\n\",
+              42, 42, -42, 7, 7, 255, 255, 8, 8, 16, 'z', \"hello\", \"hello\", \"ab\", -1, 0, 7);
+       printf(\"%*d|%-*d|%.*s|%ld\\n\", 4, 1, 4, 2, 3, \"abcdef\", -9223372036854775807 - 1);
+       return printf(\"\"); }",
+    "int main() { char *p; char *q; int *r;
+       p = malloc(10); q = malloc(20); free(p); r = malloc(8);
+       printf(\"%d %d\\n\", r == p, q - p);
+       memset(q, 'x', 19); q[19] = 0; printf(\"%s %d %d\\n\", q, memcmp(q, \"xxa\", 3), memcmp(\"ab\", \"ab\", 2));
+       return -1 / 2 + 7 % -3 + (-9223372036854775807 - 1) / -1 + (-3 >> 1) + (1 << 65); }",
+    "int main() { int fd; fd = open(\"/nonexistent/file\", 0); printf(\"%d %d\\n\", fd, close(fd)); return 3; }",
+    "int main() { char c; c = 200; printf(\"%d %p\\n\", c, &c); return c; }",
+    "enum { A, B = 5, C }; int g; char *s; int *q;
+     int bump() { g = g + 10; return 1; }
+     int none(int x) { x * 2; }
+     int main() { char c; int i; int *p;
+       p = q = malloc(4 * sizeof(int));
+       i = 0; while (i < 4) { p[i] = i * C; i++; }
+       c = 127; c++; g = 1; i = g + bump();
+       printf(\"%d %d %d %d %d\\n\", c, i, *(p + 3), &p[3] - p, none(21));
+       s = \"ab\" \"cd\";
+       printf(\"%s %d %d %d %d\\n\", s, 2 || 0, 0 && 1, (g = 3) + g, !s + ~1 + -i);
+       return ++*p + p[1]-- + p[1] + (c ? A : B); }",
+];
+
+// Programs that fault in the VM, and must stop the same way (exit status 255)
+pub(crate) const FAULTS: [&str; 4] = [
+    "int main() { int x; x = 0; printf(\"before\\n\"); return 5 / x; }",
+    "int main() { return *(int *)0; }",
+    "int f(int n) { return f(n + 1); } int main() { return f(0); }",
+    "int main() { free((char *)12); return 0; }",
+];
+
+// Numbers the files the tests make, so tests running at once don't share one
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+// A new path in the temporary directory
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("c4_{}_{}_{}", name, std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)))
+}
+
+// Are the host's tools that a test needs installed? If not the test fails, unless
+// C4_SKIP_TOOL_TESTS is set, in which case it says it was skipped and gives back false.
+pub(crate) fn have_tools(test: &str, tools: &[&str]) -> bool {
+    let missing: Vec<&str> = tools.iter().copied().filter(|tool| Command::new(tool).arg("--version").output().is_err()).collect();
+    if missing.is_empty() {
+        return true;
+    }
+    if env::var_os("C4_SKIP_TOOL_TESTS").is_none() {
+        panic!("{} needs {} (set C4_SKIP_TOOL_TESTS=1 to skip it)", test, missing.join(" and "));
+    }
+    eprintln!("skipped {}: {} not found", test, missing.join(" and "));
+    false
+}
+
+// Build source into an executable with build, run it with the argument "arg", and check it
+// prints the same and exits the same as the VM, with and without -O
+pub(crate) fn check(source: &str, build: impl Fn(&str, &Path) -> Result<(), String>) {
+    let path = temp_path("test");
+    build(source, &path).unwrap_or_else(|err| panic!("{}\n{}", err, source));
+    let run = Command::new(&path).arg("arg").output().unwrap();
+    let _ = fs::remove_file(&path);
+
+    for optimize in [false, true] {
+        let program = compile_with(source, &HostFunctions::new(), CompileOptions { optimize }).unwrap();
+        let output = SharedBuffer::new();
+        let args = vec![path.display().to_string(), "arg".to_string()];
+        let config = RunConfig { args, output: Some(Box::new(output.clone())), ..RunConfig::default() };
+        let code = program.run(config).map_or(255, |status| status.code & 255);
+        assert_eq!(String::from_utf8_lossy(&run.stdout), output.text(), "{}", source);
+        assert_eq!(run.status.code(), Some(code as i32), "{}", source);
+    }
+}
@@ -0,0 +1,328 @@
+// The .c4b file format: a compiled program saved to disk, so it can be run without
+// compiling it again.
+//
+// Everything is little-endian. The file is:
+//
+//     magic     4 bytes  "C4B" followed by a zero byte
+//     version   u32      FORMAT_VERSION
+//     entry     u64      where main() starts in the text
+//     text      u64 count, then count i64 words
+//     data      u64 length, then length bytes
+//     symbols   u64 count, then for each: u8 kind, u32 name length, the name, i64 value, i64 end
+//     lines     u64 count (0 or the number of text words), then a u32 line and u32 col for each word
+//     checksum  u64      FNV-1a hash of every byte before it
+//
+// A symbol's kind is 0 for a function (value..end is its code), 1 for a global variable
+// (value is its address) and 2 for a host function (value is its instruction number).
+use crate::host::FIRST_HOST_CODE;
+use crate::lexer::Span;
+use crate::memory::{MemoryConfig, DATA_BASE};
+use crate::opcode::Op;
+use crate::Program;
+use std::fmt;
+
+pub const MAGIC: [u8; 4] = *b"C4B\0";
+// Version 2 added the ADDI and LLI instructions, which moved the host function numbers up
+pub const FORMAT_VERSION: u32 = 2;
+
+const FUNCTION: u8 = 0;
+const GLOBAL: u8 = 1;
+const HOST: u8 = 2;
+
+// Why a file could not be loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    NotBytecode,                 // The file doesn't start with the magic bytes
+    UnsupportedVersion(u32),     // The file was written by a different version of the format
+    Truncated(&'static str),     // The file ends in the middle of this section
+    ChecksumMismatch,            // The bytes were changed after the file was written
+    TrailingBytes,               // There is more after the checksum
+    Invalid(String),             // The sections don't make a program that can run
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "not a c4 bytecode file"),
+            LoadError::UnsupportedVersion(version) => {
+                write!(f, "bytecode version {} is not supported (expected {})", version, FORMAT_VERSION)
+            }
+            LoadError::Truncated(section) => write!(f, "file is truncated in the {} section", section),
+            LoadError::ChecksumMismatch => write!(f, "checksum mismatch, the file is corrupted"),
+            LoadError::TrailingBytes => write!(f, "unexpected bytes after the checksum"),
+            LoadError::Invalid(message) => write!(f, "invalid program: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+// Does this look like a bytecode file (rather than C source)?
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+// 64-bit FNV-1a, small and good enough to notice damaged files
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+fn put_name(out: &mut Vec<u8>, kind: u8, name: &str, value: i64, end: i64) {
+    out.push(kind);
+    out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    out.extend_from_slice(name.as_bytes());
+    out.extend_from_slice(&value.to_le_bytes());
+    out.extend_from_slice(&end.to_le_bytes());
+}
+
+// Reads the file front to back, saying which section it was in if the bytes run out
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    section: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        if self.bytes.len() - self.pos < len {
+            return Err(LoadError::Truncated(self.section));
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, LoadError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64, LoadError> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    // A count of things that are at least size bytes each, checked against what is left
+    // so a damaged count can't make us allocate a huge vector
+    fn count(&mut self, size: usize) -> Result<usize, LoadError> {
+        let count = self.u64()?;
+        let left = (self.bytes.len() - self.pos) as u64;
+        if count > left / size as u64 {
+            return Err(LoadError::Truncated(self.section));
+        }
+        Ok(count as usize)
+    }
+}
+
+impl Program {
+    // Write the program in the .c4b format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        out.extend_from_slice(&(self.entry as u64).to_le_bytes());
+
+        out.extend_from_slice(&(self.text.len() as u64).to_le_bytes());
+        for word in &self.text {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+
+        out.extend_from_slice(&(self.data.len() as u64).to_le_bytes());
+        out.extend_from_slice(&self.data);
+
+        let count = self.functions.len() + self.globals.len() + self.imports.len();
+        out.extend_from_slice(&(count as u64).to_le_bytes());
+        for (name, range) in &self.functions {
+            put_name(&mut out, FUNCTION, name, range.start as i64, range.end as i64);
+        }
+        for (name, address) in &self.globals {
+            put_name(&mut out, GLOBAL, name, *address, 0);
+        }
+        for (name, code) in &self.imports {
+            put_name(&mut out, HOST, name, *code, 0);
+        }
+
+        out.extend_from_slice(&(self.spans.len() as u64).to_le_bytes());
+        for span in &self.spans {
+            out.extend_from_slice(&(span.line as u32).to_le_bytes());
+            out.extend_from_slice(&(span.col as u32).to_le_bytes());
+        }
+
+        let sum = checksum(&out);
+        out.extend_from_slice(&sum.to_le_bytes());
+        out
+    }
+
+    // Read a program written by to_bytes, checking that it is whole and makes sense
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, LoadError> {
+        if !is_bytecode(bytes) {
+            return Err(LoadError::NotBytecode);
+        }
+        let mut reader = Reader { bytes, pos: MAGIC.len(), section: "header" };
+        let version = reader.u32()?;
+        if version != FORMAT_VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+        let entry = reader.u64()? as usize;
+
+        reader.section = "text";
+        let count = reader.count(8)?;
+        let mut text = Vec::with_capacity(count);
+        for _ in 0..count {
+            text.push(reader.i64()?);
+        }
+
+        reader.section = "data";
+        let len = reader.count(1)?;
+        let data = reader.take(len)?.to_vec();
+
+        reader.section = "symbol";
+        let mut program = Program { text, data, entry, ..Program::default() };
+        for _ in 0..reader.count(21)? {
+            let kind = reader.u8()?;
+            let len = reader.u32()? as usize;
+            let name = String::from_utf8(reader.take(len)?.to_vec())
+                .map_err(|_| LoadError::Invalid("a symbol name is not UTF-8".to_string()))?;
+            let value = reader.i64()?;
+            let end = reader.i64()?;
+            match kind {
+                FUNCTION => program.functions.push((name, value as usize..end as usize)),
+                GLOBAL => program.globals.push((name, value)),
+                HOST => program.imports.push((name, value)),
+                _ => return Err(LoadError::Invalid(format!("unknown symbol kind {}", kind))),
+            }
+        }
+
+        reader.section = "line table";
+        for _ in 0..reader.count(8)? {
+            let line = reader.u32()? as usize;
+            let col = reader.u32()? as usize;
+            program.spans.push(Span { line, col });
+        }
+
+        reader.section = "checksum";
+        let end = reader.pos;
+        if reader.u64()? != checksum(&bytes[..end]) {
+            return Err(LoadError::ChecksumMismatch);
+        }
+        if reader.pos != bytes.len() {
+            return Err(LoadError::TrailingBytes);
+        }
+
+        program.validate().map_err(LoadError::Invalid)?;
+        Ok(program)
+    }
+
+    // Check that the program can't send the VM somewhere that isn't there:
+    // every instruction is known, operands are present and jumps land inside the text
+    fn validate(&self) -> Result<(), String> {
+        let len = self.text.len();
+        if self.entry >= len {
+            return Err(format!("entry point {} is outside the text ({} words)", self.entry, len));
+        }
+        if !self.spans.is_empty() && self.spans.len() != len {
+            return Err(format!("line table has {} entries for {} words of text", self.spans.len(), len));
+        }
+        for (name, range) in &self.functions {
+            if range.start > range.end || range.end > len {
+                return Err(format!("function {} is outside the text", name));
+            }
+        }
+        let data_size = MemoryConfig::default().data_size;
+        if self.data.len() > data_size {
+            return Err(format!("data is {} bytes but the data segment only holds {}", self.data.len(), data_size));
+        }
+        let data_end = DATA_BASE + self.data.len() as i64;
+        for (name, address) in &self.globals {
+            if !(DATA_BASE..data_end).contains(address) {
+                return Err(format!("global {} is outside the data", name));
+            }
+        }
+
+        let mut pc = 0;
+        while pc < len {
+            let code = self.text[pc];
+            match Op::from_code(code) {
+                Some(op) if op.has_operand() => {
+                    let Some(&operand) = self.text.get(pc + 1) else {
+                        return Err(format!("{} at {} is missing its operand", op, pc));
+                    };
+                    let jumps = matches!(op, Op::Jmp | Op::Jsr | Op::Bz | Op::Bnz);
+                    if jumps && !(0..len as i64).contains(&operand) {
+                        return Err(format!("{} at {} jumps outside the text to {}", op, pc, operand));
+                    }
+                    pc += 2;
+                }
+                Some(_) => pc += 1,
+                None if code >= FIRST_HOST_CODE && self.imports.iter().any(|(_, c)| *c == code) => pc += 1,
+                None => return Err(format!("unknown instruction {} at {}", code, pc)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile;
+
+    const SOURCE: &str = "int n; char *s; int sq(int x) { return x * x; } int main() { n = sq(7); s = \"ok\"; return n; }";
+
+    #[test]
+    fn test_round_trip() {
+        let program = compile(SOURCE).unwrap();
+        let bytes = program.to_bytes();
+        assert!(is_bytecode(&bytes));
+        let loaded = Program::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded, program);
+        assert_eq!(loaded.run(Default::default()).unwrap().code, 49);
+        assert_eq!(loaded.function_range("sq").map(|range| range.start), Some(0));
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        let bytes = compile(SOURCE).unwrap().to_bytes();
+
+        assert_eq!(Program::from_bytes(b"int main() {}"), Err(LoadError::NotBytecode));
+        for len in 4..bytes.len() {
+            assert!(matches!(Program::from_bytes(&bytes[..len]), Err(LoadError::Truncated(_))), "length {}", len);
+        }
+
+        let mut corrupted = bytes.clone();
+        corrupted[30] ^= 1;
+        assert_eq!(Program::from_bytes(&corrupted), Err(LoadError::ChecksumMismatch));
+
+        let mut newer = bytes.clone();
+        newer[4] = 3;
+        assert_eq!(Program::from_bytes(&newer), Err(LoadError::UnsupportedVersion(3)));
+
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(Program::from_bytes(&longer), Err(LoadError::TrailingBytes));
+
+        // A well-formed file whose program jumps nowhere is still refused
+        let mut program = compile(SOURCE).unwrap();
+        program.text[0] = Op::Jmp.code(); // The first instruction is sq's ENT, make it a JMP
+        program.text[1] = 1000;
+        let error = Program::from_bytes(&program.to_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "invalid program: JMP at 0 jumps outside the text to 1000");
+
+        // So is one with more data than the data segment holds
+        let mut program = compile(SOURCE).unwrap();
+        program.data.resize(MemoryConfig::default().data_size + 1, 0);
+        let error = Program::from_bytes(&program.to_bytes()).unwrap_err();
+        assert!(error.to_string().starts_with("invalid program: data is 262145 bytes"), "{}", error);
+    }
+}
@@ -0,0 +1,746 @@
+// C source (--c-source). A program is written out as one standalone C file that does what the
+// VM would do, for checking the compiler against the system's C compiler.
+//
+// The C is made from the source, not from the compiled code. The source is read again with the
+// lexer and the parser's grammar and rules: what each name means, the type of each expression,
+// pointer scaling, and where globals and strings go in the data segment. Each function becomes
+// a C function, each statement a C statement and each expression one C expression on int64_t
+// values. So a mistake in the parser or in the code it makes shows up as a difference between
+// the VM and the C program.
+//
+// Memory is the VM's, from runtime.c. Arguments and locals live in stack frames at the same
+// addresses as in the VM. Loads, stores, the operators and the system calls work the way the
+// VM does them, so the file gives the same output and exit code on any C compiler. What's
+// different: there are no cycle counts or --max-* limits, and host functions can't be called.
+// A few things C4 only allows by accident, like assigning to a ?:, are refused.
+use crate::lexer::Lexer;
+use crate::memory::{MemoryConfig, DATA_BASE, HEAP_BASE, STACK_TOP};
+use crate::parser::{get_precedence, Symbol};
+use crate::token::{Class, Token, Type};
+use crate::vm::SYSCALLS;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+// Turn a C program into one C file, the runtime included. The program has to compile first,
+// so errors in it are reported the way the compiler reports them.
+pub fn c_source(source: &str) -> Result<String, String> {
+    crate::compile(source).map_err(|diagnostics| diagnostics.to_string())?;
+    let mut translator = Translator::new(source);
+    translator.program()?;
+
+    let config = MemoryConfig::default();
+    let mut out = String::new();
+    writeln!(out, "// Made by c4 from C source. Build it with: cc -o prog prog.c").unwrap();
+    writeln!(out, "#define DATA_BASE {:#x}", DATA_BASE).unwrap();
+    writeln!(out, "#define HEAP_BASE {:#x}", HEAP_BASE).unwrap();
+    writeln!(out, "#define STACK_TOP {:#x}", STACK_TOP).unwrap();
+    writeln!(out, "#define DATA_SIZE {}", config.data_size).unwrap();
+    writeln!(out, "#define HEAP_SIZE {}", config.heap_size).unwrap();
+    writeln!(out, "#define STACK_SIZE {}", config.stack_size).unwrap();
+    out += "\n// The data segment: globals and string literals\nstatic unsigned char data[DATA_SIZE] = {";
+    for (i, byte) in translator.data.iter().enumerate() {
+        if i % 16 == 0 {
+            out += "\n   ";
+        }
+        write!(out, " {},", byte).unwrap();
+    }
+    out += "\n};\n\n";
+    out += include_str!("runtime.c");
+    out += "\n// The program's functions\n";
+    out += &translator.functions;
+    out += "\nint main(int argc, char **argv) {\n    start(argc, argv);\n    a = f_main();\n    push(a); // Like the VM's exit stub: PSH, EXIT\n    sys_exit();\n}\n";
+    Ok(out)
+}
+
+// An expression as C, with what the parser would know about it
+struct Expr {
+    code: String,            // The C expression for its value
+    ty: Type,                // Its type, by the parser's rules
+    address: Option<String>, // Where the value was loaded from, if it can be assigned to (the parser's LI or LC)
+    effects: bool,           // Does working it out store, call a function or do I/O?
+}
+
+impl Expr {
+    fn new(code: String, ty: Type, effects: bool) -> Expr {
+        Expr { code, ty, address: None, effects }
+    }
+
+    // The value at an address: chars are loaded like LC, everything else like LI
+    fn load(address: String, ty: Type, effects: bool) -> Expr {
+        let code = format!("{}({})", load(&ty), address);
+        Expr { code, ty, address: Some(address), effects }
+    }
+
+    // Is it a plain number, which is the same whenever it is worked out?
+    fn constant(&self) -> bool {
+        self.code.parse::<i64>().is_ok()
+    }
+}
+
+// Reads the source the way the parser does and writes C instead of instructions
+struct Translator<'a> {
+    lexer: Lexer<'a>,                        // Where we get tokens from
+    current_token: Option<Token>,            // The current token we're looking at
+    symbols: HashMap<String, Symbol>,        // Every name we know about, like the parser's
+    shadowed: Vec<(String, Option<Symbol>)>, // Names hidden by the current function's locals
+    data: Vec<u8>,                           // The data segment, laid out like the parser's
+    loc: i64,                                // The slot number where the current function's locals start
+    temporaries: usize,                      // How many temporaries the current function uses
+    functions: String,                       // The C functions written so far
+}
+
+impl<'a> Translator<'a> {
+    fn new(source: &'a str) -> Self {
+        let mut lexer = Lexer::new(source);
+        let current_token = lexer.next_token();
+        let mut symbols = HashMap::new();
+        for (name, op) in SYSCALLS {
+            symbols.insert(name.to_string(), Symbol { class: Class::Sys, ty: Type::Int, val: op.code() });
+        }
+        Translator { lexer, current_token, symbols, shadowed: Vec::new(), data: Vec::new(), loc: 0, temporaries: 0, functions: String::new() }
+    }
+
+    fn advance(&mut self) {
+        self.current_token = self.lexer.next_token();
+    }
+
+    fn at(&self, token: Token) -> bool {
+        self.current_token == Some(token)
+    }
+
+    // Something that can't be written as C, with where it is
+    fn error<T>(&self, message: &str) -> Result<T, String> {
+        Err(format!("{} at line {}, col {}", message, self.lexer.start.line, self.lexer.start.col))
+    }
+
+    fn expect(&mut self, token: Token, message: &str) -> Result<(), String> {
+        if !self.at(token) {
+            return self.error(message);
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn take_id(&mut self) -> Result<String, String> {
+        match self.current_token.clone() {
+            Some(Token::Id(name)) => {
+                self.advance();
+                Ok(name)
+            }
+            _ => self.error("name expected"),
+        }
+    }
+
+    // A base type, int if there is none, and the stars after it
+    fn parse_type(&mut self) -> Type {
+        let mut ty = Type::Int;
+        if self.at(Token::Int) {
+            self.advance();
+        } else if self.at(Token::Char) {
+            self.advance();
+            ty = Type::Char;
+        }
+        self.parse_pointers(ty)
+    }
+
+    fn parse_pointers(&mut self, mut ty: Type) -> Type {
+        while self.at(Token::Mul) {
+            self.advance();
+            ty = ty.ptr_to();
+        }
+        ty
+    }
+
+    // A new temporary of the current function
+    fn temporary(&mut self) -> String {
+        self.temporaries += 1;
+        format!("t{}", self.temporaries)
+    }
+
+    // The two sides of an operator, the left worked out first like in the VM. C doesn't say
+    // which argument of a call is worked out first, so if either side changes anything the left
+    // is saved in a temporary first. Gives back what to put in front and the left side to use.
+    fn in_order(&mut self, left: &Expr, right: &Expr) -> (String, String) {
+        if (left.effects || right.effects) && !left.constant() && !right.constant() {
+            let temporary = self.temporary();
+            (format!("{} = {}, ", temporary, left.code), temporary)
+        } else {
+            (String::new(), left.code.clone())
+        }
+    }
+
+    // An address to use twice, saved in a temporary if working it out changes anything
+    fn once(&mut self, address: String, effects: bool) -> (String, String) {
+        if effects {
+            let temporary = self.temporary();
+            (format!("{} = {}, ", temporary, address), temporary)
+        } else {
+            (String::new(), address)
+        }
+    }
+
+    // The whole program: enums, global variables and functions, like Parser::parse_program
+    fn program(&mut self) -> Result<(), String> {
+        while !self.at(Token::Eof) {
+            let mut base = Type::Int;
+            if self.at(Token::Int) {
+                self.advance();
+            } else if self.at(Token::Char) {
+                self.advance();
+                base = Type::Char;
+            } else if self.at(Token::Enum) {
+                self.advance();
+                self.parse_enum()?;
+            }
+
+            while !self.at(Token::Semicolon) && !self.at(Token::RBrace) {
+                let ty = self.parse_pointers(base.clone());
+                let name = self.take_id()?;
+                if self.at(Token::LParen) {
+                    self.function(name, ty)?;
+                } else {
+                    let address = DATA_BASE + self.data.len() as i64;
+                    self.data.extend_from_slice(&[0; 8]);
+                    self.symbols.insert(name, Symbol { class: Class::Glo, ty, val: address });
+                }
+                if self.at(Token::Comma) {
+                    self.advance();
+                }
+            }
+            self.advance();
+        }
+        Ok(())
+    }
+
+    fn parse_enum(&mut self) -> Result<(), String> {
+        if !self.at(Token::LBrace) {
+            self.advance();
+        }
+        if !self.at(Token::LBrace) {
+            return Ok(());
+        }
+        self.advance();
+        let mut value = 0;
+        while !self.at(Token::RBrace) {
+            let name = self.take_id()?;
+            if self.at(Token::Assign) {
+                self.advance();
+                match self.current_token {
+                    Some(Token::Num(n)) => value = n,
+                    _ => return self.error("bad enum initializer"),
+                }
+                self.advance();
+            }
+            self.symbols.insert(name, Symbol { class: Class::Num, ty: Type::Int, val: value });
+            value += 1;
+            if self.at(Token::Comma) {
+                self.advance();
+            }
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn declare_local(&mut self, name: String, ty: Type, slot: i64) {
+        let previous = self.symbols.insert(name.clone(), Symbol { class: Class::Loc, ty, val: slot });
+        self.shadowed.push((name, previous));
+    }
+
+    // A function, starting at the '(' after its name. Its frame is built like the VM's: the
+    // arguments, then the return address, the caller's bp and the locals.
+    fn function(&mut self, name: String, ty: Type) -> Result<(), String> {
+        self.symbols.insert(name.clone(), Symbol { class: Class::Fun, ty, val: 0 });
+        self.advance(); // Skip '('
+
+        let mut slot = 0;
+        let mut params = Vec::new();
+        while !self.at(Token::RParen) {
+            let ty = self.parse_type();
+            let param = self.take_id()?;
+            params.push(param.clone());
+            self.declare_local(param, ty, slot);
+            slot += 1;
+            if self.at(Token::Comma) {
+                self.advance();
+            }
+        }
+        self.advance(); // Skip ')'
+        self.expect(Token::LBrace, "bad function definition")?;
+        slot += 1;
+        self.loc = slot;
+
+        while self.at(Token::Int) || self.at(Token::Char) {
+            let base = if self.at(Token::Int) { Type::Int } else { Type::Char };
+            self.advance();
+            while !self.at(Token::Semicolon) {
+                let ty = self.parse_pointers(base.clone());
+                let local = self.take_id()?;
+                slot += 1;
+                self.declare_local(local, ty, slot);
+                if self.at(Token::Comma) {
+                    self.advance();
+                }
+            }
+            self.advance(); // Skip ';'
+        }
+
+        self.temporaries = 0;
+        let mut body = String::new();
+        while !self.at(Token::RBrace) {
+            self.statement(&mut body, 1)?;
+        }
+
+        writeln!(self.functions, "\n// {}({})", name, params.join(", ")).unwrap();
+        for (local, _) in &self.shadowed {
+            writeln!(self.functions, "// {} is at {}", local, self.frame_address(self.symbols[local].val)).unwrap();
+        }
+        writeln!(self.functions, "static int64_t f_{}(void) {{", name).unwrap();
+        if self.temporaries > 0 {
+            let temporaries: Vec<String> = (1..=self.temporaries).map(|i| format!("t{}", i)).collect();
+            writeln!(self.functions, "    int64_t {};", temporaries.join(", ")).unwrap();
+        }
+        // A function that runs off its end gives back what is in a, like LEV in the VM
+        if !body.ends_with("    return leave();\n") {
+            body += "    return leave();\n";
+        }
+        writeln!(self.functions, "    enter({});\n{}}}", slot - self.loc, body).unwrap();
+
+        while let Some((local, previous)) = self.shadowed.pop() {
+            match previous {
+                Some(symbol) => self.symbols.insert(local, symbol),
+                None => self.symbols.remove(&local),
+            };
+        }
+        Ok(())
+    }
+
+    // Where the local in a slot is, the same place LEA finds it in the VM
+    fn frame_address(&self, slot: i64) -> String {
+        match (self.loc - slot) * 8 {
+            offset if offset < 0 => format!("(bp - {})", -offset),
+            offset => format!("(bp + {})", offset),
+        }
+    }
+
+    // One statement, written at depth. Every value the VM would leave in a is put in a,
+    // because a function that ends without a return gives back what is in a.
+    fn statement(&mut self, out: &mut String, depth: usize) -> Result<(), String> {
+        let indent = "    ".repeat(depth);
+        let assign = get_precedence(&Token::Assign);
+        match self.current_token {
+            Some(Token::If) => {
+                self.advance();
+                self.expect(Token::LParen, "open paren expected")?;
+                let condition = self.expression(assign)?;
+                self.expect(Token::RParen, "close paren expected")?;
+                writeln!(out, "{}if ((a = {}) != 0) {{", indent, condition.code).unwrap();
+                self.statement(out, depth + 1)?;
+                if self.at(Token::Else) {
+                    self.advance();
+                    writeln!(out, "{}}} else {{", indent).unwrap();
+                    self.statement(out, depth + 1)?;
+                }
+                writeln!(out, "{}}}", indent).unwrap();
+            }
+            Some(Token::While) => {
+                self.advance();
+                self.expect(Token::LParen, "open paren expected")?;
+                let condition = self.expression(assign)?;
+                self.expect(Token::RParen, "close paren expected")?;
+                writeln!(out, "{}while ((a = {}) != 0) {{", indent, condition.code).unwrap();
+                self.statement(out, depth + 1)?;
+                writeln!(out, "{}}}", indent).unwrap();
+            }
+            Some(Token::Return) => {
+                self.advance();
+                if !self.at(Token::Semicolon) {
+                    let value = self.expression(assign)?;
+                    writeln!(out, "{}a = {};", indent, value.code).unwrap();
+                }
+                writeln!(out, "{}return leave();", indent).unwrap();
+                self.expect(Token::Semicolon, "semicolon expected")?;
+            }
+            Some(Token::LBrace) => {
+                self.advance();
+                while !self.at(Token::RBrace) {
+                    self.statement(out, depth)?;
+                }
+                self.advance();
+            }
+            Some(Token::Semicolon) => self.advance(),
+            _ => {
+                let value = self.expression(assign)?;
+                writeln!(out, "{}a = {};", indent, value.code).unwrap();
+                self.expect(Token::Semicolon, "semicolon expected")?;
+            }
+        }
+        Ok(())
+    }
+
+    // An expression whose operators all have at least min_prec precedence, like
+    // Parser::parse_expression
+    fn expression(&mut self, min_prec: u8) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        while let Some(op) = self.current_token.clone() {
+            let prec = get_precedence(&op);
+            if prec == 0 || prec < min_prec {
+                break;
+            }
+            let t = left.ty.clone();
+            left = match op {
+                Token::Assign => {
+                    self.advance();
+                    let Some(address) = left.address.clone() else {
+                        return self.error("bad lvalue in assignment");
+                    };
+                    let value = self.expression(get_precedence(&Token::Assign))?;
+                    let target = Expr::new(address, t.clone(), left.effects);
+                    let (first, address) = self.in_order(&target, &value);
+                    let code = sequence(first, format!("{}({}, {})", store(&t), address, value.code));
+                    Expr::new(code, t, true)
+                }
+                Token::Cond => {
+                    self.advance();
+                    let then = self.expression(get_precedence(&Token::Assign))?;
+                    self.expect(Token::Colon, "conditional missing colon")?;
+                    let otherwise = self.expression(get_precedence(&Token::Cond))?;
+                    let code = format!("({} != 0 ? {} : {})", left.code, then.code, otherwise.code);
+                    Expr::new(code, otherwise.ty, left.effects || then.effects || otherwise.effects)
+                }
+                Token::Lor => {
+                    // The result is the left side's value if it isn't 0, not 1
+                    self.advance();
+                    let right = self.expression(prec + 1)?;
+                    let temporary = self.temporary();
+                    let code = format!("(({} = {}) != 0 ? {} : {})", temporary, left.code, temporary, right.code);
+                    Expr::new(code, Type::Int, left.effects || right.effects)
+                }
+                Token::Lan => {
+                    self.advance();
+                    let right = self.expression(prec + 1)?;
+                    let code = format!("({} != 0 ? {} : 0)", left.code, right.code);
+                    Expr::new(code, Type::Int, left.effects || right.effects)
+                }
+                Token::Add => {
+                    self.advance();
+                    let right = self.expression(prec + 1)?;
+                    let (first, left_code) = self.in_order(&left, &right);
+                    let code = sequence(first, format!("add({}, {})", left_code, scaled(&t, &right.code)));
+                    Expr::new(code, t, left.effects || right.effects)
+                }
+                Token::Sub => {
+                    self.advance();
+                    let right = self.expression(prec + 1)?;
+                    let (first, left_code) = self.in_order(&left, &right);
+                    let effects = left.effects || right.effects;
+                    if t.scale() > 1 && t == right.ty {
+                        // Pointer minus pointer: the number of elements between them
+                        Expr::new(sequence(first, format!("divide(sub({}, {}), 8)", left_code, right.code)), Type::Int, effects)
+                    } else {
+                        Expr::new(sequence(first, format!("sub({}, {})", left_code, scaled(&t, &right.code))), t, effects)
+                    }
+                }
+                Token::Inc | Token::Dec => {
+                    // Post-increment: store the new value, then undo the step for the result
+                    let Some(address) = left.address.clone() else {
+                        return self.error("bad lvalue in post-increment");
+                    };
+                    self.advance();
+                    let (first, address) = self.once(address, left.effects);
+                    let (step, undo) = if op == Token::Inc { ("add", "sub") } else { ("sub", "add") };
+                    let size = step_size(&t);
+                    let code = sequence(first, format!("{}({}({}, {}({}({}), {})), {})", undo, store(&t), address, step, load(&t), address, size, size));
+                    Expr::new(code, t, true)
+                }
+                Token::Brak => {
+                    self.advance();
+                    let index = self.expression(get_precedence(&Token::Assign))?;
+                    self.expect(Token::RBrak, "close bracket expected")?;
+                    let Some(ty) = t.deref() else {
+                        return self.error("pointer type expected");
+                    };
+                    let (first, left_code) = self.in_order(&left, &index);
+                    let address = sequence(first, format!("add({}, {})", left_code, scaled(&t, &index.code)));
+                    Expr::load(address, ty, left.effects || index.effects)
+                }
+                _ => {
+                    let (operator, function) = match op {
+                        Token::Or => ("|", ""),
+                        Token::Xor => ("^", ""),
+                        Token::And => ("&", ""),
+                        Token::Eq => ("==", ""),
+                        Token::Ne => ("!=", ""),
+                        Token::Lt => ("<", ""),
+                        Token::Gt => (">", ""),
+                        Token::Le => ("<=", ""),
+                        Token::Ge => (">=", ""),
+                        Token::Shl => ("", "shift_left"),
+                        Token::Shr => ("", "shift_right"),
+                        Token::Mul => ("", "mul"),
+                        Token::Div => ("", "divide"),
+                        _ => ("", "modulo"),
+                    };
+                    self.advance();
+                    let right = self.expression(prec + 1)?;
+                    let (first, left_code) = self.in_order(&left, &right);
+                    let code = match (operator, function) {
+                        ("|" | "^" | "&", _) => format!("({} {} {})", left_code, operator, right.code),
+                        ("", function) => format!("{}({}, {})", function, left_code, right.code),
+                        _ => format!("(int64_t)({} {} {})", left_code, operator, right.code),
+                    };
+                    let code = sequence(first, code);
+                    Expr::new(code, Type::Int, left.effects || right.effects)
+                }
+            };
+        }
+        Ok(left)
+    }
+
+    // The start of an expression: a number, string, name, call, cast or prefix operator, like
+    // Parser::parse_unary
+    fn unary(&mut self) -> Result<Expr, String> {
+        let inc = get_precedence(&Token::Inc);
+        match self.current_token.clone() {
+            Some(Token::Num(val)) => {
+                self.advance();
+                Ok(Expr::new(literal(val), Type::Int, false))
+            }
+            Some(Token::Str(text)) => {
+                // Strings written next to each other are joined, and go where the parser puts them
+                let address = DATA_BASE + self.data.len() as i64;
+                let mut text = text;
+                self.advance();
+                while let Some(Token::Str(more)) = &self.current_token {
+                    text.push_str(more);
+                    self.advance();
+                }
+                self.data.extend_from_slice(text.as_bytes());
+                self.data.push(0);
+                while !self.data.len().is_multiple_of(8) {
+                    self.data.push(0);
+                }
+                Ok(Expr::new(literal(address), Type::Char.ptr_to(), false))
+            }
+            Some(Token::Sizeof) => {
+                self.advance();
+                self.expect(Token::LParen, "open paren expected in sizeof")?;
+                let ty = self.parse_type();
+                self.expect(Token::RParen, "close paren expected in sizeof")?;
+                Ok(Expr::new(literal(ty.size()), Type::Int, false))
+            }
+            Some(Token::Id(name)) => {
+                self.advance();
+                let symbol = self.symbols.get(&name).cloned();
+                if self.at(Token::LParen) {
+                    return self.call(&name, symbol);
+                }
+                match symbol {
+                    Some(Symbol { class: Class::Num, val, .. }) => Ok(Expr::new(literal(val), Type::Int, false)),
+                    Some(Symbol { class: Class::Loc, ty, val }) => Ok(Expr::load(self.frame_address(val), ty, false)),
+                    Some(Symbol { class: Class::Glo, ty, val }) => Ok(Expr::load(literal(val), ty, false)),
+                    _ => self.error("undefined variable"),
+                }
+            }
+            Some(Token::LParen) => {
+                self.advance();
+                if self.at(Token::Int) || self.at(Token::Char) {
+                    // A cast only changes the type, so (char)x = 1 stores a char where x is
+                    let ty = self.parse_type();
+                    self.expect(Token::RParen, "bad cast")?;
+                    let mut value = self.expression(inc)?;
+                    value.ty = ty;
+                    Ok(value)
+                } else {
+                    let value = self.expression(get_precedence(&Token::Assign))?;
+                    self.expect(Token::RParen, "close paren expected")?;
+                    Ok(value)
+                }
+            }
+            Some(Token::Mul) => {
+                self.advance();
+                let pointer = self.expression(inc)?;
+                match pointer.ty.deref() {
+                    Some(ty) => Ok(Expr::load(pointer.code, ty, pointer.effects)),
+                    None => self.error("bad dereference"),
+                }
+            }
+            Some(Token::And) => {
+                self.advance();
+                let value = self.expression(inc)?;
+                match value.address {
+                    Some(address) => Ok(Expr::new(address, value.ty.ptr_to(), value.effects)),
+                    None => self.error("bad address-of"),
+                }
+            }
+            Some(Token::Not) => {
+                self.advance();
+                let value = self.expression(inc)?;
+                Ok(Expr::new(format!("(int64_t)({} == 0)", value.code), Type::Int, value.effects))
+            }
+            Some(Token::Tilde) => {
+                self.advance();
+                let value = self.expression(inc)?;
+                Ok(Expr::new(format!("({} ^ -1)", value.code), Type::Int, value.effects))
+            }
+            Some(Token::Add) => {
+                // Only the type changes, so +x can still be assigned to, like in C4
+                self.advance();
+                let mut value = self.expression(inc)?;
+                value.ty = Type::Int;
+                Ok(value)
+            }
+            Some(Token::Sub) => {
+                self.advance();
+                if let Some(Token::Num(val)) = self.current_token {
+                    self.advance();
+                    return Ok(Expr::new(literal(val.wrapping_neg()), Type::Int, false));
+                }
+                let value = self.expression(inc)?;
+                Ok(Expr::new(format!("mul(-1, {})", value.code), Type::Int, value.effects))
+            }
+            Some(op @ (Token::Inc | Token::Dec)) => {
+                // Pre-increment: the result is the new value
+                self.advance();
+                let value = self.expression(inc)?;
+                let Some(address) = value.address else {
+                    return self.error("bad lvalue in pre-increment");
+                };
+                let (first, address) = self.once(address, value.effects);
+                let step = if op == Token::Inc { "add" } else { "sub" };
+                let code = sequence(first, format!("{}({}, {}({}({}), {}))", store(&value.ty), address, step, load(&value.ty), address, step_size(&value.ty)));
+                Ok(Expr::new(code, value.ty, true))
+            }
+            _ => self.error("bad expression"),
+        }
+    }
+
+    // A call like f(x, y). The arguments are pushed on the VM's stack from left to right and
+    // dropped after it, the way the VM does, so the system calls find them where they expect.
+    fn call(&mut self, name: &str, symbol: Option<Symbol>) -> Result<Expr, String> {
+        self.advance(); // Skip '('
+        let mut parts = Vec::new();
+        while !self.at(Token::RParen) {
+            let argument = self.expression(get_precedence(&Token::Assign))?;
+            parts.push(format!("push({})", argument.code));
+            if self.at(Token::Comma) {
+                self.advance();
+            }
+        }
+        self.advance(); // Skip ')'
+        let argc = parts.len();
+
+        let (call, ty) = match symbol {
+            Some(Symbol { class: Class::Sys, ty, .. }) => {
+                let call = match name {
+                    "open" => "sys_open()".to_string(),
+                    "read" => "sys_read()".to_string(),
+                    "close" => "sys_close()".to_string(),
+                    // printf takes as many arguments as the ADJ after it drops, like in the VM
+                    "printf" => format!("sys_printf({})", argc.max(1)),
+                    "malloc" => "sys_malloc()".to_string(),
+                    "free" => "sys_free()".to_string(),
+                    "memset" => "sys_memset()".to_string(),
+                    "memcmp" => "sys_memcmp()".to_string(),
+                    "exit" => "(sys_exit(), 0)".to_string(),
+                    _ => return Err(format!("host function '{}' can't be called from C", name)),
+                };
+                (call, ty)
+            }
+            Some(Symbol { class: Class::Fun, ty, .. }) => (format!("call(f_{})", name), ty),
+            _ => return self.error("bad function call"),
+        };
+        parts.push(if argc > 0 { format!("drop({}, {})", argc, call) } else { call });
+        let code = if parts.len() == 1 { parts.pop().unwrap() } else { format!("({})", parts.join(", ")) };
+        Ok(Expr::new(code, ty, true))
+    }
+}
+
+// Work out first, then code, with C's comma operator
+fn sequence(first: String, code: String) -> String {
+    if first.is_empty() { code } else { format!("({}{})", first, code) }
+}
+
+// The runtime function that loads a value of this type
+fn load(ty: &Type) -> &'static str {
+    if *ty == Type::Char { "load_char" } else { "load_int" }
+}
+
+// The runtime function that stores a value of this type and gives back what was stored
+fn store(ty: &Type) -> &'static str {
+    if *ty == Type::Char { "store_char" } else { "store_int" }
+}
+
+// How far ++ and -- move a value of this type
+fn step_size(ty: &Type) -> i64 {
+    if ty.scale() > 1 { 8 } else { 1 }
+}
+
+// The right side of + or - on a value of type ty, multiplied by 8 if ty points to ints or pointers
+fn scaled(ty: &Type, code: &str) -> String {
+    if ty.scale() > 1 { format!("mul({}, 8)", code) } else { code.to_string() }
+}
+
+// A 64-bit number as C source. The smallest one can't be written as a plain literal.
+fn literal(n: i64) -> String {
+    match n {
+        i64::MIN => "INT64_MIN".to_string(),
+        n if i32::try_from(n).is_ok() => n.to_string(),
+        n => format!("INT64_C({})", n),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend_tests::{check, have_tools, temp_path, FAULTS, SAME_AS_VM};
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+
+    // Translate a program to C and build it with cc
+    fn build(source: &str, path: &Path) -> Result<(), String> {
+        let c_file = temp_path("csource").with_extension("c");
+        fs::write(&c_file, c_source(source)?).map_err(|err| err.to_string())?;
+        let cc = Command::new("cc").args(["-std=c11", "-O1", "-o"]).arg(path).arg(&c_file).output().map_err(|err| err.to_string())?;
+        let _ = fs::remove_file(&c_file);
+        if !cc.status.success() {
+            return Err(String::from_utf8_lossy(&cc.stderr).into_owned());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_translation() {
+        let source = c_source("int f(int n) { int x; x = n * 7; return x / 2; } int main() { return f(6) + f(1); }").unwrap();
+        assert!(source.contains("\n// f(n)\n// n is at (bp + 16)\n// x is at (bp - 8)\nstatic int64_t f_f(void) {\n    enter(1);\n"));
+        assert!(source.contains("    a = store_int((bp - 8), mul(load_int((bp + 16)), 7));\n"));
+        assert!(source.contains("    a = divide(load_int((bp - 8)), 2);\n    return leave();\n}\n"));
+
+        // Calls change things, so the left side of + is worked out into a temporary first
+        assert!(source.contains("    int64_t t1;\n    enter(0);\n    a = (t1 = (push(6), drop(1, call(f_f))), add(t1, (push(1), drop(1, call(f_f)))));\n"));
+        assert_eq!(literal(i64::MIN), "INT64_MIN");
+        assert_eq!(literal(1 << 40), "INT64_C(1099511627776)");
+
+        assert_eq!(c_source("int main() { return x; }").unwrap_err(), "Syntax Error: undefined variable (found ';') at line 1, col 22");
+    }
+
+    #[test]
+    fn test_same_as_vm() {
+        if !have_tools("csource::test_same_as_vm", &["cc"]) {
+            return;
+        }
+        for source in SAME_AS_VM {
+            check(source, build);
+        }
+    }
+
+    #[test]
+    fn test_faults() {
+        if !have_tools("csource::test_faults", &["cc"]) {
+            return;
+        }
+        for source in FAULTS {
+            check(source, build);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+// Dead code: instructions that can never run. Following every way control can go from main
+// finds the live instructions; the rest are after a return, on the side of a branch whose
+// condition is a constant, or in functions nobody calls. The parser warns about them, and
+// with -O eliminate() takes them out of the program.
+use crate::opcode::Op;
+use crate::Program;
+use std::collections::HashSet;
+
+// Where each instruction starts, in order. Gives back None if an instruction's operand is
+// missing off the end of the text.
+pub fn instruction_starts(text: &[i64]) -> Option<Vec<usize>> {
+    let mut starts = Vec::new();
+    let mut pc = 0;
+    while pc < text.len() {
+        starts.push(pc);
+        pc += match Op::from_code(text[pc]) {
+            Some(op) if op.has_operand() => 2,
+            _ => 1,
+        };
+    }
+    (pc == text.len()).then_some(starts)
+}
+
+fn is_jump(code: i64) -> bool {
+    matches!(Op::from_code(code), Some(Op::Jmp | Op::Jsr | Op::Bz | Op::Bnz))
+}
+
+// Which words of the text start an instruction that can run, starting at entry.
+// A BZ or BNZ right after "IMM n" (with nothing else jumping to it) always goes the same way.
+// Gives back None for text that doesn't split into instructions or jumps between them.
+pub fn reachable(text: &[i64], entry: usize) -> Option<Vec<bool>> {
+    let starts = instruction_starts(text)?;
+    let is_start: HashSet<usize> = starts.iter().copied().collect();
+    let mut targets = HashSet::new();
+    for &pc in &starts {
+        if is_jump(text[pc]) {
+            let target = usize::try_from(text[pc + 1]).ok().filter(|t| is_start.contains(t) || *t == text.len())?;
+            targets.insert(target);
+        }
+    }
+
+    let mut live = vec![false; text.len()];
+    let mut work = vec![entry];
+    while let Some(pc) = work.pop() {
+        if pc >= text.len() || live[pc] {
+            continue;
+        }
+        live[pc] = true;
+        let op = Op::from_code(text[pc]);
+        let next = pc + if op.is_some_and(|op| op.has_operand()) { 2 } else { 1 };
+        let target = || text[pc + 1] as usize;
+        // The value of a when the branch at pc runs, if it can only be one constant
+        let constant = (pc >= 2 && text[pc - 2] == Op::Imm.code() && is_start.contains(&(pc - 2)) && !targets.contains(&pc))
+            .then(|| text[pc - 1]);
+        match op {
+            Some(Op::Jmp) => work.push(target()),
+            Some(Op::Jsr) => work.extend([target(), next]),
+            Some(Op::Bz) => match constant {
+                Some(0) => work.push(target()),
+                Some(_) => work.push(next),
+                None => work.extend([target(), next]),
+            },
+            Some(Op::Bnz) => match constant {
+                Some(0) => work.push(next),
+                Some(_) => work.push(target()),
+                None => work.extend([target(), next]),
+            },
+            Some(Op::Lev | Op::Exit) => {}
+            _ => work.push(next),
+        }
+    }
+    Some(live)
+}
+
+// Take every instruction that can't run out of the program, along with the functions that
+// are never called. Gives back how many words of text were removed.
+pub fn eliminate(program: &mut Program) -> usize {
+    let Some(live) = reachable(&program.text, program.entry) else {
+        return 0;
+    };
+    let starts = instruction_starts(&program.text).unwrap();
+
+    // moved[pc] is where the code at pc (or the next live code after it) is now
+    let old = program.text.len();
+    let mut moved = vec![0; old + 1];
+    let mut text = Vec::new();
+    let mut spans = Vec::new();
+    for (i, &pc) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(old);
+        moved[pc..end].fill(text.len());
+        if live[pc] {
+            text.extend_from_slice(&program.text[pc..end]);
+            spans.extend_from_slice(program.spans.get(pc..end).unwrap_or_default());
+        }
+    }
+    moved[old] = text.len();
+
+    let mut pc = 0;
+    while pc < text.len() {
+        if is_jump(text[pc]) {
+            text[pc + 1] = moved[text[pc + 1] as usize] as i64;
+        }
+        pc += match Op::from_code(text[pc]) {
+            Some(op) if op.has_operand() => 2,
+            _ => 1,
+        };
+    }
+
+    program.functions.retain(|(_, range)| live.get(range.start).copied().unwrap_or(false));
+    for (_, range) in &mut program.functions {
+        *range = moved[range.start]..moved[range.end];
+    }
+    program.entry = moved[program.entry];
+    program.text = text;
+    program.spans = spans;
+    old - program.text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile;
+    use crate::diagnostic::Severity;
+
+    #[test]
+    fn test_unreachable_warnings() {
+        let source = "int unused() { return 1; }
+int main() {
+  int x;
+  x = 2;
+  if (0) { x = 3; }
+  while (1) {
+    if (x) return x;
+  }
+  x = 4;
+}";
+        let program = compile(source).unwrap();
+        let warnings: Vec<String> = program.warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "Warning: function 'unused' is never used at line 1, col 5",
+                "Warning: unreachable code at line 5, col 12",
+                "Warning: unreachable code at line 9, col 3",
+            ]
+        );
+        assert!(program.warnings.iter().all(|w| w.severity == Severity::Warning));
+    }
+
+    #[test]
+    fn test_eliminate() {
+        let source = "int unused() { return 1; }
+int twice(int n) { return n + n; }
+int main() { int x; x = twice(4); if (0) x = 0; return x; printf(\"never\"); }";
+        let program = compile(source).unwrap();
+        let mut optimized = program.clone();
+        let removed = eliminate(&mut optimized);
+        assert!(removed > 0);
+        assert_eq!(optimized.functions.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), vec!["twice", "main"]);
+        assert_eq!(optimized.run(Default::default()).unwrap().code, 8);
+        assert_eq!(optimized.text.len(), program.text.len() - removed);
+
+        // Nothing more to take out the second time
+        assert_eq!(eliminate(&mut optimized), 0);
+    }
+}
@@ -0,0 +1,329 @@
+use crate::lexer::Span;
+use crate::parser::{Parser, Symbol};
+use crate::token::{Class, Type};
+use crate::vm::{VmError, VmState, VM};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+
+const HELP: &str = "\
+Commands:
+  break <line|function>  stop when the program gets to this line or function (b)
+  step                   run to the next source line, going into calls (s)
+  next                   run to the next source line, stepping over calls (n)
+  finish                 run until the current function returns (fin)
+  continue               run until a breakpoint or the end of the program (c)
+  print <var>            show a local or global variable (p)
+  backtrace              show the functions that are being called (bt)
+  x <addr>               show the four words of memory starting at addr
+  info registers         show a, sp, bp, pc and the cycle count (i r)
+  quit                   leave the debugger (q)";
+
+// How far a command lets the program run
+#[derive(Clone, Copy, PartialEq)]
+enum Resume {
+    Step,     // until the source line changes
+    Next,     // until the source line changes in this function (or a caller)
+    Finish,   // until this function returns
+    Continue, // until a breakpoint
+}
+
+// An interactive debugger for one compiled program. It drives the VM one instruction at a time
+// and uses the parser's spans and symbols to talk about lines, functions and variables.
+pub struct Debugger<'a> {
+    pub vm: VM,
+    lines: Vec<&'a str>,                               // The source, one line at a time
+    spans: Vec<Span>,                                  // Where each word of text came from
+    functions: Vec<(String, Range<usize>)>,            // Where each function's code is
+    globals: HashMap<String, Symbol>,                  // Global variables and their addresses
+    locals: HashMap<String, Vec<(String, Symbol)>>,    // Each function's locals and their bp offsets
+    breakpoints: Vec<usize>,                           // The pcs to stop at, breakpoint 1 first
+    finished: bool,                                    // Has the program exited or faulted?
+}
+
+impl<'a> Debugger<'a> {
+    // Load the program compiled by parser, stopped at its first instruction.
+    // Fails if the program's data doesn't fit in the VM's memory.
+    pub fn new(source: &'a str, parser: &Parser, entry: usize) -> Result<Self, VmError> {
+        let globals = parser
+            .symbols
+            .iter()
+            .filter(|(_, symbol)| symbol.class == Class::Glo)
+            .map(|(name, symbol)| (name.clone(), symbol.clone()))
+            .collect();
+        Ok(Debugger {
+            vm: VM::new(parser.text.clone(), parser.data.clone(), entry)?,
+            lines: source.lines().collect(),
+            spans: parser.spans.clone(),
+            functions: parser.functions(),
+            globals,
+            locals: parser.locals.clone(),
+            breakpoints: Vec::new(),
+            finished: false,
+        })
+    }
+
+    // Read commands from input until quit (or the end of input), answering on out
+    pub fn run(&mut self, input: &mut impl BufRead, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "Type 'help' for a list of commands.")?;
+        self.show_position(out)?;
+        loop {
+            write!(out, "(c4db) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => {}
+                ["q" | "quit"] => return Ok(()),
+                ["help"] => writeln!(out, "{}", HELP)?,
+                ["b" | "break", place] => self.set_breakpoint(place, out)?,
+                ["s" | "step"] => self.resume(Resume::Step, out)?,
+                ["n" | "next"] => self.resume(Resume::Next, out)?,
+                ["fin" | "finish"] => self.resume(Resume::Finish, out)?,
+                ["c" | "continue"] => self.resume(Resume::Continue, out)?,
+                ["p" | "print", name] => self.print(name, out)?,
+                ["bt" | "backtrace"] => self.backtrace(out)?,
+                ["x", address] => self.examine(address, out)?,
+                ["i" | "info", "r" | "registers"] => {
+                    let vm = &self.vm;
+                    writeln!(out, "a      {}", vm.a)?;
+                    writeln!(out, "sp     {:#x}", vm.sp)?;
+                    writeln!(out, "bp     {:#x}", vm.bp)?;
+                    writeln!(out, "pc     {}", vm.pc)?;
+                    writeln!(out, "cycle  {}", vm.cycle)?;
+                }
+                _ => writeln!(out, "Unknown command '{}'. Type 'help' for a list of commands.", line.trim())?,
+            }
+        }
+    }
+
+    // The source line the instruction at pc was compiled from
+    fn line_at(&self, pc: usize) -> Option<usize> {
+        self.spans.get(pc).map(|span| span.line)
+    }
+
+    // The name of the function whose code holds pc
+    fn function_at(&self, pc: usize) -> Option<&str> {
+        self.functions.iter().find(|(_, range)| range.contains(&pc)).map(|(name, _)| name.as_str())
+    }
+
+    // The bp of the function we are in. Right after a call ENT hasn't run yet,
+    // so this is where ENT is about to put it.
+    fn frame_bp(&self) -> i64 {
+        if self.functions.iter().any(|(_, range)| range.start == self.vm.pc) {
+            self.vm.sp - 8
+        } else {
+            self.vm.bp
+        }
+    }
+
+    // Say where the program is stopped
+    fn show_position(&self, out: &mut impl Write) -> io::Result<()> {
+        let pc = self.vm.pc;
+        let function = self.function_at(pc).unwrap_or("??");
+        match self.line_at(pc) {
+            Some(line) => {
+                let text = self.lines.get(line - 1).copied().unwrap_or("");
+                writeln!(out, "{} () at line {}: {}", function, line, text.trim())
+            }
+            None => writeln!(out, "{} () at pc {}", function, pc),
+        }
+    }
+
+    // break <line|function>
+    fn set_breakpoint(&mut self, place: &str, out: &mut impl Write) -> io::Result<()> {
+        let pc = match place.parse::<usize>() {
+            // The first instruction made for that line
+            Ok(line) => self.spans.iter().position(|span| span.line == line),
+            Err(_) => self.functions.iter().find(|(name, _)| name == place).map(|(_, range)| range.start),
+        };
+        match pc {
+            Some(pc) => {
+                if !self.breakpoints.contains(&pc) {
+                    self.breakpoints.push(pc);
+                }
+                let number = self.breakpoints.iter().position(|&b| b == pc).unwrap() + 1;
+                let line = self.line_at(pc).unwrap_or(0);
+                writeln!(out, "Breakpoint {} at pc {}: {}, line {}", number, pc, self.function_at(pc).unwrap_or("??"), line)
+            }
+            None => writeln!(out, "No code for '{}'", place),
+        }
+    }
+
+    // Let the program run until the command's stopping point, a breakpoint or the end
+    fn resume(&mut self, how: Resume, out: &mut impl Write) -> io::Result<()> {
+        if self.finished {
+            return writeln!(out, "The program is not running.");
+        }
+        let start_line = self.line_at(self.vm.pc);
+        let start_frame = self.frame_bp();
+
+        loop {
+            match self.vm.step() {
+                VmState::Running => {}
+                VmState::Halted(code) => {
+                    self.finished = true;
+                    return writeln!(out, "Program exited with code {}.", code);
+                }
+                VmState::Faulted(err) => {
+                    self.finished = true;
+                    return writeln!(out, "Program stopped: VM error: {}", err);
+                }
+                VmState::WaitingForInput => self.vm.close_input(), // the debugger reads stdin itself
+            }
+
+            let pc = self.vm.pc;
+            if let Some(number) = self.breakpoints.iter().position(|&b| b == pc) {
+                writeln!(out, "Breakpoint {}", number + 1)?;
+                break;
+            }
+            let new_line = self.line_at(pc).is_some() && self.line_at(pc) != start_line;
+            let stop = match how {
+                Resume::Step => new_line,
+                Resume::Next => new_line && self.frame_bp() >= start_frame, // not inside a call
+                Resume::Finish => self.frame_bp() > start_frame,            // back in the caller
+                Resume::Continue => false,
+            };
+            if stop {
+                break;
+            }
+        }
+        self.show_position(out)
+    }
+
+    // Where a variable lives and what type it is: locals of the current function first, then globals
+    fn lookup(&self, name: &str) -> Option<(i64, Type)> {
+        let function = self.function_at(self.vm.pc);
+        let local = function.and_then(|f| self.locals.get(f)).and_then(|locals| locals.iter().find(|(n, _)| n == name));
+        match local {
+            Some((_, symbol)) => Some((self.frame_bp() + symbol.val * 8, symbol.ty.clone())),
+            None => self.globals.get(name).map(|symbol| (symbol.val, symbol.ty.clone())),
+        }
+    }
+
+    // print <var>
+    fn print(&self, name: &str, out: &mut impl Write) -> io::Result<()> {
+        let Some((address, ty)) = self.lookup(name) else {
+            return writeln!(out, "No variable named '{}' here", name);
+        };
+        let memory = &self.vm.memory;
+        let value = match ty {
+            Type::Char => memory.load_u8(address).map(|c| format!("{} '{}'", c, (c as char).escape_default())),
+            Type::Int => memory.load_i64(address).map(|n| n.to_string()),
+            Type::Ptr(ref to) => memory.load_i64(address).map(|p| match (&**to, memory.read_cstr(p)) {
+                (Type::Char, Ok(text)) => format!("{:#x} {:?}", p, String::from_utf8_lossy(&text)),
+                _ => format!("{:#x}", p),
+            }),
+        };
+        match value {
+            Ok(value) => writeln!(out, "{} = {}", name, value),
+            Err(fault) => writeln!(out, "Can't read {}: {}", name, fault),
+        }
+    }
+
+    // backtrace: follow the saved bp and return address of each frame
+    fn backtrace(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut pc = self.vm.pc;
+        let mut bp = self.frame_bp();
+        for depth in 0.. {
+            let function = self.function_at(pc).unwrap_or("??");
+            match self.line_at(pc) {
+                Some(line) => writeln!(out, "#{} {} () at line {}", depth, function, line)?,
+                None => writeln!(out, "#{} {} () at pc {}", depth, function, pc)?,
+            }
+
+            // ENT pushed the caller's bp, and the return address is just above it
+            let memory = &self.vm.memory;
+            match (memory.load_i64(bp + 8), memory.load_i64(bp)) {
+                (Ok(ret), Ok(caller_bp)) if (ret as usize) < self.spans.len() && ret >= 0 => {
+                    pc = ret as usize;
+                    bp = caller_bp;
+                }
+                _ => break, // main returns to the exit code after the program's text
+            }
+        }
+        Ok(())
+    }
+
+    // x <addr>: four words of memory, the address can be decimal or 0x hex
+    fn examine(&self, address: &str, out: &mut impl Write) -> io::Result<()> {
+        let parsed = match address.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16),
+            None => address.parse(),
+        };
+        let Ok(address) = parsed else {
+            return writeln!(out, "Bad address '{}'", address);
+        };
+        write!(out, "{:#x}:", address)?;
+        for i in 0..4 {
+            match self.vm.memory.load_i64(address + i * 8) {
+                Ok(word) => write!(out, " {}", word)?,
+                Err(fault) => {
+                    write!(out, " ({})", fault)?;
+                    break;
+                }
+            }
+        }
+        writeln!(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    // Run the debugger on source with the given commands and give back what it printed
+    fn session(source: &str, commands: &str) -> String {
+        let mut parser = Parser::new(Lexer::new(source));
+        parser.parse_program().unwrap();
+        let mut debugger = Debugger::new(source, &parser, parser.entry().unwrap()).unwrap();
+        let mut out = Vec::new();
+        debugger.run(&mut commands.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    const PROGRAM: &str = "int total;
+int add(int x, int y) {
+  int sum;
+  sum = x + y;
+  return sum;
+}
+int main() {
+  char *s;
+  s = \"ok\";
+  total = add(2, 3);
+  total = add(total, 10);
+  return total;
+}
+";
+
+    #[test]
+    fn test_break_print_and_backtrace() {
+        let out = session(PROGRAM, "break add\ncontinue\nbt\nprint x\nnext\nprint sum\nfinish\nprint s\nprint total\nprint x\nc\n");
+        assert!(out.starts_with("Type 'help' for a list of commands.\nmain () at line 9: s = \"ok\";\n"));
+        assert!(out.contains("Breakpoint 1 at pc 0: add, line 4\n"));
+        assert!(out.contains("Breakpoint 1\nadd () at line 4: sum = x + y;\n"));
+        assert!(out.contains("#0 add () at line 4\n#1 main () at line 10\n"));
+        assert!(out.contains("x = 2\n"));
+        assert!(out.contains("add () at line 5: return sum;\n(c4db) sum = 5\n"));
+        assert!(out.contains("main () at line 10: total = add(2, 3);\n"));
+        assert!(out.contains("s = 0x100008 \"ok\"\n"));
+        assert!(out.contains("total = 0\n")); // add has returned, but its result isn't stored yet
+        assert!(out.contains("No variable named 'x' here\n"));
+        assert_eq!(out.matches("Breakpoint 1\n").count(), 2); // stopped again at the second call
+    }
+
+    #[test]
+    fn test_step_into_and_run_to_the_end() {
+        let out = session(PROGRAM, "break 10\nc\nstep\ninfo registers\nc\nc\nc\nstep\n");
+        assert!(out.contains("add () at line 4"));
+        assert!(out.contains("cycle  "));
+        assert!(out.contains("Program exited with code 15."));
+        assert!(out.contains("The program is not running."));
+    }
+}
@@ -0,0 +1,73 @@
+use crate::lexer::Span;
+use std::fmt;
+
+// How bad a diagnostic is: errors stop the program from compiling, warnings don't
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// A message from the compiler about one place in the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn error(message: String, span: Span) -> Self {
+        Diagnostic { severity: Severity::Error, message, span }
+    }
+
+    pub fn warning(message: String, span: Span) -> Self {
+        Diagnostic { severity: Severity::Warning, message, span }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.severity {
+            Severity::Error => "Syntax Error",
+            Severity::Warning => "Warning",
+        };
+        write!(f, "{}: {} at line {}, col {}", kind, self.message, self.span.line, self.span.col)
+    }
+}
+
+// Everything the compiler had to say about a program, in the order it was found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diagnostics {
+    pub list: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    // Did anything stop the program from compiling?
+    pub fn has_errors(&self) -> bool {
+        self.list.iter().any(|d| d.severity == Severity::Error)
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.list.iter().filter(|d| d.severity == Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.list.iter().filter(|d| d.severity == Severity::Warning)
+    }
+}
+
+// One diagnostic per line
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, diagnostic) in self.list.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
@@ -0,0 +1,358 @@
+// The fast interpreter loop behind VM::run.
+//
+// step() does a lot for every instruction: it looks the word up, checks for the debugger,
+// -d and --trace, and goes through push() and pop() and the memory's segment lookup. Here the
+// text is decoded once, before the program runs, into an array of instructions with their
+// operands inside them and jump targets turned into indices into the array. The loop keeps a,
+// sp and bp in local variables and only goes back to the VM when it has to.
+//
+// Every instruction checks what it will do before it changes anything. If it would fault, go
+// over a limit or do something the loop doesn't know (a system call, say), the loop stops
+// there and step() runs that one instruction instead. So a program faults at the same pc
+// with the same error, and counts the same cycles, as it does one step at a time.
+use crate::memory::STACK_TOP;
+use crate::opcode::Op;
+use crate::vm::{VmState, VM};
+
+// One decoded instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fast {
+    Lea(i64),
+    Imm(i64),
+    Jmp(u32),                      // The index of the instruction to go to
+    Jsr { target: u32, ret: u32 }, // ret is the text address to come back to, as JSR pushes it
+    Bz(u32),
+    Bnz(u32),
+    Ent(i64),
+    Adj(i64),
+    Lev,
+    Li,
+    Lc,
+    Si,
+    Sc,
+    Psh,
+    // The most used operators get their own instruction, the rest go through Op::apply
+    Add,
+    Sub,
+    Mul,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Binary(Op),
+    Addi(i64),
+    Lli(i64),
+    Slow, // Anything else: system calls, host functions and words that aren't instructions
+}
+
+const NONE: u32 = u32::MAX;
+
+struct Decoded {
+    code: Vec<Fast>,
+    pcs: Vec<usize>, // The text address of each instruction
+    index: Vec<u32>, // For each text word, the instruction that starts there (or NONE)
+}
+
+impl Decoded {
+    // The instruction starting at a text address
+    fn at(&self, pc: usize) -> Option<usize> {
+        self.index.get(pc).copied().filter(|&i| i != NONE).map(|i| i as usize)
+    }
+}
+
+fn decode(text: &[i64]) -> Decoded {
+    let mut code = Vec::new();
+    let mut pcs = Vec::new();
+    let mut index = vec![NONE; text.len() + 1];
+    let mut pc = 0;
+    while pc < text.len() {
+        index[pc] = code.len() as u32;
+        pcs.push(pc);
+        let op = Op::from_code(text[pc]);
+        let operand = match op {
+            Some(op) if op.has_operand() => text.get(pc + 1).copied(),
+            _ => None,
+        };
+        let n = operand.unwrap_or(0);
+        code.push(match op {
+            Some(op) if op.has_operand() && operand.is_none() => Fast::Slow, // Its operand is missing
+            Some(Op::Lea) => Fast::Lea(n),
+            Some(Op::Imm) => Fast::Imm(n),
+            Some(Op::Jmp) => Fast::Jmp(0),
+            Some(Op::Jsr) => Fast::Jsr { target: 0, ret: (pc + 2) as u32 },
+            Some(Op::Bz) => Fast::Bz(0),
+            Some(Op::Bnz) => Fast::Bnz(0),
+            Some(Op::Ent) => Fast::Ent(n),
+            Some(Op::Adj) => Fast::Adj(n),
+            Some(Op::Lev) => Fast::Lev,
+            Some(Op::Li) => Fast::Li,
+            Some(Op::Lc) => Fast::Lc,
+            Some(Op::Si) => Fast::Si,
+            Some(Op::Sc) => Fast::Sc,
+            Some(Op::Psh) => Fast::Psh,
+            Some(Op::Add) => Fast::Add,
+            Some(Op::Sub) => Fast::Sub,
+            Some(Op::Mul) => Fast::Mul,
+            Some(Op::Eq) => Fast::Eq,
+            Some(Op::Ne) => Fast::Ne,
+            Some(Op::Lt) => Fast::Lt,
+            Some(Op::Gt) => Fast::Gt,
+            Some(Op::Le) => Fast::Le,
+            Some(Op::Ge) => Fast::Ge,
+            Some(op) if op.is_binary() => Fast::Binary(op),
+            Some(Op::Addi) => Fast::Addi(n),
+            Some(Op::Lli) => Fast::Lli(n),
+            _ => Fast::Slow,
+        });
+        pc += if operand.is_some() { 2 } else { 1 };
+    }
+    // Running off the end of the text lands here, and step() reports it
+    index[text.len()] = code.len() as u32;
+    pcs.push(text.len());
+    code.push(Fast::Slow);
+
+    // Point the jumps at instructions. A jump that doesn't land on one is left to step().
+    for i in 0..code.len() {
+        let target = usize::try_from(text.get(pcs[i] + 1).copied().unwrap_or(-1)).ok();
+        let target = target.filter(|&t| t < text.len()).map_or(NONE, |t| index[t]);
+        code[i] = match code[i] {
+            _ if target == NONE && matches!(code[i], Fast::Jmp(_) | Fast::Jsr { .. } | Fast::Bz(_) | Fast::Bnz(_)) => Fast::Slow,
+            Fast::Jmp(_) => Fast::Jmp(target),
+            Fast::Jsr { ret, .. } => Fast::Jsr { target, ret },
+            Fast::Bz(_) => Fast::Bz(target),
+            Fast::Bnz(_) => Fast::Bnz(target),
+            other => other,
+        };
+    }
+    Decoded { code, pcs, index }
+}
+
+impl VM {
+    // Run until the program stops running, like run_until(|_| false) but much faster.
+    // Gives back the state it stopped in.
+    pub(crate) fn run_fast(&mut self) -> VmState {
+        let decoded = decode(&self.text);
+        let code = &decoded.code[..];
+        let stack_base = self.memory.stack_base();
+        let cycle_limit = self.limits.max_cycles.unwrap_or(u64::MAX);
+        let depth_limit = self.limits.max_call_depth.unwrap_or(usize::MAX);
+        loop {
+            // Take single steps until pc is at an instruction the loop knows
+            let mut i = loop {
+                if let VmState::Halted(_) | VmState::Faulted(_) = self.state() {
+                    return self.state();
+                }
+                match decoded.at(self.pc) {
+                    Some(i) => break i,
+                    None => {
+                        let state = self.step();
+                        if state != VmState::Running {
+                            return state;
+                        }
+                    }
+                }
+            };
+
+            let (mut a, mut sp, mut bp, mut cycle, mut depth) = (self.a, self.sp, self.bp, self.cycle, self.depth);
+            let memory = &mut self.memory;
+            while cycle < cycle_limit {
+                // Each arm either does the whole instruction or breaks out with nothing changed
+                match code[i] {
+                    Fast::Lea(n) => {
+                        a = bp.wrapping_add(n.wrapping_mul(8));
+                        i += 1;
+                    }
+                    Fast::Imm(n) => {
+                        a = n;
+                        i += 1;
+                    }
+                    Fast::Jmp(target) => i = target as usize,
+                    Fast::Jsr { target, ret } => {
+                        if depth >= depth_limit || memory.store_stack(sp.wrapping_sub(8), ret as i64).is_none() {
+                            break;
+                        }
+                        sp -= 8;
+                        depth += 1;
+                        i = target as usize;
+                    }
+                    Fast::Bz(target) => i = if a == 0 { target as usize } else { i + 1 },
+                    Fast::Bnz(target) => i = if a != 0 { target as usize } else { i + 1 },
+                    Fast::Ent(n) => {
+                        let frame = sp.wrapping_sub(8);
+                        let new_sp = frame.wrapping_sub(n.wrapping_mul(8));
+                        if new_sp < stack_base || new_sp > STACK_TOP || memory.store_stack(frame, bp).is_none() {
+                            break;
+                        }
+                        bp = frame;
+                        sp = new_sp;
+                        i += 1;
+                    }
+                    Fast::Adj(n) => {
+                        let new_sp = sp.wrapping_add(n.wrapping_mul(8));
+                        if new_sp < stack_base || new_sp > STACK_TOP {
+                            break;
+                        }
+                        sp = new_sp;
+                        i += 1;
+                    }
+                    Fast::Lev => {
+                        let (Some(old_bp), Some(ret)) = (memory.load_stack(bp), memory.load_stack(bp.wrapping_add(8))) else {
+                            break;
+                        };
+                        if bp.wrapping_add(8) >= STACK_TOP {
+                            break;
+                        }
+                        let Some(target) = usize::try_from(ret).ok().filter(|&t| t < self.text.len()).and_then(|t| decoded.at(t)) else {
+                            break;
+                        };
+                        sp = bp + 16;
+                        bp = old_bp;
+                        depth = depth.saturating_sub(1);
+                        i = target;
+                    }
+                    Fast::Li => {
+                        let Ok(value) = memory.load_i64(a) else { break };
+                        a = value;
+                        i += 1;
+                    }
+                    Fast::Lc => {
+                        let Ok(value) = memory.load_u8(a) else { break };
+                        a = value as i8 as i64;
+                        i += 1;
+                    }
+                    Fast::Si => {
+                        let Some(address) = memory.load_stack(sp).filter(|_| sp < STACK_TOP) else { break };
+                        if memory.store_i64(address, a).is_err() {
+                            break;
+                        }
+                        sp += 8;
+                        i += 1;
+                    }
+                    Fast::Sc => {
+                        let Some(address) = memory.load_stack(sp).filter(|_| sp < STACK_TOP) else { break };
+                        if memory.store_u8(address, a as u8).is_err() {
+                            break;
+                        }
+                        a = a as u8 as i8 as i64;
+                        sp += 8;
+                        i += 1;
+                    }
+                    Fast::Psh => {
+                        if memory.store_stack(sp.wrapping_sub(8), a).is_none() {
+                            break;
+                        }
+                        sp -= 8;
+                        i += 1;
+                    }
+                    Fast::Addi(n) => {
+                        a = a.wrapping_add(n);
+                        i += 1;
+                    }
+                    Fast::Lli(n) => {
+                        let Ok(value) = memory.load_i64(bp.wrapping_add(n.wrapping_mul(8))) else { break };
+                        a = value;
+                        i += 1;
+                    }
+                    Fast::Slow => break,
+                    binary => {
+                        // The left side is on the stack, the right side in a
+                        let Some(left) = memory.load_stack(sp).filter(|_| sp < STACK_TOP) else { break };
+                        a = match binary {
+                            Fast::Add => left.wrapping_add(a),
+                            Fast::Sub => left.wrapping_sub(a),
+                            Fast::Mul => left.wrapping_mul(a),
+                            Fast::Eq => (left == a) as i64,
+                            Fast::Ne => (left != a) as i64,
+                            Fast::Lt => (left < a) as i64,
+                            Fast::Gt => (left > a) as i64,
+                            Fast::Le => (left <= a) as i64,
+                            Fast::Ge => (left >= a) as i64,
+                            Fast::Binary(op) => match op.apply(left, a) {
+                                Some(value) => value,
+                                None => break,
+                            },
+                            _ => unreachable!(),
+                        };
+                        sp += 8;
+                        i += 1;
+                    }
+                }
+                cycle += 1;
+            }
+
+            // Hand the registers back and let step() run the instruction the loop stopped at
+            (self.a, self.sp, self.bp, self.cycle, self.depth) = (a, sp, bp, cycle, depth);
+            self.pc = decoded.pcs[i];
+            let state = self.step();
+            if state != VmState::Running {
+                return state;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble;
+    use crate::host::SharedBuffer;
+    use crate::vm::{Limits, VmState};
+    use crate::{compile, Program, RunConfig};
+
+    // Run a program with the fast loop and one step at a time, and check they end the same:
+    // same state, same output, same registers and cycle count
+    fn check(program: &Program, limits: Limits) -> VmState {
+        let mut ends = Vec::new();
+        for fast in [true, false] {
+            let output = SharedBuffer::new();
+            let config = RunConfig { output: Some(Box::new(output.clone())), limits, ..RunConfig::default() };
+            let mut vm = program.vm(config).unwrap();
+            let state = if fast { vm.run_fast() } else { vm.run_until(|_| false) };
+            ends.push((state, output.text(), vm.a, vm.sp, vm.bp, vm.pc, vm.cycle));
+        }
+        assert_eq!(ends[0], ends[1]);
+        ends[0].0
+    }
+
+    #[test]
+    fn test_same_as_stepping() {
+        let source = "int fib(int n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+                      int main() { char *s; int i; s = malloc(16); i = 0;
+                                   while (i < 10) { s[i] = 'a' + i; i++; } s[i] = 0;
+                                   printf(\"%s %d\\n\", s, fib(10) % 7 << 2 >> 1 | 1 ^ 3); return fib(12); }";
+        assert_eq!(check(&compile(source).unwrap(), Limits::default()), VmState::Halted(144));
+        let mut optimized = compile(source).unwrap();
+        optimized.optimize();
+        assert_eq!(check(&optimized, Limits::default()), VmState::Halted(144));
+    }
+
+    #[test]
+    fn test_faults_match() {
+        let sources = [
+            "int main() { int x; x = 0; return 5 / x; }",
+            "int main() { int *p; p = 0; return *p; }",
+            "int main() { char *p; p = 0; *p = 1; return 0; }",
+            "int f(int n) { return f(n + 1); } int main() { return f(0); }",
+        ];
+        for source in sources {
+            let state = check(&compile(source).unwrap(), Limits::default());
+            assert!(matches!(state, VmState::Faulted(_)), "{}", source);
+        }
+        // A jump out of the text is left to step()
+        let program = assemble("main:\n    JMP 1000\n").unwrap();
+        assert!(matches!(check(&program, Limits::default()), VmState::Faulted(_)));
+    }
+
+    #[test]
+    fn test_limits_match() {
+        let program = compile("int f(int n) { if (n) return f(n - 1) + 1; return 0; } int main() { return f(100); }").unwrap();
+        for limits in [
+            Limits { max_cycles: Some(1000), ..Limits::default() },
+            Limits { max_call_depth: Some(50), ..Limits::default() },
+            Limits { max_call_depth: Some(101), ..Limits::default() },
+        ] {
+            check(&program, limits);
+        }
+    }
+}
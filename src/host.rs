@@ -0,0 +1,234 @@
+use crate::memory::{Memory, MemoryFault};
+use crate::opcode::Op;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Cursor, Read, Write};
+use std::rc::Rc;
+
+// Flags for open(), using the same numbers as Linux so C code can pass them through
+pub const O_ACCMODE: i64 = 0o3;
+pub const O_WRONLY: i64 = 0o1;
+pub const O_RDWR: i64 = 0o2;
+pub const O_CREAT: i64 = 0o100;
+pub const O_TRUNC: i64 = 0o1000;
+pub const O_APPEND: i64 = 0o2000;
+
+// Where the VM's open() finds files. The program can only read what it opens
+// (C4 has no write()), but the flags still create and truncate files like on the host.
+pub trait FileSystem {
+    fn open(&mut self, path: &str, flags: i64) -> io::Result<Box<dyn Read>>;
+}
+
+// The real files of the machine we are running on
+pub struct HostFileSystem;
+
+impl FileSystem for HostFileSystem {
+    fn open(&mut self, path: &str, flags: i64) -> io::Result<Box<dyn Read>> {
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options
+            .create(flags & O_CREAT != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .append(flags & O_APPEND != 0);
+        Ok(Box::new(options.open(path)?))
+    }
+}
+
+// A file tree kept in memory, so tests can run programs without touching the disk.
+// Clones share the same files, so a test can keep one and still see what the program did.
+#[derive(Clone, Default)]
+pub struct MemoryFileSystem {
+    files: Rc<RefCell<HashMap<String, Vec<u8>>>>,
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        MemoryFileSystem::default()
+    }
+
+    // Put a file in the tree, replacing any file with the same path
+    pub fn add_file(&self, path: &str, contents: &[u8]) {
+        self.files.borrow_mut().insert(path.to_string(), contents.to_vec());
+    }
+
+    // What is in a file now, if it exists
+    pub fn contents(&self, path: &str) -> Option<Vec<u8>> {
+        self.files.borrow().get(path).cloned()
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn open(&mut self, path: &str, flags: i64) -> io::Result<Box<dyn Read>> {
+        let mut files = self.files.borrow_mut();
+        if flags & O_CREAT != 0 {
+            files.entry(path.to_string()).or_default();
+        }
+        let file = files.get_mut(path).ok_or(io::ErrorKind::NotFound)?;
+        if flags & O_TRUNC != 0 && flags & O_ACCMODE != 0 {
+            file.clear();
+        }
+        // The program reads the file as it was when it was opened
+        Ok(Box::new(Cursor::new(file.clone())))
+    }
+}
+
+// An output that keeps everything written to it, for capturing what a program prints.
+// Clones share the same bytes.
+#[derive(Clone, Default)]
+pub struct SharedBuffer {
+    bytes: Rc<RefCell<Vec<u8>>>,
+}
+
+impl SharedBuffer {
+    pub fn new() -> Self {
+        SharedBuffer::default()
+    }
+
+    // Everything written so far
+    pub fn contents(&self) -> Vec<u8> {
+        self.bytes.borrow().clone()
+    }
+
+    // Everything written so far, as text
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.bytes.borrow()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bytes.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Host functions get instruction numbers after the last instruction, in the order they are registered
+pub const FIRST_HOST_CODE: i64 = Op::Lli as i64 + 1;
+
+// A Rust function that C programs can call like a system call. It gets the VM's memory
+// (to read strings or fill buffers) and the arguments, first one first, and gives back
+// the value of the call.
+pub type NativeFunction = Box<dyn FnMut(&mut Memory, &[i64]) -> Result<i64, MemoryFault>>;
+
+struct HostFunction {
+    name: String,
+    arity: usize, // How many arguments it takes from the stack
+    function: NativeFunction,
+}
+
+// The functions an embedder added. The parser enters their names as system calls,
+// and the VM runs them when it meets their instruction numbers.
+#[derive(Default)]
+pub struct HostFunctions {
+    functions: Vec<HostFunction>,
+}
+
+impl HostFunctions {
+    pub fn new() -> Self {
+        HostFunctions::default()
+    }
+
+    // Let C code call function by name with arity arguments. Gives back its instruction number.
+    // Registering a name again replaces the function but keeps the number.
+    pub fn register(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl FnMut(&mut Memory, &[i64]) -> Result<i64, MemoryFault> + 'static,
+    ) -> i64 {
+        let host_function = HostFunction { name: name.to_string(), arity, function: Box::new(function) };
+        match self.functions.iter().position(|f| f.name == name) {
+            Some(i) => {
+                self.functions[i] = host_function;
+                FIRST_HOST_CODE + i as i64
+            }
+            None => {
+                self.functions.push(host_function);
+                FIRST_HOST_CODE + self.functions.len() as i64 - 1
+            }
+        }
+    }
+
+    // Every function's name and instruction number
+    pub fn names(&self) -> impl Iterator<Item = (&str, i64)> {
+        self.functions.iter().enumerate().map(|(i, f)| (f.name.as_str(), FIRST_HOST_CODE + i as i64))
+    }
+
+    // Renumber the functions so each import has the number a program was compiled with.
+    // Gives back the number of the first import that isn't registered.
+    pub fn arrange(&mut self, imports: &[(String, i64)]) -> Result<(), i64> {
+        for (name, code) in imports {
+            let from = self.functions.iter().position(|f| &f.name == name);
+            match (from, usize::try_from(code - FIRST_HOST_CODE)) {
+                (Some(from), Ok(to)) if to < self.functions.len() => self.functions.swap(from, to),
+                _ => return Err(*code),
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, code: i64) -> Option<&HostFunction> {
+        self.functions.get(usize::try_from(code - FIRST_HOST_CODE).ok()?)
+    }
+
+    // The name of the function with this instruction number
+    pub fn name(&self, code: i64) -> Option<&str> {
+        self.get(code).map(|f| f.name.as_str())
+    }
+
+    // How many arguments the function with this instruction number takes
+    pub fn arity(&self, code: i64) -> Option<usize> {
+        self.get(code).map(|f| f.arity)
+    }
+
+    // Run the function with this instruction number
+    pub fn call(&mut self, code: i64, memory: &mut Memory, args: &[i64]) -> Result<i64, MemoryFault> {
+        let i = (code - FIRST_HOST_CODE) as usize;
+        (self.functions[i].function)(memory, args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_host_functions() {
+        let mut host = HostFunctions::new();
+        let add = host.register("add", 2, |_, args| Ok(args[0] + args[1]));
+        let twice = host.register("twice", 1, |_, args| Ok(args[0] * 2));
+        assert_eq!((add, twice), (FIRST_HOST_CODE, FIRST_HOST_CODE + 1));
+        assert_eq!(host.register("add", 3, |_, args| Ok(args[0] + args[1] + args[2])), add);
+
+        let mut memory = Memory::new(Default::default(), &[]).unwrap();
+        assert_eq!(host.arity(add), Some(3));
+        assert_eq!(host.call(add, &mut memory, &[1, 2, 3]), Ok(6));
+        assert_eq!(host.name(twice), Some("twice"));
+        assert_eq!(host.name(twice + 1), None);
+    }
+
+    #[test]
+    fn test_memory_file_system() {
+        let mut fs = MemoryFileSystem::new();
+        fs.add_file("in.txt", b"data");
+
+        let mut text = String::new();
+        fs.open("in.txt", 0).unwrap().read_to_string(&mut text).unwrap();
+        assert_eq!(text, "data");
+
+        assert!(fs.open("missing.txt", 0).is_err());
+        fs.open("new.txt", O_WRONLY | O_CREAT).unwrap();
+        assert_eq!(fs.contents("new.txt"), Some(Vec::new()));
+        fs.open("in.txt", O_WRONLY | O_TRUNC).unwrap();
+        assert_eq!(fs.contents("in.txt"), Some(Vec::new()));
+    }
+}
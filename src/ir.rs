@@ -0,0 +1,720 @@
+// A mid-level IR for optimising whole functions, built from a compiled program.
+//
+// Each function is split into basic blocks joined by a control-flow graph. The code in the
+// blocks is in SSA form: every value is made once, by one instruction, and is named v0, v1, ...
+// C4's stack and accumulator disappear: the builder runs each block on a stack of values
+// instead of numbers, so "PSH; IMM 2; ADD" just becomes "v3 = add v1, v2".
+//
+// Local variables whose address is never taken become SSA values too, so "x = x + 1" makes a
+// new value instead of storing to memory. Where control flow joins, a phi picks the value
+// from the block we came from. Locals that are used through pointers (or as chars) stay in
+// memory and are read and written with load and store.
+//
+// The passes in ir_passes.rs optimise the IR, and --dump-ir prints it. The parser makes VM
+// code in one pass with no AST, so the IR comes after the bytecode rather than before it:
+// the optimised IR is compiled for the register VM (regvm.rs), and the stack VM's code is
+// never made from it.
+use crate::opcode::Op;
+use crate::Program;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+pub type ValueId = usize;
+pub type BlockId = usize;
+
+// What makes a value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inst {
+    Undef,                                               // A local read before it was written
+    Const(i64),                                          // A number or a data address
+    Param(i64),                                          // The argument at bp + n words
+    LocalAddr(i64),                                      // The address bp + n words, of a local kept in memory
+    Copy(ValueId),                                       // The same value, from an assignment to a local
+    Binary(Op, ValueId, ValueId),                        // One of the operators from OR to MOD
+    Load { char: bool, address: ValueId },               // Read an int or a char from memory
+    Store { char: bool, address: ValueId, value: ValueId }, // Write to memory; its value is what SI/SC leave in a
+    Call { target: usize, args: Vec<ValueId> },          // Call the function at this text address
+    Syscall { code: i64, args: Vec<ValueId> },           // A system call or host function
+    Phi(Vec<(BlockId, ValueId)>),                        // The value from whichever block we came from
+}
+
+impl Inst {
+    // The values this instruction uses
+    pub fn operands(&self) -> Vec<ValueId> {
+        match self {
+            Inst::Copy(v) => vec![*v],
+            Inst::Binary(_, a, b) => vec![*a, *b],
+            Inst::Load { address, .. } => vec![*address],
+            Inst::Store { address, value, .. } => vec![*address, *value],
+            Inst::Call { args, .. } | Inst::Syscall { args, .. } => args.clone(),
+            Inst::Phi(incoming) => incoming.iter().map(|&(_, v)| v).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut ValueId> {
+        match self {
+            Inst::Copy(v) => vec![v],
+            Inst::Binary(_, a, b) => vec![a, b],
+            Inst::Load { address, .. } => vec![address],
+            Inst::Store { address, value, .. } => vec![address, value],
+            Inst::Call { args, .. } | Inst::Syscall { args, .. } => args.iter_mut().collect(),
+            Inst::Phi(incoming) => incoming.iter_mut().map(|(_, v)| v).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Does it only compute a value, so it can be moved, merged or dropped if unused?
+    // Division is left out because it can fault.
+    pub fn is_pure(&self) -> bool {
+        match self {
+            Inst::Undef | Inst::Const(_) | Inst::Param(_) | Inst::LocalAddr(_) | Inst::Copy(_) | Inst::Phi(_) => true,
+            Inst::Binary(op, _, _) => !matches!(op, Op::Div | Op::Mod),
+            _ => false,
+        }
+    }
+}
+
+// How a block ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch { cond: ValueId, nonzero: BlockId, zero: BlockId },
+    Return(ValueId),
+    Exit(ValueId), // exit(): the whole program stops
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match *self {
+            Terminator::Jump(b) => vec![b],
+            Terminator::Branch { nonzero, zero, .. } => vec![nonzero, zero],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn operands(&self) -> Vec<ValueId> {
+        match *self {
+            Terminator::Jump(_) => Vec::new(),
+            Terminator::Branch { cond, .. } => vec![cond],
+            Terminator::Return(v) | Terminator::Exit(v) => vec![v],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut ValueId> {
+        match self {
+            Terminator::Jump(_) => Vec::new(),
+            Terminator::Branch { cond, .. } => vec![cond],
+            Terminator::Return(v) | Terminator::Exit(v) => vec![v],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub address: usize,        // Where its code started in the text (0 for blocks the passes add)
+    pub insts: Vec<ValueId>,   // Its instructions in order, phis first
+    pub term: Terminator,
+    pub preds: Vec<BlockId>,   // The blocks that can come here
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    pub address: usize,     // Where it starts in the text
+    pub frame: i64,         // How many words of locals ENT makes room for
+    pub values: Vec<Inst>,  // Every value, by id. Values the passes remove are left here but in no block.
+    pub addresses: Vec<usize>, // Where in the text the instruction that made each value was
+    pub blocks: Vec<Block>, // Block 0 is the entry, and the blocks are in reverse postorder when built
+}
+
+impl Function {
+    fn add(&mut self, address: usize, block: BlockId, inst: Inst) -> ValueId {
+        self.values.push(inst);
+        self.addresses.push(address);
+        self.blocks[block].insts.push(self.values.len() - 1);
+        self.values.len() - 1
+    }
+
+    // Make every use of old use new instead
+    pub fn replace_uses(&mut self, old: ValueId, new: ValueId) {
+        for block in &mut self.blocks {
+            for &id in &block.insts {
+                for operand in self.values[id].operands_mut() {
+                    if *operand == old {
+                        *operand = new;
+                    }
+                }
+            }
+            for operand in block.term.operands_mut() {
+                if *operand == old {
+                    *operand = new;
+                }
+            }
+        }
+    }
+
+    // How many times each value is used
+    pub fn use_counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.values.len()];
+        for block in &self.blocks {
+            for &id in &block.insts {
+                for operand in self.values[id].operands() {
+                    counts[operand] += 1;
+                }
+            }
+            for operand in block.term.operands() {
+                counts[operand] += 1;
+            }
+        }
+        counts
+    }
+
+    // The block each value is in (None for removed values)
+    pub fn value_blocks(&self) -> Vec<Option<BlockId>> {
+        let mut blocks = vec![None; self.values.len()];
+        for (b, block) in self.blocks.iter().enumerate() {
+            for &id in &block.insts {
+                blocks[id] = Some(b);
+            }
+        }
+        blocks
+    }
+}
+
+// Every function of a program in IR form
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Module {
+    pub functions: Vec<Function>,
+    pub entry: usize, // The text address main() starts at
+}
+
+impl Module {
+    // Build the IR for every function. Gives back a message if a function's code can't be
+    // turned into IR, which only happens for hand-written assembly.
+    pub fn from_program(program: &Program) -> Result<Module, String> {
+        let mut functions = Vec::new();
+        for (name, range) in &program.functions {
+            functions.push(build_function(program, name, range.start, range.end)?);
+        }
+        Ok(Module { functions, entry: program.entry })
+    }
+
+    // The name of the function at this text address
+    pub fn function_name(&self, address: usize) -> Option<&str> {
+        self.functions.iter().find(|f| f.address == address).map(|f| f.name.as_str())
+    }
+}
+
+// The things the builder tracks while it runs a block: the accumulator, each stack slot and
+// each local variable kept in SSA form
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Var {
+    Acc,
+    Stack(usize),
+    Local(i64),
+}
+
+// What the accumulator or a stack slot holds while building: a value, or the address of a
+// local kept in SSA form (which only LI and SI may use)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Value(ValueId),
+    Local(i64),
+}
+
+#[derive(Debug, Clone)]
+struct State {
+    acc: Slot,
+    stack: Vec<Slot>,
+    locals: HashMap<i64, ValueId>,
+}
+
+enum BuildError {
+    Escapes(i64),        // This local's address is used some other way, so it has to stay in memory
+    Unsupported(String), // The code doesn't fit the IR at all
+}
+
+// Build one function. A local is tried in SSA form first; if its address turns out to be
+// used for anything but LI and SI, the function is built again with that local in memory.
+fn build_function(program: &Program, name: &str, start: usize, end: usize) -> Result<Function, String> {
+    let mut in_memory = HashSet::new();
+    loop {
+        match Builder::new(program, name, start, end, &in_memory)?.build() {
+            Ok(function) => return Ok(function),
+            Err(BuildError::Escapes(slot)) => {
+                in_memory.insert(slot);
+            }
+            Err(BuildError::Unsupported(message)) => return Err(format!("{}: {}", name, message)),
+        }
+    }
+}
+
+struct Builder<'a> {
+    code: Vec<(usize, i64, i64)>,  // Each instruction's address, number and operand (0 if none)
+    end: usize,                    // The address just after the function
+    pc: usize,                     // The address of the instruction being run
+    constant: Vec<Option<bool>>,   // For each instruction, whether it is a branch that always or never jumps
+    ssa_locals: Vec<i64>,          // The locals kept in SSA form, by their LEA offset
+    in_memory: &'a HashSet<i64>,
+    function: Function,
+}
+
+impl<'a> Builder<'a> {
+    fn new(program: &Program, name: &str, start: usize, end: usize, in_memory: &'a HashSet<i64>) -> Result<Self, String> {
+        let mut code = Vec::new();
+        let mut pc = start;
+        while pc < end {
+            let op = Op::from_code(program.text[pc]);
+            let operand = match op {
+                Some(op) if op.has_operand() => *program.text.get(pc + 1).ok_or("instruction cut off")?,
+                _ => 0,
+            };
+            code.push((pc, program.text[pc], operand));
+            pc += if op.is_some_and(|op| op.has_operand()) { 2 } else { 1 };
+        }
+
+        let mut ssa_locals: Vec<i64> = code
+            .iter()
+            .filter(|&&(_, c, _)| c == Op::Lea.code() || c == Op::Lli.code())
+            .map(|&(_, _, n)| n)
+            .filter(|n| !in_memory.contains(n))
+            .collect();
+        ssa_locals.sort();
+        ssa_locals.dedup();
+
+        let frame = match code.first() {
+            Some(&(_, c, n)) if c == Op::Ent.code() => n,
+            _ => 0,
+        };
+        let function = Function { name: name.to_string(), address: start, frame, values: Vec::new(), addresses: Vec::new(), blocks: Vec::new() };
+        Ok(Builder { code, end, pc: start, constant: Vec::new(), ssa_locals, in_memory, function })
+    }
+
+    fn build(mut self) -> Result<Function, BuildError> {
+        // Split the code into blocks: one starts at the function, at every jump target and
+        // after every jump, branch, return and exit
+        let mut index: HashMap<usize, usize> = self.code.iter().enumerate().map(|(i, &(pc, _, _))| (pc, i)).collect();
+        index.insert(self.end, self.code.len());
+        let mut targets = HashSet::new();
+        for &(_, code, operand) in &self.code {
+            if matches!(Op::from_code(code), Some(Op::Jmp | Op::Bz | Op::Bnz)) {
+                let target = usize::try_from(operand).ok().and_then(|t| index.get(&t));
+                targets.insert(*target.ok_or_else(|| BuildError::Unsupported(format!("jump out of the function to {}", operand)))?);
+            }
+        }
+        self.constant = (0..self.code.len()).map(|i| self.branch_taken(i, &targets)).collect();
+
+        let mut leaders = HashSet::from([0]);
+        for (i, &(_, code, operand)) in self.code.iter().enumerate() {
+            match Op::from_code(code) {
+                Some(Op::Jmp | Op::Bz | Op::Bnz) => {
+                    // Only dead code may jump to the end of the function: a branch that never
+                    // goes that way, after dead code elimination dropped what was there
+                    let target = index[&(operand as usize)];
+                    let taken = self.constant[i] != Some(false);
+                    if target == self.code.len() && taken {
+                        return Err(BuildError::Unsupported(format!("jump out of the function to {}", operand)));
+                    }
+                    leaders.insert(target);
+                    leaders.insert(i + 1);
+                }
+                Some(Op::Lev | Op::Exit) => {
+                    leaders.insert(i + 1);
+                }
+                _ => {}
+            }
+        }
+        let mut leaders: Vec<usize> = leaders.into_iter().filter(|&i| i < self.code.len()).collect();
+        leaders.sort();
+        let raw_of = |i: usize| leaders.binary_search(&i).ok();
+
+        // Where each raw block can go next
+        let raw_successors = |r: usize| -> Vec<usize> {
+            let end = leaders.get(r + 1).copied().unwrap_or(self.code.len());
+            let mut next = Vec::new();
+            for i in leaders[r]..end {
+                let (_, code, operand) = self.code[i];
+                let target = || raw_of(index[&(operand as usize)]).unwrap();
+                match Op::from_code(code) {
+                    Some(Op::Jmp) => return vec![target()],
+                    Some(Op::Bz | Op::Bnz) => match self.constant[i] {
+                        Some(true) => return vec![target()],
+                        Some(false) => break,
+                        None => {
+                            next.push(target());
+                            break;
+                        }
+                    },
+                    Some(Op::Lev | Op::Exit) => return Vec::new(),
+                    _ => {}
+                }
+            }
+            if end < self.code.len() {
+                next.push(r + 1);
+            }
+            next
+        };
+
+        // Number the reachable blocks in reverse postorder, so the entry is 0 and every block
+        // comes after its predecessors (apart from loops going back)
+        let mut postorder = Vec::new();
+        let mut seen = HashSet::from([0]);
+        let mut stack = vec![(0, raw_successors(0), 0)];
+        while let Some((r, succs, i)) = stack.last_mut() {
+            if *i < succs.len() {
+                let s = succs[*i];
+                *i += 1;
+                if seen.insert(s) {
+                    stack.push((s, raw_successors(s), 0));
+                }
+            } else {
+                postorder.push(*r);
+                stack.pop();
+            }
+        }
+        let order: Vec<usize> = postorder.into_iter().rev().collect();
+        let id_of: HashMap<usize, BlockId> = order.iter().enumerate().map(|(id, &r)| (r, id)).collect();
+        for &r in &order {
+            let address = self.code[leaders[r]].0;
+            self.function.blocks.push(Block { address, insts: Vec::new(), term: Terminator::Jump(0), preds: Vec::new() });
+        }
+        for (id, &r) in order.iter().enumerate() {
+            for s in raw_successors(r) {
+                let preds = &mut self.function.blocks[id_of[&s]].preds;
+                if !preds.contains(&id) {
+                    preds.push(id);
+                }
+            }
+        }
+
+        // Run each block on values, in order
+        let mut exits: Vec<Option<State>> = vec![None; order.len()];
+        let mut phis: Vec<(BlockId, Var, ValueId)> = Vec::new();
+        let mut depths = HashMap::new(); // How deep the stack is at the start of each join
+        for (b, &r) in order.iter().enumerate() {
+            let preds = self.function.blocks[b].preds.clone();
+            self.pc = self.function.blocks[b].address;
+            let mut state = if b == 0 {
+                let acc = Slot::Value(self.function.add(self.pc, 0, Inst::Undef));
+                let mut locals = HashMap::new();
+                for &n in &self.ssa_locals.clone() {
+                    let inst = if n >= 2 { Inst::Param(n) } else { Inst::Undef };
+                    locals.insert(n, self.function.add(self.pc, 0, inst));
+                }
+                State { acc, stack: Vec::new(), locals }
+            } else if preds.len() == 1 {
+                exits[preds[0]].clone().ok_or_else(|| BuildError::Unsupported("block reached only from later code".to_string()))?
+            } else {
+                // A join: a phi for everything, filled in once every block has been run
+                let known = preds.iter().find_map(|&p| exits[p].as_ref()).unwrap();
+                for slot in std::iter::once(&known.acc).chain(&known.stack) {
+                    if let Slot::Local(n) = slot {
+                        return Err(BuildError::Escapes(*n));
+                    }
+                }
+                let depth = known.stack.len();
+                depths.insert(b, depth);
+                let mut phi = |var: Var| {
+                    let id = self.function.add(self.pc, b, Inst::Phi(Vec::new()));
+                    phis.push((b, var, id));
+                    id
+                };
+                let acc = Slot::Value(phi(Var::Acc));
+                let stack = (0..depth).map(|i| Slot::Value(phi(Var::Stack(i)))).collect();
+                let locals = self.ssa_locals.iter().map(|&n| (n, phi(Var::Local(n)))).collect();
+                State { acc, stack, locals }
+            };
+
+            let start = leaders[r];
+            let end = leaders.get(r + 1).copied().unwrap_or(self.code.len());
+            let mut term = None;
+            for i in start..end {
+                term = self.run(b, i, &mut state, &index, &id_of, &raw_of)?;
+                if term.is_some() {
+                    break;
+                }
+            }
+            self.function.blocks[b].term = match term {
+                Some(term) => term,
+                None => Terminator::Jump(id_of[&(r + 1)]),
+            };
+            exits[b] = Some(state);
+        }
+
+        // Fill in the phis from what each predecessor had at its end
+        for (b, var, id) in phis {
+            let mut incoming = Vec::new();
+            for &p in &self.function.blocks[b].preds {
+                let exit = exits[p].as_ref().unwrap();
+                if exit.stack.len() != depths[&b] {
+                    return Err(BuildError::Unsupported("stack depth differs where paths join".to_string()));
+                }
+                let slot = match var {
+                    Var::Acc => exit.acc,
+                    Var::Stack(i) => exit.stack[i],
+                    Var::Local(n) => Slot::Value(exit.locals[&n]),
+                };
+                incoming.push((p, self.value(slot)?));
+            }
+            self.function.values[id] = Inst::Phi(incoming);
+        }
+        Ok(self.function)
+    }
+
+    // A slot's value, for any use other than LI and SI
+    fn value(&self, slot: Slot) -> Result<ValueId, BuildError> {
+        match slot {
+            Slot::Value(v) => Ok(v),
+            Slot::Local(n) => Err(BuildError::Escapes(n)),
+        }
+    }
+
+    fn pop(&self, state: &mut State) -> Result<Slot, BuildError> {
+        state.stack.pop().ok_or_else(|| BuildError::Unsupported("stack underflow".to_string()))
+    }
+
+    // The address of a local: the local itself if it is kept in SSA form
+    fn local(&mut self, b: BlockId, n: i64) -> Slot {
+        if self.in_memory.contains(&n) {
+            Slot::Value(self.function.add(self.pc, b, Inst::LocalAddr(n)))
+        } else {
+            Slot::Local(n)
+        }
+    }
+
+    // The arguments of the call at i: as many words as the ADJ after it drops, first one first
+    fn args(&self, i: usize, state: &State) -> Result<Vec<ValueId>, BuildError> {
+        let n = match self.code.get(i + 1) {
+            Some(&(_, code, n)) if code == Op::Adj.code() => n as usize,
+            _ => 0,
+        };
+        if n > state.stack.len() {
+            return Err(BuildError::Unsupported("call with more arguments than the stack has".to_string()));
+        }
+        state.stack[state.stack.len() - n..].iter().map(|&slot| self.value(slot)).collect()
+    }
+
+    // Whether the branch at i always jumps (Some(true)) or never does (Some(false)), when it
+    // comes right after "IMM n" and nothing else jumps to it
+    fn branch_taken(&self, i: usize, targets: &HashSet<usize>) -> Option<bool> {
+        let (_, code, _) = self.code[i];
+        let (_, before, n) = *self.code.get(i.checked_sub(1)?)?;
+        if before != Op::Imm.code() || targets.contains(&i) {
+            return None;
+        }
+        match Op::from_code(code) {
+            Some(Op::Bz) => Some(n == 0),
+            Some(Op::Bnz) => Some(n != 0),
+            _ => None,
+        }
+    }
+
+    // Run instruction i on values. Gives back the block's terminator if it ends the block.
+    fn run(
+        &mut self,
+        b: BlockId,
+        i: usize,
+        state: &mut State,
+        index: &HashMap<usize, usize>,
+        id_of: &HashMap<usize, BlockId>,
+        raw_of: &dyn Fn(usize) -> Option<usize>,
+    ) -> Result<Option<Terminator>, BuildError> {
+        let (pc, code, operand) = self.code[i];
+        self.pc = pc;
+        let block_at = |address: i64| id_of[&raw_of(index[&(address as usize)]).unwrap()];
+        let next = || id_of[&raw_of(i + 1).unwrap()];
+        match Op::from_code(code) {
+            Some(Op::Lea) => state.acc = self.local(b, operand),
+            Some(Op::Imm) => state.acc = Slot::Value(self.function.add(self.pc, b, Inst::Const(operand))),
+            Some(Op::Jmp) => return Ok(Some(Terminator::Jump(block_at(operand)))),
+            Some(Op::Jsr) => {
+                let args = self.args(i, state)?;
+                state.acc = Slot::Value(self.function.add(self.pc, b, Inst::Call { target: operand as usize, args }));
+            }
+            Some(Op::Bz | Op::Bnz) if self.constant[i].is_some() => {
+                let taken = self.constant[i] == Some(true);
+                return Ok(Some(Terminator::Jump(if taken { block_at(operand) } else { next() })));
+            }
+            Some(op @ (Op::Bz | Op::Bnz)) => {
+                let cond = self.value(state.acc)?;
+                let (target, fall) = (block_at(operand), next());
+                let (nonzero, zero) = if op == Op::Bz { (fall, target) } else { (target, fall) };
+                return Ok(Some(Terminator::Branch { cond, nonzero, zero }));
+            }
+            Some(Op::Ent) => {}
+            Some(Op::Adj) => {
+                let n = operand as usize;
+                if n > state.stack.len() {
+                    return Err(BuildError::Unsupported("ADJ drops more than the stack has".to_string()));
+                }
+                state.stack.truncate(state.stack.len() - n);
+            }
+            Some(Op::Lev) => return Ok(Some(Terminator::Return(self.value(state.acc)?))),
+            Some(op @ (Op::Li | Op::Lc | Op::Lli)) => {
+                let address = if op == Op::Lli { self.local(b, operand) } else { state.acc };
+                state.acc = Slot::Value(match address {
+                    Slot::Local(n) if op == Op::Lc => return Err(BuildError::Escapes(n)),
+                    Slot::Local(n) => state.locals[&n],
+                    Slot::Value(address) => self.function.add(self.pc, b, Inst::Load { char: op == Op::Lc, address }),
+                });
+            }
+            Some(op @ (Op::Si | Op::Sc)) => {
+                let address = self.pop(state)?;
+                let value = self.value(state.acc)?;
+                match address {
+                    Slot::Local(n) if op == Op::Sc => return Err(BuildError::Escapes(n)),
+                    Slot::Local(n) => {
+                        let copy = self.function.add(self.pc, b, Inst::Copy(value));
+                        state.locals.insert(n, copy);
+                    }
+                    Slot::Value(address) => {
+                        let store = self.function.add(self.pc, b, Inst::Store { char: op == Op::Sc, address, value });
+                        if op == Op::Sc {
+                            state.acc = Slot::Value(store); // SC leaves the char it stored in a
+                        }
+                    }
+                }
+            }
+            Some(Op::Psh) => state.stack.push(state.acc),
+            Some(op) if op.is_binary() => {
+                let right = self.value(state.acc)?;
+                let left = self.pop(state)?;
+                let left = self.value(left)?;
+                state.acc = Slot::Value(self.function.add(self.pc, b, Inst::Binary(op, left, right)));
+            }
+            Some(Op::Addi) => {
+                let left = self.value(state.acc)?;
+                let right = self.function.add(self.pc, b, Inst::Const(operand));
+                state.acc = Slot::Value(self.function.add(self.pc, b, Inst::Binary(Op::Add, left, right)));
+            }
+            Some(Op::Exit) => {
+                let args = self.args(i, state)?;
+                let code = match args.first() {
+                    Some(&code) => code,
+                    None => self.function.add(self.pc, b, Inst::Const(0)),
+                };
+                return Ok(Some(Terminator::Exit(code)));
+            }
+            _ => {
+                // A system call, or a host function
+                let args = self.args(i, state)?;
+                state.acc = Slot::Value(self.function.add(self.pc, b, Inst::Syscall { code, args }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Module {
+    fn write_value(&self, f: &mut fmt::Formatter, function: &Function, id: ValueId) -> fmt::Result {
+        let list = |values: &[ValueId]| values.iter().map(|v| format!("v{}", v)).collect::<Vec<_>>().join(", ");
+        write!(f, "    v{} = ", id)?;
+        match &function.values[id] {
+            Inst::Undef => writeln!(f, "undef"),
+            Inst::Const(n) => writeln!(f, "const {}", n),
+            Inst::Param(n) => writeln!(f, "param {}", n),
+            Inst::LocalAddr(n) => writeln!(f, "local {}", n),
+            Inst::Copy(v) => writeln!(f, "copy v{}", v),
+            Inst::Binary(op, a, b) => writeln!(f, "{} v{}, v{}", op.name().to_lowercase(), a, b),
+            Inst::Load { char, address } => writeln!(f, "load{} [v{}]", if *char { ".c" } else { "" }, address),
+            Inst::Store { char, address, value } => {
+                writeln!(f, "store{} [v{}], v{}", if *char { ".c" } else { "" }, address, value)
+            }
+            Inst::Call { target, args } => match self.function_name(*target) {
+                Some(name) => writeln!(f, "call {}({})", name, list(args)),
+                None => writeln!(f, "call @{}({})", target, list(args)),
+            },
+            Inst::Syscall { code, args } => match Op::from_code(*code) {
+                Some(op) => writeln!(f, "{}({})", op.name().to_lowercase(), list(args)),
+                None => writeln!(f, "host {}({})", code, list(args)),
+            },
+            Inst::Phi(incoming) => {
+                let incoming: Vec<String> = incoming.iter().map(|(b, v)| format!("b{}: v{}", b, v)).collect();
+                writeln!(f, "phi [{}]", incoming.join(", "))
+            }
+        }
+    }
+}
+
+// The IR as text, one function after another
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, function) in self.functions.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "function {} (text {}, frame {}):", function.name, function.address, function.frame)?;
+            for (b, block) in function.blocks.iter().enumerate() {
+                let preds: Vec<String> = block.preds.iter().map(|p| format!("b{}", p)).collect();
+                if preds.is_empty() {
+                    writeln!(f, "  b{}:", b)?;
+                } else {
+                    writeln!(f, "  b{}: ; from {}", b, preds.join(", "))?;
+                }
+                for &id in &block.insts {
+                    self.write_value(f, function, id)?;
+                }
+                match block.term {
+                    Terminator::Jump(target) => writeln!(f, "    jmp b{}", target)?,
+                    Terminator::Branch { cond, nonzero, zero } => writeln!(f, "    br v{}, b{}, b{}", cond, nonzero, zero)?,
+                    Terminator::Return(v) => writeln!(f, "    ret v{}", v)?,
+                    Terminator::Exit(v) => writeln!(f, "    exit v{}", v)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile;
+
+    fn module(source: &str) -> Module {
+        Module::from_program(&compile(source).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_dump() {
+        let module = module("int sq(int x) { return x * x; } int main() { return sq(3); }");
+        let expected = "\
+function sq (text 0, frame 0):
+  b0:
+    v0 = undef
+    v1 = param 2
+    v2 = mul v1, v1
+    ret v2
+
+function main (text 12, frame 0):
+  b0:
+    v0 = undef
+    v1 = const 3
+    v2 = call sq(v1)
+    ret v2
+";
+        assert_eq!(module.to_string(), expected);
+    }
+
+    #[test]
+    fn test_loop_gets_phis() {
+        let module = module("int main() { int i; int s; i = 0; s = 0; while (i < 10) { s = s + i; i = i + 1; } return s; }");
+        let main = &module.functions[0];
+        // The locals are values, not memory
+        assert!(main.values.iter().all(|inst| !matches!(inst, Inst::Load { .. } | Inst::Store { .. } | Inst::LocalAddr(_))));
+        // The loop header joins the way in and the way round, with a phi for i and s
+        let header = main.blocks.iter().find(|block| block.preds.len() == 2).unwrap();
+        let phis = header.insts.iter().filter(|&&id| matches!(main.values[id], Inst::Phi(_))).count();
+        assert!(phis >= 2);
+    }
+
+    #[test]
+    fn test_address_taken_local_stays_in_memory() {
+        let module = module("int main() { int x; int *p; p = &x; *p = 3; return x; }");
+        let main = &module.functions[0];
+        assert!(main.values.contains(&Inst::LocalAddr(-1))); // x lives at bp - 1 word
+        assert!(!main.values.contains(&Inst::LocalAddr(-2))); // p doesn't need to
+        assert!(main.values.iter().any(|inst| matches!(inst, Inst::Store { char: false, .. })));
+    }
+}
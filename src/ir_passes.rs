@@ -0,0 +1,372 @@
+// Optimisation passes over the SSA IR in ir.rs:
+//
+// - copy propagation: uses of a copy use the copied value, and a phi whose inputs are all
+//   the same value is that value
+// - common subexpression elimination (CSE): a pure instruction that was already worked out
+//   by an instruction that always runs first (one in a dominating block) is not worked out again
+// - loop-invariant code motion (LICM): a pure instruction in a loop whose inputs are all made
+//   outside the loop moves to just before the loop
+// - dead values: pure instructions nobody uses are dropped
+use crate::ir::{Block, BlockId, Function, Inst, Module, Terminator, ValueId};
+use crate::opcode::Op;
+use std::collections::{HashMap, HashSet};
+
+// The immediate dominator of each block: the nearest block every path from the entry to it
+// goes through. The entry is its own. Uses Cooper, Harvey and Kennedy's simple algorithm.
+pub fn dominators(function: &Function) -> Vec<BlockId> {
+    let n = function.blocks.len();
+
+    // Number the blocks in reverse postorder
+    let mut postorder = Vec::new();
+    let mut seen = vec![false; n];
+    seen[0] = true;
+    let mut stack = vec![(0, function.blocks[0].term.successors(), 0)];
+    while let Some((b, succs, i)) = stack.last_mut() {
+        if *i < succs.len() {
+            let s = succs[*i];
+            *i += 1;
+            if !seen[s] {
+                seen[s] = true;
+                stack.push((s, function.blocks[s].term.successors(), 0));
+            }
+        } else {
+            postorder.push(*b);
+            stack.pop();
+        }
+    }
+    let mut rank = vec![usize::MAX; n];
+    for (i, &b) in postorder.iter().enumerate() {
+        rank[b] = i; // Higher is nearer the entry
+    }
+
+    let mut idom = vec![usize::MAX; n];
+    idom[0] = 0;
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &b in postorder.iter().rev().skip(1) {
+            let mut new = usize::MAX;
+            for &p in &function.blocks[b].preds {
+                if idom[p] == usize::MAX {
+                    continue;
+                }
+                new = if new == usize::MAX {
+                    p
+                } else {
+                    let (mut x, mut y) = (p, new);
+                    while x != y {
+                        while rank[x] < rank[y] {
+                            x = idom[x];
+                        }
+                        while rank[y] < rank[x] {
+                            y = idom[y];
+                        }
+                    }
+                    x
+                };
+            }
+            if idom[b] != new {
+                idom[b] = new;
+                changed = true;
+            }
+        }
+    }
+    idom
+}
+
+// Does block a dominate block b?
+pub fn dominates(idom: &[BlockId], a: BlockId, mut b: BlockId) -> bool {
+    loop {
+        if a == b {
+            return true;
+        }
+        if b == 0 || idom[b] == usize::MAX {
+            return false;
+        }
+        b = idom[b];
+    }
+}
+
+// Replace copies and phis that don't choose anything by the value they stand for.
+// Gives back how many were removed.
+pub fn copy_propagation(function: &mut Function) -> usize {
+    let mut removed = 0;
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in 0..function.blocks.len() {
+            for id in function.blocks[b].insts.clone() {
+                let same = match &function.values[id] {
+                    Inst::Copy(v) => Some(*v),
+                    Inst::Phi(incoming) => {
+                        let mut inputs = incoming.iter().map(|&(_, v)| v).filter(|&v| v != id);
+                        let first = inputs.next();
+                        first.filter(|&first| inputs.all(|v| v == first))
+                    }
+                    _ => None,
+                };
+                if let Some(value) = same {
+                    function.replace_uses(id, value);
+                    function.blocks[b].insts.retain(|&i| i != id);
+                    removed += 1;
+                    changed = true;
+                }
+            }
+        }
+    }
+    removed
+}
+
+// What a pure instruction computes, so two that compute the same thing can be matched
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Const(i64),
+    Param(i64),
+    LocalAddr(i64),
+    Binary(Op, ValueId, ValueId),
+}
+
+fn key(inst: &Inst) -> Option<Key> {
+    match *inst {
+        Inst::Const(n) => Some(Key::Const(n)),
+        Inst::Param(n) => Some(Key::Param(n)),
+        Inst::LocalAddr(n) => Some(Key::LocalAddr(n)),
+        Inst::Binary(op, a, b) if inst.is_pure() => {
+            // a + b is b + a, so put the operands of these in order
+            let swaps = matches!(op, Op::Add | Op::Mul | Op::And | Op::Or | Op::Xor | Op::Eq | Op::Ne);
+            Some(if swaps && b < a { Key::Binary(op, b, a) } else { Key::Binary(op, a, b) })
+        }
+        _ => None,
+    }
+}
+
+// Common subexpression elimination over the dominator tree. Gives back how many instructions
+// were removed.
+pub fn cse(function: &mut Function) -> usize {
+    let idom = dominators(function);
+    let mut children = vec![Vec::new(); function.blocks.len()];
+    for b in 1..function.blocks.len() {
+        if idom[b] != usize::MAX {
+            children[idom[b]].push(b);
+        }
+    }
+
+    // Walk the tree, knowing what the blocks above have worked out
+    fn walk(function: &mut Function, children: &[Vec<BlockId>], b: BlockId, known: &mut HashMap<Key, ValueId>, removed: &mut usize) {
+        let mut added = Vec::new();
+        for id in function.blocks[b].insts.clone() {
+            let Some(key) = key(&function.values[id]) else {
+                continue;
+            };
+            match known.get(&key) {
+                Some(&earlier) => {
+                    function.replace_uses(id, earlier);
+                    function.blocks[b].insts.retain(|&i| i != id);
+                    *removed += 1;
+                }
+                None => {
+                    known.insert(key, id);
+                    added.push(key);
+                }
+            }
+        }
+        for &child in &children[b] {
+            walk(function, children, child, known, removed);
+        }
+        for key in added {
+            known.remove(&key);
+        }
+    }
+
+    let mut removed = 0;
+    walk(function, &children, 0, &mut HashMap::new(), &mut removed);
+    removed
+}
+
+// Loop-invariant code motion. Gives back how many instructions were moved out of loops.
+pub fn licm(function: &mut Function) -> usize {
+    let idom = dominators(function);
+
+    // Find the loops: an edge to a block that dominates where it comes from goes back to the
+    // loop's header, and the loop is every block that can get there without the header
+    let mut loops: Vec<(BlockId, HashSet<BlockId>)> = Vec::new();
+    for b in 0..function.blocks.len() {
+        for header in function.blocks[b].term.successors() {
+            if !dominates(&idom, header, b) {
+                continue;
+            }
+            let mut body = HashSet::from([header]);
+            let mut work = vec![b];
+            while let Some(x) = work.pop() {
+                if body.insert(x) {
+                    work.extend(function.blocks[x].preds.iter().copied());
+                }
+            }
+            match loops.iter_mut().find(|(h, _)| *h == header) {
+                Some((_, existing)) => existing.extend(body),
+                None => loops.push((header, body)),
+            }
+        }
+    }
+    loops.sort_by_key(|(_, body)| body.len()); // Inner loops first
+
+    let mut moved = 0;
+    for i in 0..loops.len() {
+        let (header, body) = loops[i].clone();
+        let outside: Vec<BlockId> = function.blocks[header].preds.iter().copied().filter(|p| !body.contains(p)).collect();
+        let &[from] = outside.as_slice() else {
+            continue;
+        };
+
+        // The code goes at the end of the block that leads into the loop. If that block can
+        // also go somewhere else, put a new block between it and the header.
+        let preheader = if function.blocks[from].term.successors().len() == 1 {
+            from
+        } else {
+            let new = function.blocks.len();
+            function.blocks.push(Block { address: 0, insts: Vec::new(), term: Terminator::Jump(header), preds: vec![from] });
+            if let Terminator::Branch { nonzero, zero, .. } = &mut function.blocks[from].term {
+                for target in [nonzero, zero] {
+                    if *target == header {
+                        *target = new;
+                    }
+                }
+            }
+            for pred in &mut function.blocks[header].preds {
+                if *pred == from {
+                    *pred = new;
+                }
+            }
+            for &id in &function.blocks[header].insts {
+                if let Inst::Phi(incoming) = &mut function.values[id] {
+                    for (pred, _) in incoming {
+                        if *pred == from {
+                            *pred = new;
+                        }
+                    }
+                }
+            }
+            for (_, outer) in &mut loops[i + 1..] {
+                if outer.contains(&from) {
+                    outer.insert(new);
+                }
+            }
+            new
+        };
+
+        let mut blocks: Vec<BlockId> = body.iter().copied().collect();
+        blocks.sort();
+        let mut where_is = function.value_blocks();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in &blocks {
+                for id in function.blocks[b].insts.clone() {
+                    let inst = &function.values[id];
+                    let invariant = inst.is_pure()
+                        && !matches!(inst, Inst::Phi(_) | Inst::Copy(_))
+                        && inst.operands().iter().all(|&v| where_is[v].is_none_or(|at| !body.contains(&at)));
+                    if invariant {
+                        function.blocks[b].insts.retain(|&i| i != id);
+                        function.blocks[preheader].insts.push(id);
+                        where_is[id] = Some(preheader);
+                        moved += 1;
+                        changed = true;
+                    }
+                }
+            }
+        }
+    }
+    moved
+}
+
+// Drop pure instructions whose values are never used. Gives back how many were dropped.
+pub fn remove_dead(function: &mut Function) -> usize {
+    let mut removed = 0;
+    loop {
+        let uses = function.use_counts();
+        let before = removed;
+        for b in 0..function.blocks.len() {
+            let values = &function.values;
+            let block = &mut function.blocks[b];
+            let len = block.insts.len();
+            block.insts.retain(|&id| !(values[id].is_pure() && uses[id] == 0));
+            removed += len - block.insts.len();
+        }
+        if removed == before {
+            return removed;
+        }
+    }
+}
+
+// Run every pass on every function. Gives back how much each pass did.
+pub fn optimize(module: &mut Module) -> HashMap<&'static str, usize> {
+    let mut done = HashMap::new();
+    for function in &mut module.functions {
+        *done.entry("copy-propagation").or_default() += copy_propagation(function);
+        *done.entry("cse").or_default() += cse(function);
+        *done.entry("licm").or_default() += licm(function);
+        *done.entry("copy-propagation").or_default() += copy_propagation(function);
+        *done.entry("dead-values").or_default() += remove_dead(function);
+    }
+    done
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile;
+
+    // Build the IR for f (and an empty main) and optimise it
+    fn optimized(source: &str) -> (Module, HashMap<&'static str, usize>) {
+        let source = format!("{} int main() {{ return 0; }}", source);
+        let mut module = Module::from_program(&compile(&source).unwrap()).unwrap();
+        let done = optimize(&mut module);
+        (module, done)
+    }
+
+    // The instructions left in a function's blocks
+    fn insts(function: &Function) -> Vec<&Inst> {
+        function.blocks.iter().flat_map(|block| &block.insts).map(|&id| &function.values[id]).collect()
+    }
+
+    #[test]
+    fn test_copy_propagation() {
+        let (module, done) = optimized("int f(int a) { int b; int c; b = a; c = b; return c; }");
+        assert!(done["copy-propagation"] >= 2);
+        let f = &module.functions[0];
+        assert_eq!(insts(f), vec![&Inst::Param(2)]);
+        assert_eq!(f.blocks[0].term, Terminator::Return(f.blocks[0].insts[0]));
+    }
+
+    #[test]
+    fn test_cse() {
+        let (module, done) = optimized("int f(int a, int b) { return a * b + b * a; }");
+        assert!(done["cse"] >= 1);
+        let muls = insts(&module.functions[0]).into_iter().filter(|inst| matches!(inst, Inst::Binary(Op::Mul, _, _))).count();
+        assert_eq!(muls, 1);
+    }
+
+    #[test]
+    fn test_licm() {
+        let source = "int f(int n) { int i; int s; i = 0; s = 0; while (i < 10) { s = s + n * 4; i = i + 1; } return s; }";
+        let (module, done) = optimized(source);
+        assert!(done["licm"] >= 1);
+        let f = &module.functions[0];
+        let idom = dominators(f);
+        let header = (0..f.blocks.len()).find(|&b| f.blocks[b].preds.iter().any(|&p| dominates(&idom, b, p))).unwrap();
+        let mul = f.blocks.iter().position(|block| block.insts.iter().any(|&id| matches!(f.values[id], Inst::Binary(Op::Mul, _, _)))).unwrap();
+        // n * 4 is worked out once, before the loop
+        assert!(dominates(&idom, mul, header) && mul != header);
+    }
+
+    #[test]
+    fn test_division_stays_in_loop() {
+        // Moving a / b out of the loop would fault when the loop never runs
+        let source = "int f(int a, int b) { int i; int s; i = 0; s = 0; while (i < b) { s = s + a / b; i = i + 1; } return s; }";
+        let (module, _) = optimized(source);
+        let f = &module.functions[0];
+        let div = f.blocks.iter().position(|block| block.insts.iter().any(|&id| matches!(f.values[id], Inst::Binary(Op::Div, _, _)))).unwrap();
+        assert!(f.blocks[div].preds.len() == 1 && div != 0);
+    }
+}
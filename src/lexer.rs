@@ -1,6 +1,19 @@
 use crate::token::Token;
 use std::collections::HashMap;
 
+// A place in the source code. Lines and columns both count from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
 // This is the structure of our lexer. It reads the input code one character at a time.
 pub struct Lexer<'a> {
     chars: std::str::Chars<'a>,         // The iterator over characters in the input
@@ -8,6 +21,7 @@ pub struct Lexer<'a> {
     pub keywords: HashMap<String, Token>, // A list of reserved words like 'if', 'return', etc.
     pub line: usize,                    // Keeps track of the current line number (for debugging)
     pub col: usize,                     // Keeps track of the current column
+    pub start: Span,                    // Where the token we just read starts
 }
 
 impl<'a> Lexer<'a> {
@@ -20,12 +34,13 @@ impl<'a> Lexer<'a> {
         let mut keywords = HashMap::new();
         keywords.insert("if".to_string(), Token::If);
         keywords.insert("else".to_string(), Token::Else);
+        keywords.insert("enum".to_string(), Token::Enum);
         keywords.insert("int".to_string(), Token::Int);
         keywords.insert("char".to_string(), Token::Char);
         keywords.insert("return".to_string(), Token::Return);
         keywords.insert("while".to_string(), Token::While);
         keywords.insert("sizeof".to_string(), Token::Sizeof);
-        keywords.insert("printf".to_string(), Token::Printf);
+        keywords.insert("void".to_string(), Token::Char); // C4 treats void like char
 
         Lexer {
             chars,
@@ -33,6 +48,7 @@ impl<'a> Lexer<'a> {
             keywords,
             line: 1,
             col: 0,
+            start: Span { line: 1, col: 1 },
         }
     }
 
@@ -66,9 +82,28 @@ impl<'a> Lexer<'a> {
         result
     }
 
+    // Reads one character inside a string or char literal, turning escapes like \n into the real character
+    fn read_literal_char(&mut self) -> Option<char> {
+        let c = self.current?;
+        self.advance();
+        if c != '\\' {
+            return Some(c);
+        }
+        let escaped = self.current?;
+        self.advance();
+        Some(match escaped {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            other => other, // \\, \", \' and anything else stand for themselves
+        })
+    }
+
     // This is the core function that returns the next token (e.g. number, keyword, operator)
     pub fn next_token(&mut self) -> Option<Token> {
         while let Some(c) = self.current {
+            self.start = Span { line: self.line, col: self.col + 1 };
             match c {
                 // Skip whitespace and move to the next character
                 ' ' | '\n' | '\r' | '\t' => {
@@ -76,10 +111,29 @@ impl<'a> Lexer<'a> {
                     continue;
                 }
 
-                // If it's a digit, collect all digits and return a Num token
+                // Skip preprocessor lines like #include, C4 doesn't have a preprocessor
+                '#' => {
+                    self.collect_while(|ch| ch != '\n');
+                    continue;
+                }
+
+                // If it's a digit, collect the number: decimal, hex (0x1f) or octal (017)
                 '0'..='9' => {
-                    let num_str = self.collect_while(|ch| ch.is_ascii_digit());
-                    let val = num_str.parse::<i64>().unwrap();
+                    let num_str = self.collect_while(|ch| ch.is_ascii_alphanumeric());
+                    let (digits, radix) = if let Some(hex) = num_str.strip_prefix("0x").or(num_str.strip_prefix("0X")) {
+                        (hex, 16)
+                    } else if num_str.len() > 1 && num_str.starts_with('0') {
+                        (&num_str[1..], 8)
+                    } else {
+                        (num_str.as_str(), 10)
+                    };
+                    let mut val: i64 = 0;
+                    for digit in digits.chars() {
+                        match digit.to_digit(radix) {
+                            Some(d) => val = val.wrapping_mul(radix as i64).wrapping_add(d as i64),
+                            None => break, // C4 stops at the first character that isn't a digit
+                        }
+                    }
                     return Some(Token::Num(val));
                 }
 
@@ -93,6 +147,29 @@ impl<'a> Lexer<'a> {
                     }
                 }
 
+                // A string literal like "hello\n"
+                '"' => {
+                    self.advance(); // skip the opening quote
+                    let mut text = String::new();
+                    while self.current.is_some() && self.current != Some('"') {
+                        if let Some(ch) = self.read_literal_char() {
+                            text.push(ch);
+                        }
+                    }
+                    self.advance(); // skip the closing quote
+                    return Some(Token::Str(text));
+                }
+
+                // A character literal like 'a' is just a number
+                '\'' => {
+                    self.advance(); // skip the opening quote
+                    let value = self.read_literal_char().unwrap_or('\0');
+                    if self.current == Some('\'') {
+                        self.advance(); // skip the closing quote
+                    }
+                    return Some(Token::Num(value as i64));
+                }
+
                 // Operators and symbols
                 '=' => {
                     self.advance();
@@ -108,7 +185,7 @@ impl<'a> Lexer<'a> {
                         self.advance();
                         return Some(Token::Ne);
                     }
-                    return Some(Token::Unknown('!'));
+                    return Some(Token::Not);
                 }
                 '<' => {
                     self.advance();
@@ -170,12 +247,48 @@ impl<'a> Lexer<'a> {
                 }
                 '/' => {
                     self.advance();
+                    if self.current == Some('/') {
+                        self.collect_while(|ch| ch != '\n'); // a // comment runs to the end of the line
+                        continue;
+                    }
                     return Some(Token::Div);
                 }
                 '%' => {
                     self.advance();
                     return Some(Token::Mod);
                 }
+                '^' => {
+                    self.advance();
+                    return Some(Token::Xor);
+                }
+                '?' => {
+                    self.advance();
+                    return Some(Token::Cond);
+                }
+                '[' => {
+                    self.advance();
+                    return Some(Token::Brak);
+                }
+                ']' => {
+                    self.advance();
+                    return Some(Token::RBrak);
+                }
+                '~' => {
+                    self.advance();
+                    return Some(Token::Tilde);
+                }
+                '{' => {
+                    self.advance();
+                    return Some(Token::LBrace);
+                }
+                '}' => {
+                    self.advance();
+                    return Some(Token::RBrace);
+                }
+                ':' => {
+                    self.advance();
+                    return Some(Token::Colon);
+                }
                 '(' => {
                     self.advance();
                     return Some(Token::LParen);
@@ -188,6 +301,10 @@ impl<'a> Lexer<'a> {
                     self.advance();
                     return Some(Token::Semicolon);
                 }
+                ',' => {
+                    self.advance();
+                    return Some(Token::Comma);
+                }
 
                 // If it's something we don't recognize, return it as Unknown
                 _ => {
@@ -198,6 +315,7 @@ impl<'a> Lexer<'a> {
         }
 
         // End of input
+        self.start = Span { line: self.line, col: self.col + 1 };
         Some(Token::Eof)
     }
 }
@@ -0,0 +1,248 @@
+// The C4 compiler and virtual machine as a library.
+//
+// The simple way in is compile() and Program::run():
+//
+//     let program = c4::compile("int main() { printf(\"hi\\n\"); return 0; }")?;
+//     let status = program.run(c4::RunConfig::default())?;
+//
+// The modules underneath (lexer, parser, vm, ...) are public too, for tools that need
+// more control, like the command-line program, listings and the debugger.
+pub mod asm;
+#[cfg(test)]
+mod backend_tests;
+pub mod bytecode;
+pub mod csource;
+pub mod dce;
+pub mod debugger;
+pub mod diagnostic;
+mod fast;
+pub mod host;
+pub mod ir;
+pub mod ir_passes;
+pub mod lexer;
+pub mod listing;
+pub mod memory;
+pub mod native;
+pub mod opcode;
+pub mod parser;
+pub mod peephole;
+pub mod regvm;
+pub mod token;
+pub mod trace;
+pub mod vm;
+
+pub use bytecode::LoadError;
+pub use diagnostic::{Diagnostic, Diagnostics, Severity};
+pub use host::{FileSystem, HostFileSystem, HostFunctions, MemoryFileSystem, NativeFunction, SharedBuffer};
+pub use lexer::{Lexer, Span};
+pub use memory::{Memory, MemoryConfig, MemoryError, MemoryFault};
+pub use parser::Parser;
+pub use token::Token;
+pub use vm::{Limits, VmError, VmErrorKind, VmState, VM};
+
+use std::io::{Read, Write};
+use std::ops::Range;
+use token::Class;
+
+// A compiled program, ready to run as many times as you like
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Program {
+    pub text: Vec<i64>,            // The instructions
+    pub data: Vec<u8>,             // The starting data segment: globals and string literals
+    pub entry: usize,              // Where main() starts in the text
+    pub spans: Vec<Span>,          // Where in the source each word of text came from
+    pub functions: Vec<(String, Range<usize>)>, // Every function and where its code is, by address
+    pub globals: Vec<(String, i64)>,             // Every global variable and its address, by address
+    pub imports: Vec<(String, i64)>,             // The host functions it was compiled with, and their numbers
+    pub warnings: Vec<Diagnostic>, // Things worth knowing that didn't stop the compile
+}
+
+// How to compile a program. The default makes the same code as C4.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompileOptions {
+    pub optimize: bool, // Make faster code (-O): fold constants and run the peephole optimiser
+}
+
+// How to run a program. The default runs it like the command line does, with no arguments.
+#[derive(Default)]
+pub struct RunConfig {
+    pub memory: MemoryConfig,                     // How big each memory segment is
+    pub limits: Limits,                           // When to stop a program that runs away
+    pub args: Vec<String>,                        // argv for main, argv[0] is usually the program's name
+    pub output: Option<Box<dyn Write>>,           // Where printf writes (stdout if None)
+    pub input: Option<Box<dyn Read>>,             // Where read(0, ...) reads from (stdin if None)
+    pub file_system: Option<Box<dyn FileSystem>>, // Where open() finds files (the host's if None)
+    pub host_functions: HostFunctions,            // The host functions the program was compiled with, in any order
+}
+
+// How a program finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatus {
+    pub code: i64,   // What main returned, or what was passed to exit()
+    pub cycles: u64, // How many instructions ran
+}
+
+// Compile C source into a program. Gives back every diagnostic if it doesn't compile.
+pub fn compile(source: &str) -> Result<Program, Diagnostics> {
+    compile_with_host(source, &HostFunctions::new())
+}
+
+// Compile C source that can call the given host functions.
+// Pass the same functions to RunConfig::host_functions when running it.
+pub fn compile_with_host(source: &str, host: &HostFunctions) -> Result<Program, Diagnostics> {
+    compile_with(source, host, CompileOptions::default())
+}
+
+// Compile C source with the given host functions and options
+pub fn compile_with(source: &str, host: &HostFunctions, options: CompileOptions) -> Result<Program, Diagnostics> {
+    let mut parser = Parser::new(Lexer::new(source));
+    parser.declare_host_functions(host);
+    parser.optimize = options.optimize;
+    if let Err(error) = parser.parse_program() {
+        let mut list = parser.warnings;
+        list.push(error);
+        return Err(Diagnostics { list });
+    }
+    let span = Span { line: parser.lexer.line, col: parser.lexer.col };
+    let mut list = parser.warnings.clone();
+    let mut program = Program::from_parser(parser).ok_or_else(|| {
+        list.push(Diagnostic::error("main() not defined".to_string(), span));
+        Diagnostics { list }
+    })?;
+    if options.optimize {
+        program.optimize();
+    }
+    Ok(program)
+}
+
+impl Program {
+    // Take what a parser made of a whole program. Gives back None if there is no main().
+    pub fn from_parser(parser: Parser) -> Option<Program> {
+        let entry = parser.entry()?;
+        let functions = parser.functions();
+        let mut globals = Vec::new();
+        let mut imports = Vec::new();
+        for (name, symbol) in &parser.symbols {
+            match symbol.class {
+                Class::Glo => globals.push((name.clone(), symbol.val)),
+                Class::Sys if symbol.val >= host::FIRST_HOST_CODE => imports.push((name.clone(), symbol.val)),
+                _ => {}
+            }
+        }
+        globals.sort_by_key(|&(_, address)| address);
+        imports.sort_by_key(|&(_, code)| code);
+        Some(Program { text: parser.text, data: parser.data, entry, spans: parser.spans, functions, globals, imports, warnings: parser.warnings })
+    }
+
+    // Make the program smaller and faster without changing what it does (-O): take out the
+    // code that can never run, then apply the peephole rules
+    pub fn optimize(&mut self) {
+        dce::eliminate(self);
+        peephole::optimize(self);
+    }
+
+    // Where the code of the function with this name is in the text
+    pub fn function_range(&self, name: &str) -> Option<Range<usize>> {
+        self.functions.iter().find(|(n, _)| n == name).map(|(_, range)| range.clone())
+    }
+
+    // Make a VM that is ready to run this program with the given configuration
+    pub fn vm(&self, config: RunConfig) -> Result<VM, VmError> {
+        let mut vm = VM::with_config(self.text.clone(), self.data.clone(), self.entry, config.memory)?;
+        vm.limits = config.limits;
+        vm.host_functions = config.host_functions;
+        // The functions may have been registered in another order than when it was compiled
+        if let Err(code) = vm.host_functions.arrange(&self.imports) {
+            return Err(VmError { kind: VmErrorKind::HostFunctionMissing { code }, pc: self.entry, code: None });
+        }
+        if let Some(output) = config.output {
+            vm.output = output;
+        }
+        if let Some(input) = config.input {
+            vm.input = input;
+        }
+        if let Some(file_system) = config.file_system {
+            vm.file_system = file_system;
+        }
+        vm.set_args(&config.args)?;
+        Ok(vm)
+    }
+
+    // Run the program to the end
+    pub fn run(&self, config: RunConfig) -> Result<ExitStatus, VmError> {
+        let mut vm = self.vm(config)?;
+        let code = vm.run()?;
+        Ok(ExitStatus { code, cycles: vm.cycle })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile_and_run() {
+        let program = compile("int main(int argc, char **argv) { printf(\"%s!\\n\", argv[1]); return argc; }").unwrap();
+        let output = SharedBuffer::new();
+        let config = RunConfig {
+            args: vec!["prog".to_string(), "hello".to_string()],
+            output: Some(Box::new(output.clone())),
+            ..RunConfig::default()
+        };
+        let status = program.run(config).unwrap();
+        assert_eq!(status.code, 2);
+        assert!(status.cycles > 0);
+        assert_eq!(output.text(), "hello!\n");
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let diagnostics = compile("int main() { return 1 }").unwrap_err();
+        assert!(diagnostics.has_errors());
+        assert_eq!(diagnostics.to_string(), "Syntax Error: semicolon expected (found '}') at line 1, col 23");
+
+        let diagnostics = compile("int f() { return 1; }").unwrap_err();
+        assert_eq!(diagnostics.errors().next().unwrap().message, "main() not defined");
+    }
+
+    #[test]
+    fn test_run_faults() {
+        let program = compile("int main() { return *(int *)0; }").unwrap();
+        let error = program.run(RunConfig::default()).unwrap_err();
+        assert!(matches!(error.kind, VmErrorKind::InvalidMemoryAccess { address: 0, len: 8 }));
+    }
+
+    #[test]
+    fn test_host_functions_by_name() {
+        let mut host = HostFunctions::new();
+        host.register("add", 2, |_, args| Ok(args[0] + args[1]));
+        host.register("twice", 1, |_, args| Ok(args[0] * 2));
+        let program = compile_with_host("int main() { return add(twice(5), 1); }", &host).unwrap();
+
+        // Registered in the other order, the names still find the right functions
+        let mut host = HostFunctions::new();
+        host.register("twice", 1, |_, args| Ok(args[0] * 2));
+        host.register("add", 2, |_, args| Ok(args[0] + args[1]));
+        let status = program.run(RunConfig { host_functions: host, ..RunConfig::default() }).unwrap();
+        assert_eq!(status.code, 11);
+
+        let mut host = HostFunctions::new();
+        let twice = host.register("twice", 1, |_, args| Ok(args[0] * 2));
+        let error = program.run(RunConfig { host_functions: host, ..RunConfig::default() }).unwrap_err();
+        assert_eq!(error.kind, VmErrorKind::HostFunctionMissing { code: twice });
+        assert_eq!(error.to_string(), format!("host function {} that the program was compiled with isn't registered", twice));
+    }
+
+    #[test]
+    fn test_run_does_not_fit() {
+        let program = compile("char *s; int main() { s = \"hello\"; return 0; }").unwrap();
+        let run = |memory| program.run(RunConfig { memory, ..RunConfig::default() }).unwrap_err().kind;
+
+        let memory = MemoryConfig { data_size: 4, ..MemoryConfig::default() };
+        assert!(matches!(run(memory), VmErrorKind::Memory(MemoryError::DataTooBig { limit: 4, .. })));
+        let memory = MemoryConfig { heap_size: usize::MAX, ..MemoryConfig::default() };
+        assert_eq!(run(memory), VmErrorKind::Memory(MemoryError::SegmentsTooBig));
+        let memory = MemoryConfig { stack_size: 16, ..MemoryConfig::default() };
+        assert_eq!(run(memory), VmErrorKind::StackOverflow);
+    }
+}
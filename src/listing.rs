@@ -0,0 +1,178 @@
+use crate::memory::DATA_BASE;
+use crate::opcode::Op;
+use crate::parser::Parser;
+use crate::token::Class;
+use std::collections::HashMap;
+
+// C4's -s listing: each source line, followed by the instructions that were made while
+// the lexer was on that line. Instruction names are printed with C4's "%8.4s".
+pub fn c4_listing(source: &str, parser: &Parser) -> String {
+    let (text, spans) = (&parser.text, &parser.spans);
+    let mut out = String::new();
+    let mut i = 0;
+    for (number, line) in source.split_inclusive('\n').enumerate() {
+        let number = number + 1;
+        out += &format!("{}: {}", number, line);
+        if !line.ends_with('\n') {
+            out.push('\n');
+        }
+        while i < text.len() && spans[i].line <= number {
+            let (name, has_operand) = instruction(parser, text[i]);
+            let name = format!("{:<4}", name);
+            if has_operand {
+                out += &format!("{:>8} {}\n", name, text[i + 1]);
+                i += 2;
+            } else {
+                out += &format!("{:>8}\n", name);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+// The name of the instruction with this number and whether an operand follows it.
+// Host functions are named after the function.
+fn instruction(parser: &Parser, code: i64) -> (String, bool) {
+    if let Some(op) = Op::from_code(code) {
+        return (op.name().to_string(), op.has_operand());
+    }
+    let host = parser.symbols.iter().find(|(_, symbol)| symbol.class == Class::Sys && symbol.val == code);
+    (host.map_or("???".to_string(), |(name, _)| name.clone()), false)
+}
+
+// Names for the places in the text that something jumps to: functions keep their own
+// name, every other jump target gets a label L1, L2, ... in the order they appear.
+fn labels(parser: &Parser) -> HashMap<i64, String> {
+    let mut labels = HashMap::new();
+    for (name, symbol) in &parser.symbols {
+        if symbol.class == Class::Fun {
+            labels.insert(symbol.val, name.clone());
+        }
+    }
+
+    let mut targets = Vec::new();
+    let mut i = 0;
+    while i < parser.text.len() {
+        let code = parser.text[i];
+        if [Op::Jmp, Op::Bz, Op::Bnz].iter().any(|op| op.code() == code) {
+            targets.push(parser.text[i + 1]);
+        }
+        i += if instruction(parser, code).1 { 2 } else { 1 };
+    }
+    targets.sort();
+    targets.dedup();
+    let mut next = 1;
+    for target in targets {
+        labels.entry(target).or_insert_with(|| {
+            next += 1;
+            format!("L{}", next - 1)
+        });
+    }
+    labels
+}
+
+// What lives at a data segment address: a global's name or a string literal's text
+fn data_comment(parser: &Parser, address: i64) -> Option<String> {
+    for (name, symbol) in &parser.symbols {
+        if symbol.class == Class::Glo && symbol.val == address {
+            return Some(name.clone());
+        }
+    }
+    if parser.strings.contains(&address) {
+        let start = (address - DATA_BASE) as usize;
+        let end = start + parser.data[start..].iter().position(|&b| b == 0).unwrap_or(0);
+        return Some(format!("{:?}", String::from_utf8_lossy(&parser.data[start..end])));
+    }
+    None
+}
+
+// A listing that goes further than C4's -s: the source lines are shown as the code for them
+// starts, each instruction has its address and the line:col of the token the parser was on
+// when it made it, jumps and calls show the label they go to, and data addresses say which
+// global or string they point at.
+pub fn annotated_listing(source: &str, parser: &Parser) -> String {
+    let lines: Vec<&str> = source.lines().collect();
+    let labels = labels(parser);
+    let mut out = String::new();
+    let mut shown = 0; // How many source lines have been printed so far
+
+    let mut i = 0;
+    while i < parser.text.len() {
+        // Show the source up to the line this instruction came from
+        let span = parser.spans[i];
+        while shown < span.line.min(lines.len()) {
+            out += &format!("{:>6} | {}\n", shown + 1, lines[shown]);
+            shown += 1;
+        }
+
+        if let Some(label) = labels.get(&(i as i64)) {
+            out += &format!("{}:\n", label);
+        }
+
+        let position = span.to_string();
+        let Some(op) = Op::from_code(parser.text[i]).filter(|op| op.has_operand()) else {
+            out += &format!("{:>6}  {:<8} {}\n", i, position, instruction(parser, parser.text[i]).0);
+            i += 1;
+            continue;
+        };
+
+        let operand = parser.text[i + 1];
+        let (operand, comment) = match op {
+            Op::Jmp | Op::Bz | Op::Bnz | Op::Jsr => match labels.get(&operand) {
+                Some(label) => (label.clone(), None),
+                None => (operand.to_string(), None),
+            },
+            Op::Imm => (operand.to_string(), data_comment(parser, operand)),
+            _ => (operand.to_string(), None),
+        };
+        match comment {
+            Some(comment) => out += &format!("{:>6}  {:<8} {:<4} {:<12} ; {}\n", i, position, op, operand, comment),
+            None => out += &format!("{:>6}  {:<8} {:<4} {}\n", i, position, op, operand),
+        }
+        i += 2;
+    }
+
+    // Any source after the last instruction (usually just the closing brace)
+    while shown < lines.len() {
+        out += &format!("{:>6} | {}\n", shown + 1, lines[shown]);
+        shown += 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn parse(source: &str) -> Parser<'_> {
+        let mut parser = Parser::new(Lexer::new(source));
+        parser.parse_program().unwrap();
+        parser
+    }
+
+    #[test]
+    fn test_c4_listing_matches_c4() {
+        let source = "int main()\n{\n  return 7;\n}\n";
+        let parser = parse(source);
+        assert_eq!(
+            c4_listing(source, &parser),
+            "1: int main()\n2: {\n3:   return 7;\n    ENT  0\n    IMM  7\n    LEV \n4: }\n    LEV \n"
+        );
+    }
+
+    #[test]
+    fn test_annotated_listing() {
+        let source = "int n;\nint main() {\n  while (n) n = 0;\n  printf(\"hi\\n\");\n}\n";
+        let parser = parse(source);
+        let listing = annotated_listing(source, &parser);
+
+        assert!(listing.contains("     3 |   while (n) n = 0;\nmain:\n     0  3:3      ENT  0\nL1:\n"));
+        assert!(listing.contains("     5  3:13     BZ   L2\n"));
+        assert!(listing.contains("    13  4:3      JMP  L1\nL2:\n"));
+        assert!(listing.contains("; n\n"));
+        assert!(listing.contains("; \"hi\\n\"\n"));
+        assert!(!listing.contains("JSR")); // printf is a system call, not a function
+    }
+}
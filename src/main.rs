@@ -1,55 +1,399 @@
-// Import the modules we created for each part of the compiler
-mod lexer;
-mod parser;
-mod vm;
-mod token;
-
-// Bring important parts into scope
-use crate::lexer::Lexer;
-use crate::parser::Parser;
-use crate::vm::VM;
+// The compiler and VM live in the c4 library (src/lib.rs), this is the command-line tool
+use c4::asm::{assemble, disassemble};
+use c4::bytecode::is_bytecode;
+use c4::csource::c_source;
+use c4::debugger::Debugger;
+use c4::ir::Module;
+use c4::ir_passes;
+use c4::lexer::Lexer;
+use c4::listing::{annotated_listing, c4_listing};
+use c4::native;
+use c4::parser::Parser;
+use c4::trace::{parse_range, Trace};
+use c4::regvm::{RegProgram, RegisterVM};
+use c4::vm::{Limits, VM};
+use c4::{MemoryConfig, Program, RunConfig};
 use std::env;
 use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
 
-fn main() {
-    // Collect command-line arguments (expects: program_name source_file)
-    let args: Vec<String> = env::args().collect();
+// What was asked for on the command line
+#[derive(Debug, PartialEq)]
+struct Options {
+    debugger: bool,     // "debug" subcommand: run the program under the interactive debugger
+    source: bool,       // -s: print the source with the instructions made for each line, then stop
+    listing: bool,      // --listing: like -s, but with addresses, line:col, labels and data names
+    debug: bool,        // -d: print each instruction as it runs
+    assembly: bool,     // -S: print the program as C4 assembly instead of running it
+    optimize: bool,     // -O: fold constants, drop dead code and run the peephole optimiser
+    dump_ir: bool,      // --dump-ir: print the SSA IR built from the bytecode (optimised with -O) instead of running it
+    register_vm: bool,  // --register-vm: run the program on the register VM instead of the stack VM
+    x86: bool,          // --x86: print the program as x86-64 assembly instead of running it
+    c_source: bool,     // --c-source: print the program as standalone C instead of running it
+    native: Option<String>, // --native=<file>: build a native executable instead of running the program
+    summary: bool,      // --summary: print C4's "exit(%d) cycle = %d" line when the program ends
+    trace: Option<Option<String>>, // --trace[=file]: log every instruction, to stderr or to a file
+    trace_only: Option<String>,    // --trace-only=<function|start..end>: only log these instructions
+    limits: Limits,     // --max-cycles, --max-depth, --max-heap and --max-files
+    output: Option<String>, // -o <file>: save the compiled program as bytecode instead of running it
+    program_args: Vec<String>, // The source file and the arguments for the program's main
+}
 
-    // If no input file is given, show usage message and stop
-    if args.len() < 2 {
-        eprintln!("Usage: {} <source.c>", args[0]);
-        return;
+// Read the options, which come before the source file like in C4.
+// Gives back a message for the user if the command line is wrong.
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let name = args.first().map(String::as_str).unwrap_or("c4");
+    let usage = format!(
+        concat!(
+            "Usage: {} [debug] [-s] [-d] [-S] [-O] [-o prog.c4b] [--listing] [--dump-ir] [--register-vm] ",
+            "[--x86] [--native=exe] [--c-source] [--summary] [--trace[=file]] ",
+            "[--trace-only=<function|start..end>] [--max-cycles=N] [--max-depth=N] [--max-heap=N] ",
+            "[--max-files=N] <source.c|prog.s|prog.c4b> [args...]",
+        ),
+        name
+    );
+
+    let mut options = Options {
+        debugger: false,
+        source: false,
+        listing: false,
+        debug: false,
+        assembly: false,
+        optimize: false,
+        dump_ir: false,
+        register_vm: false,
+        x86: false,
+        c_source: false,
+        native: None,
+        summary: false,
+        trace: None,
+        trace_only: None,
+        limits: Limits::default(),
+        output: None,
+        program_args: Vec::new(),
+    };
+    let mut next = 1;
+    if args.get(1).is_some_and(|arg| arg == "debug") {
+        options.debugger = true;
+        next = 2;
+    }
+    while next < args.len() && args[next].starts_with('-') {
+        match args[next].as_str() {
+            "-s" => options.source = true,
+            "-d" => options.debug = true,
+            "-S" => options.assembly = true,
+            "-O" => options.optimize = true,
+            "--listing" => options.listing = true,
+            "--dump-ir" => options.dump_ir = true,
+            "--register-vm" => options.register_vm = true,
+            "--x86" => options.x86 = true,
+            "--c-source" => options.c_source = true,
+            arg if arg.starts_with("--native=") => options.native = Some(arg["--native=".len()..].to_string()),
+            "-o" => {
+                next += 1;
+                let Some(path) = args.get(next) else {
+                    return Err(format!("-o needs a file name\n{}", usage));
+                };
+                options.output = Some(path.clone());
+            }
+            "--summary" => options.summary = true,
+            "--trace" => options.trace = Some(None),
+            arg if arg.starts_with("--trace=") => options.trace = Some(Some(arg["--trace=".len()..].to_string())),
+            arg if arg.starts_with("--trace-only=") => options.trace_only = Some(arg["--trace-only=".len()..].to_string()),
+            arg if arg.starts_with("--max-") => {
+                let (name, value) = arg.split_once('=').unwrap_or((arg, ""));
+                let Ok(value) = value.parse::<usize>() else {
+                    return Err(format!("{} needs a number, like {}=1000", name, name));
+                };
+                match name {
+                    "--max-cycles" => options.limits.max_cycles = Some(value as u64),
+                    "--max-depth" => options.limits.max_call_depth = Some(value),
+                    "--max-heap" => options.limits.max_heap_bytes = Some(value),
+                    "--max-files" => options.limits.max_open_files = Some(value),
+                    other => return Err(format!("Unknown option: {}\n{}", other, usage)),
+                }
+            }
+            other => return Err(format!("Unknown option: {}\n{}", other, usage)),
+        }
+        next += 1;
+    }
+
+    // If no input file is given, show usage message
+    if next >= args.len() {
+        return Err(usage);
+    }
+    options.program_args = args[next..].to_vec();
+    Ok(options)
+}
+
+// Compile C source. Gives back None if -s, --listing or debug already did all that was asked.
+fn compile(options: &Options, source_code: &str) -> Option<Program> {
+    // Pass the source code to the lexer
+    let lexer = Lexer::new(source_code);
+
+    // Create the parser using the lexer
+    let mut parser = Parser::new(lexer);
+    parser.optimize = options.optimize;
+
+    // Parse the source code into instructions for the VM.
+    // A syntax error stops everything, like in C4.
+    let result = parser.parse_program();
+    for warning in &parser.warnings {
+        eprintln!("{}", warning);
+    }
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        process::exit(-1);
     }
 
-    // Get the source file path from command-line
-    let source_path = &args[1];
+    // With -s we only show what was compiled, like C4
+    if options.source {
+        print!("{}", c4_listing(source_code, &parser));
+        return None;
+    }
+    if options.listing {
+        print!("{}", annotated_listing(source_code, &parser));
+        return None;
+    }
+
+    // The program starts running at main()
+    let Some(entry) = parser.entry() else {
+        eprintln!("main() not defined");
+        process::exit(-1);
+    };
+
+    // The debug subcommand hands the program to the debugger instead of running it
+    if options.debugger {
+        let started = Debugger::new(source_code, &parser, entry)
+            .and_then(|mut debugger| debugger.vm.set_args(&options.program_args).map(|_| debugger));
+        let mut debugger = started.unwrap_or_else(|err| {
+            eprintln!("VM error: {}", err);
+            process::exit(-1);
+        });
+        debugger.run(&mut io::stdin().lock(), &mut io::stdout()).unwrap();
+        return None;
+    }
+
+    Program::from_parser(parser)
+}
 
-    // Try reading the source file into a string
-    let source_code = match fs::read_to_string(source_path) {
-        Ok(content) => content, // Success
+fn main() {
+    // Collect command-line arguments (expects: program_name [options] source_file [args...])
+    let args: Vec<String> = env::args().collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(-1);
+        }
+    };
+
+    // Get the source file path from command-line.
+    // It and everything after it are passed on to the program's main(argc, argv).
+    let source_path = &options.program_args[0];
+
+    // Read the file. It is C source, C4 assembly (.s) or a program saved with -o.
+    let bytes = match fs::read(source_path) {
+        Ok(bytes) => bytes, // Success
         Err(err) => {
             // If file fails to load, show error and stop
             eprintln!("Failed to read file '{}': {}", source_path, err);
+            process::exit(-1);
+        }
+    };
+    let is_assembly = source_path.ends_with(".s");
+    if (is_bytecode(&bytes) || is_assembly) && (options.source || options.listing || options.debugger || options.c_source) {
+        eprintln!("-s, --listing, --c-source and debug need C source, '{}' is not", source_path);
+        process::exit(-1);
+    }
+    let mut program = if is_bytecode(&bytes) {
+        match Program::from_bytes(&bytes) {
+            Ok(program) => program,
+            Err(err) => {
+                eprintln!("Failed to load '{}': {}", source_path, err);
+                process::exit(-1);
+            }
+        }
+    } else {
+        let Ok(source_code) = String::from_utf8(bytes) else {
+            eprintln!("Failed to read file '{}': stream did not contain valid UTF-8", source_path);
+            process::exit(-1);
+        };
+        if is_assembly {
+            assemble(&source_code).unwrap_or_else(|errors| {
+                eprintln!("{}", errors);
+                process::exit(-1);
+            })
+        } else if options.c_source {
+            // With --c-source the program is shown as C instead of run
+            match c_source(&source_code) {
+                Ok(source) => print!("{}", source),
+                Err(err) => {
+                    eprintln!("Failed to translate to C: {}", err);
+                    process::exit(-1);
+                }
+            }
             return;
+        } else {
+            match compile(&options, &source_code) {
+                Some(program) => program,
+                None => return,
+            }
         }
     };
 
-    // Pass the source code to the lexer
-    let lexer = Lexer::new(&source_code);
+    if options.optimize {
+        program.optimize();
+    }
 
-    // Create the parser using the lexer
-    let mut parser = Parser::new(lexer);
+    // With -S the program is shown as assembly instead of run
+    if options.assembly {
+        print!("{}", disassemble(&program));
+        return;
+    }
+
+    // With --dump-ir the program is shown as IR instead of run
+    if options.dump_ir {
+        let mut module = Module::from_program(&program).unwrap_or_else(|err| {
+            eprintln!("Failed to build the IR: {}", err);
+            process::exit(-1);
+        });
+        if options.optimize {
+            ir_passes::optimize(&mut module);
+        }
+        print!("{}", module);
+        return;
+    }
+
+    // With --x86 the program is shown as x86-64 assembly instead of run
+    if options.x86 {
+        match native::assembly(&program) {
+            Ok(assembly) => print!("{}", assembly),
+            Err(err) => {
+                eprintln!("Failed to compile to x86-64: {}", err);
+                process::exit(-1);
+            }
+        }
+        return;
+    }
+
+    // With --native the program is built into an executable instead of run
+    if let Some(path) = &options.native {
+        if let Err(err) = native::build(&program, Path::new(path)) {
+            eprintln!("Failed to build '{}': {}", path, err);
+            process::exit(-1);
+        }
+        return;
+    }
+
+    // With -o the program is saved instead of run, unless it could never be loaded again
+    if let Some(path) = &options.output {
+        let data_size = MemoryConfig::default().data_size;
+        if program.data.len() > data_size {
+            eprintln!("Failed to write '{}': data is {} bytes but the data segment only holds {}", path, program.data.len(), data_size);
+            process::exit(-1);
+        }
+        if let Err(err) = fs::write(path, program.to_bytes()) {
+            eprintln!("Failed to write '{}': {}", path, err);
+            process::exit(-1);
+        }
+        return;
+    }
+
+    // With --register-vm the program is compiled again, through the IR, for the register VM
+    if options.register_vm {
+        if options.debug || options.trace.is_some() {
+            eprintln!("-d and --trace only work on the stack VM");
+            process::exit(-1);
+        }
+        let code = RegProgram::from_program(&program, options.optimize).unwrap_or_else(|err| {
+            eprintln!("Failed to compile for the register VM: {}", err);
+            process::exit(-1);
+        });
+        let config = RunConfig { limits: options.limits, args: options.program_args.clone(), ..RunConfig::default() };
+        let result = RegisterVM::new(&program, code, config).and_then(|mut vm| Ok((vm.run()?, vm.cycle)));
+        let (exit_code, cycles) = result.unwrap_or_else(|err| {
+            eprintln!("VM error: {}", err);
+            process::exit(-1);
+        });
+        if options.summary {
+            println!("exit({}) cycle = {}", exit_code, cycles);
+        }
+        process::exit(exit_code as i32);
+    }
 
-    // Parse the source code into intermediate instructions
-    parser.parse_program();
+    // Set up --trace before the program's symbols go away
+    let trace = options.trace.as_ref().map(|path| {
+        let mut trace = match path {
+            Some(path) => Trace::to_file(path).unwrap_or_else(|err| {
+                eprintln!("Failed to create trace file '{}': {}", path, err);
+                process::exit(-1);
+            }),
+            None => Trace::to_stderr(),
+        };
+        if let Some(only) = &options.trace_only {
+            trace.only = parse_range(only, |name| program.function_range(name));
+            if trace.only.is_none() {
+                eprintln!("--trace-only: no function or pc range '{}'", only);
+                process::exit(-1);
+            }
+        }
+        trace
+    });
 
-    // Print all the instructions that were generated by the parser
-    println!("\n Instructions:");
-    for inst in &parser.instructions {
-        println!("{}", inst);
+    // Run the virtual machine with the instructions and the data segment.
+    // If the program faults, say why and exit with -1 like C4 does.
+    let mut vm = VM::new(program.text, program.data, program.entry).unwrap_or_else(|err| {
+        eprintln!("VM error: {}", err);
+        process::exit(-1);
+    });
+    vm.debug = options.debug;
+    vm.trace = trace;
+    vm.limits = options.limits;
+    let exit_code = match vm.set_args(&options.program_args).and_then(|_| vm.run()) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("VM error: {}", err);
+            process::exit(-1);
+        }
+    };
+
+    // What main returned (or what was passed to exit) becomes our own exit status
+    if options.summary {
+        println!("exit({}) cycle = {}", exit_code, vm.cycle);
     }
+    process::exit(exit_code as i32);
+}
 
-    // Run the virtual machine with the instructions
-    let mut vm = VM::new(parser.instructions);
-    vm.run();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(&strings(&["c4", "-s", "-d", "prog.c", "-x", "y"])).unwrap();
+        assert!(options.source && options.debug && !options.summary && !options.assembly);
+        assert_eq!(options.program_args, strings(&["prog.c", "-x", "y"])); // options after the file belong to the program
+
+        assert!(parse_args(&strings(&["c4", "debug", "prog.c"])).unwrap().debugger);
+        assert!(parse_args(&strings(&["c4", "-O", "--dump-ir", "prog.c"])).unwrap().dump_ir);
+        assert!(parse_args(&strings(&["c4", "--register-vm", "prog.c"])).unwrap().register_vm);
+        assert!(parse_args(&strings(&["c4", "--x86", "prog.c"])).unwrap().x86);
+        assert!(parse_args(&strings(&["c4", "--c-source", "prog.c"])).unwrap().c_source);
+        assert_eq!(parse_args(&strings(&["c4", "--native=prog", "prog.c"])).unwrap().native, Some("prog".to_string()));
+        let options = parse_args(&strings(&["c4", "--max-cycles=100", "--max-heap=4096", "prog.c"])).unwrap();
+        assert_eq!(options.limits, Limits { max_cycles: Some(100), max_heap_bytes: Some(4096), ..Limits::default() });
+        assert!(parse_args(&strings(&["c4", "--max-depth", "prog.c"])).is_err());
+        assert_eq!(parse_args(&strings(&["c4", "-o", "prog.c4b", "prog.c"])).unwrap().output, Some("prog.c4b".to_string()));
+        assert!(parse_args(&strings(&["c4", "-o"])).is_err());
+
+        assert!(parse_args(&strings(&["c4", "-s"])).is_err());
+        assert!(parse_args(&strings(&["c4", "-q", "prog.c"])).is_err());
+    }
 }
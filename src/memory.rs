@@ -0,0 +1,300 @@
+use std::fmt;
+
+// The VM's memory is one address space split into segments:
+//
+//   0 .. DATA_BASE            unmapped, so NULL pointers fault
+//   DATA_BASE ..              data segment (globals and string literals)
+//   HEAP_BASE ..              heap segment (malloc and free)
+//   .. STACK_TOP              stack segment, growing down towards lower addresses
+//
+// The instructions themselves (the text segment) are kept by the VM and are not
+// addressable as data. Each segment has its own size limit, set with MemoryConfig.
+pub const DATA_BASE: i64 = 0x0010_0000;
+pub const HEAP_BASE: i64 = 0x1000_0000;
+pub const STACK_TOP: i64 = 0x7fff_0000;
+
+// Every heap block starts with a header that remembers the block's size
+const HEADER_SIZE: usize = 8;
+
+// How big each segment is allowed to be, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryConfig {
+    pub data_size: usize,
+    pub heap_size: usize,
+    pub stack_size: usize,
+}
+
+impl Default for MemoryConfig {
+    // The same pool size C4 uses for each of its areas
+    fn default() -> Self {
+        MemoryConfig {
+            data_size: 256 * 1024,
+            heap_size: 16 * 1024 * 1024,
+            stack_size: 256 * 1024,
+        }
+    }
+}
+
+// An access to an address that is not inside any segment (or runs off the end of one)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryFault {
+    pub address: i64, // The first address that was accessed
+    pub len: usize,   // How many bytes were accessed
+}
+
+impl fmt::Display for MemoryFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid memory access at address {:#x} ({} bytes)", self.address, self.len)
+    }
+}
+
+// Why a memory can't be made for a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    SegmentsTooBig,                           // The segments don't fit in the VM's address space
+    DataTooBig { size: usize, limit: usize }, // The program's data is bigger than the data segment
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryError::SegmentsTooBig => write!(f, "memory segments don't fit in the VM address space"),
+            MemoryError::DataTooBig { size, limit } => {
+                write!(f, "data segment needs {} bytes but only {} are allowed", size, limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MemoryError {}
+
+// The memory of one running program
+pub struct Memory {
+    pub config: MemoryConfig,
+    data: Vec<u8>,                    // Bytes of the data segment
+    heap: Vec<u8>,                    // Bytes of the heap that malloc has handed out so far
+    stack: Vec<u8>,                   // Bytes of the stack, the last one is just below STACK_TOP
+    free_blocks: Vec<(usize, usize)>, // Heap blocks given back with free(): (offset, size)
+}
+
+impl Memory {
+    // Make a memory whose data segment starts with the given bytes.
+    // Fails if the segments don't fit in the address space or the data is too big.
+    pub fn new(config: MemoryConfig, initial_data: &[u8]) -> Result<Self, MemoryError> {
+        let fits = |size: usize, room: i64| size as u128 <= room as u128;
+        if !fits(config.data_size, HEAP_BASE - DATA_BASE)
+            || !fits(config.heap_size.saturating_add(config.stack_size), STACK_TOP - HEAP_BASE)
+        {
+            return Err(MemoryError::SegmentsTooBig);
+        }
+        if initial_data.len() > config.data_size {
+            return Err(MemoryError::DataTooBig { size: initial_data.len(), limit: config.data_size });
+        }
+
+        let mut data = initial_data.to_vec();
+        data.resize(config.data_size, 0);
+        Ok(Memory {
+            config,
+            data,
+            heap: Vec::new(),
+            stack: vec![0; config.stack_size],
+            free_blocks: Vec::new(),
+        })
+    }
+
+    // The lowest address the stack is allowed to grow down to
+    pub fn stack_base(&self) -> i64 {
+        STACK_TOP - self.config.stack_size as i64
+    }
+
+    // Find which segment holds len bytes starting at address.
+    // Gives back the segment's bytes and where the access starts inside them.
+    fn locate(&self, address: i64, len: usize) -> Result<(&[u8], usize), MemoryFault> {
+        let fault = MemoryFault { address, len };
+        let (segment, base) = if address >= self.stack_base() {
+            (&self.stack, self.stack_base())
+        } else if address >= HEAP_BASE {
+            (&self.heap, HEAP_BASE)
+        } else if address >= DATA_BASE {
+            (&self.data, DATA_BASE)
+        } else {
+            return Err(fault);
+        };
+
+        let offset = (address - base) as usize;
+        match offset.checked_add(len) {
+            Some(end) if end <= segment.len() => Ok((segment, offset)),
+            _ => Err(fault),
+        }
+    }
+
+    // The same as locate, but for writing
+    fn locate_mut(&mut self, address: i64, len: usize) -> Result<(&mut [u8], usize), MemoryFault> {
+        let (_, offset) = self.locate(address, len)?;
+        let segment = if address >= self.stack_base() {
+            &mut self.stack
+        } else if address >= HEAP_BASE {
+            &mut self.heap
+        } else {
+            &mut self.data
+        };
+        Ok((segment, offset))
+    }
+
+    // The len bytes starting at address
+    pub fn bytes(&self, address: i64, len: usize) -> Result<&[u8], MemoryFault> {
+        let (segment, offset) = self.locate(address, len)?;
+        Ok(&segment[offset..offset + len])
+    }
+
+    // The len bytes starting at address, for writing
+    pub fn bytes_mut(&mut self, address: i64, len: usize) -> Result<&mut [u8], MemoryFault> {
+        let (segment, offset) = self.locate_mut(address, len)?;
+        Ok(&mut segment[offset..offset + len])
+    }
+
+    // Load an 8-byte integer (an int or a pointer)
+    pub fn load_i64(&self, address: i64) -> Result<i64, MemoryFault> {
+        let bytes = self.bytes(address, 8)?;
+        Ok(i64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    // Store an 8-byte integer
+    pub fn store_i64(&mut self, address: i64, value: i64) -> Result<(), MemoryFault> {
+        self.bytes_mut(address, 8)?.copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    // Load a single byte (a char)
+    pub fn load_u8(&self, address: i64) -> Result<u8, MemoryFault> {
+        Ok(self.bytes(address, 1)?[0])
+    }
+
+    // Store a single byte
+    pub fn store_u8(&mut self, address: i64, value: u8) -> Result<(), MemoryFault> {
+        self.bytes_mut(address, 1)?[0] = value;
+        Ok(())
+    }
+
+    // The word at address if it is all inside the stack segment. The fast interpreter loop
+    // uses these for its pushes and pops, which only ever touch the stack.
+    #[inline]
+    pub fn load_stack(&self, address: i64) -> Option<i64> {
+        let offset = usize::try_from(address.wrapping_sub(self.stack_base())).ok()?;
+        let bytes = self.stack.get(offset..offset.checked_add(8)?)?;
+        Some(i64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    #[inline]
+    pub fn store_stack(&mut self, address: i64, value: i64) -> Option<()> {
+        let offset = usize::try_from(address.wrapping_sub(self.stack_base())).ok()?;
+        self.stack.get_mut(offset..offset.checked_add(8)?)?.copy_from_slice(&value.to_le_bytes());
+        Some(())
+    }
+
+    // Read a zero-terminated C string, not including the zero
+    pub fn read_cstr(&self, address: i64) -> Result<Vec<u8>, MemoryFault> {
+        let (segment, offset) = self.locate(address, 1)?;
+        let rest = &segment[offset..];
+        match rest.iter().position(|&b| b == 0) {
+            Some(end) => Ok(rest[..end].to_vec()),
+            None => Err(MemoryFault { address, len: rest.len() + 1 }), // ran off the segment
+        }
+    }
+
+    // How many bytes of the heap malloc has used so far, block headers included
+    pub fn heap_used(&self) -> usize {
+        self.heap.len()
+    }
+
+    // Hand out a heap block of at least size bytes, or 0 if the heap is full
+    pub fn malloc(&mut self, size: i64) -> i64 {
+        if size < 0 {
+            return 0;
+        }
+        let size = (size as usize).next_multiple_of(8);
+
+        // Reuse the first freed block that is big enough
+        if let Some(i) = self.free_blocks.iter().position(|&(_, s)| s >= size) {
+            let (offset, _) = self.free_blocks.remove(i);
+            return HEAP_BASE + offset as i64;
+        }
+
+        let offset = self.heap.len() + HEADER_SIZE;
+        if offset + size > self.config.heap_size {
+            return 0;
+        }
+        self.heap.extend_from_slice(&(size as i64).to_le_bytes());
+        self.heap.resize(offset + size, 0);
+        HEAP_BASE + offset as i64
+    }
+
+    // Give a heap block back to malloc. Faults if address is not a heap block.
+    pub fn free(&mut self, address: i64) -> Result<(), MemoryFault> {
+        if address == 0 {
+            return Ok(()); // free(NULL) does nothing
+        }
+        let fault = MemoryFault { address, len: 0 };
+        if !(HEAP_BASE + HEADER_SIZE as i64..STACK_TOP).contains(&address) {
+            return Err(fault);
+        }
+        let size = self.load_i64(address - HEADER_SIZE as i64).map_err(|_| fault)?;
+        self.free_blocks.push(((address - HEAP_BASE) as usize, size as usize));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_store_in_each_segment() {
+        let mut memory = Memory::new(MemoryConfig::default(), b"hi\0").unwrap();
+        let block = memory.malloc(16);
+        let top = STACK_TOP - 8;
+
+        for address in [DATA_BASE + 8, block, top] {
+            memory.store_i64(address, -5).unwrap();
+            assert_eq!(memory.load_i64(address), Ok(-5));
+            memory.store_u8(address, b'x').unwrap();
+            assert_eq!(memory.load_u8(address), Ok(b'x'));
+        }
+        assert_eq!(memory.read_cstr(DATA_BASE).unwrap(), b"hi");
+    }
+
+    #[test]
+    fn test_out_of_bounds_faults() {
+        let config = MemoryConfig { data_size: 16, heap_size: 64, stack_size: 64 };
+        let mut memory = Memory::new(config, &[]).unwrap();
+
+        assert_eq!(memory.load_i64(0), Err(MemoryFault { address: 0, len: 8 }));
+        assert_eq!(memory.load_i64(DATA_BASE + 12), Err(MemoryFault { address: DATA_BASE + 12, len: 8 }));
+        assert!(memory.store_u8(STACK_TOP, 1).is_err());
+        assert!(memory.store_u8(memory.stack_base() - 1, 1).is_err());
+        assert!(memory.load_u8(HEAP_BASE).is_err()); // nothing allocated yet
+    }
+
+    #[test]
+    fn test_heap_limit_and_reuse() {
+        let config = MemoryConfig { data_size: 0, heap_size: 64, stack_size: 64 };
+        let mut memory = Memory::new(config, &[]).unwrap();
+
+        let p = memory.malloc(24);
+        assert_eq!(p, HEAP_BASE + 8);
+        assert_eq!(memory.malloc(64), 0); // doesn't fit next to p
+        memory.free(p).unwrap();
+        assert_eq!(memory.malloc(10), p);
+        assert!(memory.free(DATA_BASE).is_err());
+    }
+
+    #[test]
+    fn test_config_that_does_not_fit() {
+        let config = MemoryConfig { data_size: 16, heap_size: 64, stack_size: 64 };
+        assert_eq!(Memory::new(config, &[0; 17]).err(), Some(MemoryError::DataTooBig { size: 17, limit: 16 }));
+        let config = MemoryConfig { data_size: 0, heap_size: usize::MAX, stack_size: 64 };
+        assert_eq!(Memory::new(config, &[]).err(), Some(MemoryError::SegmentsTooBig));
+        let config = MemoryConfig { data_size: usize::MAX, heap_size: 64, stack_size: 64 };
+        assert_eq!(Memory::new(config, &[]).err(), Some(MemoryError::SegmentsTooBig));
+    }
+}
@@ -0,0 +1,272 @@
+// Native code (--native and --x86). A program is turned into x86-64 assembly for the GNU
+// assembler, one stack VM instruction at a time, and linked with the runtime in runtime.s
+// into a static Linux executable that runs without the VM.
+//
+// The code keeps the VM's registers in machine registers: a in %rax, sp in %rsp and bp in
+// %rbp. PSH is a push, JSR a call, ENT and LEV build and drop the frame like C4 does, so a
+// frame looks the same as in the VM. The data segment is linked at DATA_BASE, and the runtime
+// maps the heap and the stack at HEAP_BASE and STACK_TOP, so every address is what it would be
+// in the VM and the program's IMM words need no changes.
+//
+// What's different: there are no cycle counts or --max-* limits, a memory access only faults
+// when it misses the segments altogether, and host functions can't be called.
+use crate::memory::{MemoryConfig, DATA_BASE};
+use crate::opcode::Op;
+use crate::Program;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write as _;
+use std::path::Path;
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// The C4 system calls that are in the runtime, as they are called there
+const RUNTIME_CALLS: [(Op, &str); 9] = [
+    (Op::Open, "c4_open"),
+    (Op::Read, "c4_read"),
+    (Op::Clos, "c4_close"),
+    (Op::Prtf, "c4_printf"),
+    (Op::Malc, "c4_malloc"),
+    (Op::Free, "c4_free"),
+    (Op::Mset, "c4_memset"),
+    (Op::Mcmp, "c4_memcmp"),
+    (Op::Exit, "c4_exit"),
+];
+
+// Turn a program into one GNU assembler file, the runtime included
+pub fn assembly(program: &Program) -> Result<String, String> {
+    if let Some((name, _)) = program.imports.first() {
+        return Err(format!("host function '{}' can't be called from native code", name));
+    }
+    let text = &program.text;
+    let config = MemoryConfig::default();
+
+    // Find where every instruction starts, and which of them are jumped to
+    let mut starts = HashSet::new();
+    let mut targets = HashSet::new();
+    let mut pc = 0;
+    while pc < text.len() {
+        let op = Op::from_code(text[pc]).ok_or_else(|| format!("{} at text {} is not an instruction", text[pc], pc))?;
+        starts.insert(pc);
+        if op.has_operand() {
+            let operand = *text.get(pc + 1).ok_or_else(|| format!("{} at text {} has no operand", op, pc))?;
+            if matches!(op, Op::Jmp | Op::Jsr | Op::Bz | Op::Bnz) {
+                targets.insert(operand);
+            }
+            pc += 2;
+        } else {
+            pc += 1;
+        }
+    }
+    // The VM puts PSH and EXIT after the text for main to return to, and it can be jumped to too
+    starts.insert(text.len());
+    if let Some(target) = targets.iter().find(|&&t| !usize::try_from(t).is_ok_and(|t| starts.contains(&t))) {
+        return Err(format!("jump to {}, which is not an instruction", target));
+    }
+    let functions: HashMap<usize, &str> = program.functions.iter().map(|(name, range)| (range.start, name.as_str())).collect();
+
+    let mut out = String::new();
+    writeln!(out, "# Made by c4 from a compiled program. Build it with as and ld --section-start=.c4data={:#x}.", DATA_BASE).unwrap();
+    writeln!(out, "    .set C4_HEAP_SIZE, {}", config.heap_size).unwrap();
+    writeln!(out, "    .set C4_STACK_SIZE, {}", config.stack_size).unwrap();
+    writeln!(out, "    .set c4_main, .Lc4_{}", program.entry).unwrap();
+    out += include_str!("runtime.s");
+
+    out += "\n# The program\n    .text\n";
+    let mut pc = 0;
+    while pc < text.len() {
+        if let Some(name) = functions.get(&pc) {
+            writeln!(out, "\n# {}()", name).unwrap();
+        }
+        if targets.contains(&(pc as i64)) || pc == program.entry {
+            writeln!(out, ".Lc4_{}:", pc).unwrap();
+        }
+        let op = Op::from_code(text[pc]).unwrap();
+        let operand = if op.has_operand() { text[pc + 1] } else { 0 };
+        if op.has_operand() {
+            writeln!(out, "    # {} {}", op, operand).unwrap();
+        } else {
+            writeln!(out, "    # {}", op).unwrap();
+        }
+        let next = pc + if op.has_operand() { 2 } else { 1 };
+        instruction(&mut out, op, operand, text.get(next..next + 2));
+        pc = next;
+    }
+    if targets.contains(&(text.len() as i64)) {
+        writeln!(out, ".Lc4_{}:\n    push %rax\n    call c4_exit", text.len()).unwrap();
+    }
+
+    // The data segment, padded with zeros to its full size like the VM's
+    out += "\n# The data segment\n    .section .c4data,\"aw\"\n";
+    for line in program.data.chunks(16) {
+        let bytes: Vec<String> = line.iter().map(|b| b.to_string()).collect();
+        writeln!(out, "    .byte {}", bytes.join(", ")).unwrap();
+    }
+    if program.data.len() < config.data_size {
+        writeln!(out, "    .zero {}", config.data_size - program.data.len()).unwrap();
+    }
+    Ok(out)
+}
+
+// The code for one instruction. next is the two words after it, to find printf's ADJ.
+fn instruction(out: &mut String, op: Op, n: i64, next: Option<&[i64]>) {
+    let words = n.wrapping_mul(8);
+    let code = match op {
+        Op::Lea if fits(words) => format!("lea {}(%rbp), %rax", words),
+        Op::Lea => format!("movabs ${}, %rax\n    add %rbp, %rax", words),
+        Op::Imm if fits(n) => format!("mov ${}, %rax", n),
+        Op::Imm => format!("movabs ${}, %rax", n),
+        Op::Jmp => format!("jmp .Lc4_{}", n),
+        Op::Jsr => format!("call .Lc4_{}", n),
+        Op::Bz => format!("test %rax, %rax\n    jz .Lc4_{}", n),
+        Op::Bnz => format!("test %rax, %rax\n    jnz .Lc4_{}", n),
+        // Check for room first, as the VM does, so a big frame can't jump over the guard
+        Op::Ent => format!("push %rbp\n    mov %rsp, %rbp\n    {}\n    cmp $STACK_BASE, %rsp\n    jb stack_overflow", add_rsp(words.wrapping_neg())),
+        Op::Adj => add_rsp(words),
+        Op::Lev => "leave\n    ret".to_string(),
+        Op::Li => "mov (%rax), %rax".to_string(),
+        Op::Lc => "movsbq (%rax), %rax".to_string(),
+        Op::Si => "pop %rcx\n    mov %rax, (%rcx)".to_string(),
+        Op::Sc => "pop %rcx\n    mov %al, (%rcx)\n    movsbq %al, %rax".to_string(),
+        Op::Psh => "push %rax".to_string(),
+        Op::Or => "pop %rcx\n    or %rcx, %rax".to_string(),
+        Op::Xor => "pop %rcx\n    xor %rcx, %rax".to_string(),
+        Op::And => "pop %rcx\n    and %rcx, %rax".to_string(),
+        Op::Add => "pop %rcx\n    add %rcx, %rax".to_string(),
+        Op::Mul => "pop %rcx\n    imul %rcx, %rax".to_string(),
+        Op::Sub => "pop %rcx\n    sub %rax, %rcx\n    mov %rcx, %rax".to_string(),
+        Op::Eq | Op::Ne | Op::Lt | Op::Gt | Op::Le | Op::Ge => {
+            let set = match op {
+                Op::Eq => "sete",
+                Op::Ne => "setne",
+                Op::Lt => "setl",
+                Op::Gt => "setg",
+                Op::Le => "setle",
+                _ => "setge",
+            };
+            format!("pop %rcx\n    cmp %rax, %rcx\n    {} %al\n    movzbl %al, %eax", set)
+        }
+        // The shift count goes in %cl, and like the VM only its low 6 bits count
+        Op::Shl => "mov %rax, %rcx\n    pop %rax\n    shl %cl, %rax".to_string(),
+        Op::Shr => "mov %rax, %rcx\n    pop %rax\n    sar %cl, %rax".to_string(),
+        Op::Div => "pop %rcx\n    call c4_div".to_string(),
+        Op::Mod => "pop %rcx\n    call c4_mod".to_string(),
+        Op::Addi if fits(n) => format!("add ${}, %rax", n),
+        Op::Addi => format!("movabs ${}, %rcx\n    add %rcx, %rax", n),
+        Op::Lli if fits(words) => format!("mov {}(%rbp), %rax", words),
+        Op::Lli => format!("movabs ${}, %rax\n    mov (%rbp,%rax), %rax", words),
+        // printf takes as many arguments as the ADJ after it drops, like in the VM
+        Op::Prtf => {
+            let argc = match next {
+                Some(&[adj, n]) if adj == Op::Adj.code() => n.max(1),
+                _ => 1,
+            };
+            format!("mov ${}, %rdi\n    call c4_printf", argc)
+        }
+        _ => {
+            let (_, name) = RUNTIME_CALLS.iter().find(|(call, _)| *call == op).unwrap();
+            format!("call {}", name)
+        }
+    };
+    writeln!(out, "    {}", code).unwrap();
+}
+
+// Does n fit in an instruction's 32-bit immediate or displacement?
+fn fits(n: i64) -> bool {
+    i32::try_from(n).is_ok()
+}
+
+// Move %rsp by bytes
+fn add_rsp(bytes: i64) -> String {
+    if fits(bytes) {
+        format!("add ${}, %rsp", bytes)
+    } else {
+        format!("movabs ${}, %rcx\n    add %rcx, %rsp", bytes)
+    }
+}
+
+// Numbers the object files build makes, so builds running at once don't share one
+static OBJECTS: AtomicUsize = AtomicUsize::new(0);
+
+// Build a static executable at path with the system's as and ld
+pub fn build(program: &Program, path: &Path) -> Result<(), String> {
+    let assembly = assembly(program)?;
+    // The object goes in the temporary directory, so it can't clash with a file next to the executable
+    let object = env::temp_dir().join(format!("c4_native_{}_{}.o", process::id(), OBJECTS.fetch_add(1, Ordering::Relaxed)));
+    let mut child = Command::new("as")
+        .args(["--64", "-o"])
+        .arg(&object)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("can't run as: {}", err))?;
+    child.stdin.take().unwrap().write_all(assembly.as_bytes()).map_err(|err| format!("can't run as: {}", err))?;
+    let status = child.wait().map_err(|err| format!("can't run as: {}", err))?;
+    if !status.success() {
+        return Err(format!("as failed ({})", status));
+    }
+
+    let status = Command::new("ld")
+        .args(["-static", "-nostdlib", &format!("--section-start=.c4data={:#x}", DATA_BASE), "-o"])
+        .arg(path)
+        .arg(&object)
+        .status()
+        .map_err(|err| format!("can't run ld: {}", err));
+    let _ = fs::remove_file(&object);
+    match status? {
+        status if status.success() => Ok(()),
+        status => Err(format!("ld failed ({})", status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend_tests::{check, have_tools, FAULTS, SAME_AS_VM};
+    use crate::{compile, compile_with, CompileOptions, HostFunctions};
+
+    // Build a program into an executable, with -O if optimize is set
+    fn build_source(source: &str, path: &Path, optimize: bool) -> Result<(), String> {
+        let program = compile_with(source, &HostFunctions::new(), CompileOptions { optimize }).map_err(|err| err.to_string())?;
+        build(&program, path)
+    }
+
+    #[test]
+    fn test_assembly() {
+        let program = compile("int main() { int x; x = 7 * 6; printf(\"%d\\n\", x); return x / 2; }").unwrap();
+        let assembly = assembly(&program).unwrap();
+        assert!(assembly.contains("\n# main()\n.Lc4_"));
+        assert!(assembly.contains("    # PRTF\n    mov $2, %rdi\n    call c4_printf\n"));
+        assert!(assembly.contains("    call c4_div\n"));
+        assert!(assembly.contains("    .section .c4data,\"aw\"\n"));
+    }
+
+    #[test]
+    fn test_host_functions_are_refused() {
+        let mut program = compile("int main() { return 0; }").unwrap();
+        program.imports.push(("add".to_string(), 1000));
+        assert_eq!(assembly(&program).unwrap_err(), "host function 'add' can't be called from native code");
+    }
+
+    #[test]
+    fn test_same_as_vm() {
+        if !have_tools("native::test_same_as_vm", &["as", "ld"]) {
+            return;
+        }
+        for source in SAME_AS_VM {
+            check(source, |source, path| build_source(source, path, false));
+            check(source, |source, path| build_source(source, path, true));
+        }
+    }
+
+    #[test]
+    fn test_faults() {
+        if !have_tools("native::test_faults", &["as", "ld"]) {
+            return;
+        }
+        for source in FAULTS {
+            check(source, |source, path| build_source(source, path, false));
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+
+// The instructions of the VM, in the same order (and so with the same numbers) as in C4.
+// In the text segment each instruction is one word holding its number.
+// Instructions up to and including ADJ are followed by a second word, their operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Op {
+    Lea, // a = bp + operand words (address of a local or argument)
+    Imm, // a = operand
+    Jmp, // jump to operand
+    Jsr, // call the function at operand
+    Bz,  // jump to operand if a == 0
+    Bnz, // jump to operand if a != 0
+    Ent, // enter a function, making room for operand local words
+    Adj, // drop operand words from the stack (the arguments after a call)
+    Lev, // leave a function
+    Li,  // a = the int at address a
+    Lc,  // a = the char at address a
+    Si,  // store a as an int at the address popped from the stack
+    Sc,  // store a as a char at the address popped from the stack
+    Psh, // push a onto the stack
+    Or, Xor, And,
+    Eq, Ne, Lt, Gt, Le, Ge,
+    Shl, Shr, Add, Sub, Mul, Div, Mod,
+    Open, Read, Clos, Prtf, Malc, Free, Mset, Mcmp, Exit,
+
+    // Instructions that only the peephole optimiser (-O) makes, they are not in C4
+    Addi, // a = a + operand
+    Lli,  // a = the int at bp + operand words (LEA then LI)
+}
+
+// Every instruction, in order, so a number can be turned back into an Op
+const ALL: [Op; 41] = [
+    Op::Lea, Op::Imm, Op::Jmp, Op::Jsr, Op::Bz, Op::Bnz, Op::Ent, Op::Adj, Op::Lev,
+    Op::Li, Op::Lc, Op::Si, Op::Sc, Op::Psh,
+    Op::Or, Op::Xor, Op::And, Op::Eq, Op::Ne, Op::Lt, Op::Gt, Op::Le, Op::Ge,
+    Op::Shl, Op::Shr, Op::Add, Op::Sub, Op::Mul, Op::Div, Op::Mod,
+    Op::Open, Op::Read, Op::Clos, Op::Prtf, Op::Malc, Op::Free, Op::Mset, Op::Mcmp, Op::Exit,
+    Op::Addi, Op::Lli,
+];
+
+impl Op {
+    // Turn a word from the text segment back into an instruction
+    pub fn from_code(code: i64) -> Option<Op> {
+        ALL.get(usize::try_from(code).ok()?).copied()
+    }
+
+    // Find an instruction by its name, in upper or lower case
+    pub fn from_name(name: &str) -> Option<Op> {
+        ALL.iter().copied().find(|op| op.name().eq_ignore_ascii_case(name))
+    }
+
+    // The word that stands for this instruction in the text segment
+    pub fn code(self) -> i64 {
+        self as i64
+    }
+
+    // Is this instruction followed by an operand word?
+    pub fn has_operand(self) -> bool {
+        self <= Op::Adj || self >= Op::Addi
+    }
+
+    // Is this one of the operators from OR to MOD, which combine a popped value with a?
+    pub fn is_binary(self) -> bool {
+        (Op::Or..=Op::Mod).contains(&self)
+    }
+
+    // Work out a binary operator the way the VM does, with 64-bit wrapping arithmetic and
+    // C's division and remainder (rounded toward zero). Gives back None for division by zero
+    // and for instructions that aren't binary operators.
+    pub fn apply(self, left: i64, right: i64) -> Option<i64> {
+        Some(match self {
+            Op::Or => left | right,
+            Op::Xor => left ^ right,
+            Op::And => left & right,
+            Op::Eq => (left == right) as i64,
+            Op::Ne => (left != right) as i64,
+            Op::Lt => (left < right) as i64,
+            Op::Gt => (left > right) as i64,
+            Op::Le => (left <= right) as i64,
+            Op::Ge => (left >= right) as i64,
+            Op::Shl => left.wrapping_shl(right as u32),
+            Op::Shr => left.wrapping_shr(right as u32),
+            Op::Add => left.wrapping_add(right),
+            Op::Sub => left.wrapping_sub(right),
+            Op::Mul => left.wrapping_mul(right),
+            Op::Div if right != 0 => left.wrapping_div(right),
+            Op::Mod if right != 0 => left.wrapping_rem(right),
+            _ => return None,
+        })
+    }
+
+    // The four-letter name C4 prints for this instruction
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Lea => "LEA",
+            Op::Imm => "IMM",
+            Op::Jmp => "JMP",
+            Op::Jsr => "JSR",
+            Op::Bz => "BZ",
+            Op::Bnz => "BNZ",
+            Op::Ent => "ENT",
+            Op::Adj => "ADJ",
+            Op::Lev => "LEV",
+            Op::Li => "LI",
+            Op::Lc => "LC",
+            Op::Si => "SI",
+            Op::Sc => "SC",
+            Op::Psh => "PSH",
+            Op::Or => "OR",
+            Op::Xor => "XOR",
+            Op::And => "AND",
+            Op::Eq => "EQ",
+            Op::Ne => "NE",
+            Op::Lt => "LT",
+            Op::Gt => "GT",
+            Op::Le => "LE",
+            Op::Ge => "GE",
+            Op::Shl => "SHL",
+            Op::Shr => "SHR",
+            Op::Add => "ADD",
+            Op::Sub => "SUB",
+            Op::Mul => "MUL",
+            Op::Div => "DIV",
+            Op::Mod => "MOD",
+            Op::Open => "OPEN",
+            Op::Read => "READ",
+            Op::Clos => "CLOS",
+            Op::Prtf => "PRTF",
+            Op::Malc => "MALC",
+            Op::Free => "FREE",
+            Op::Mset => "MSET",
+            Op::Mcmp => "MCMP",
+            Op::Exit => "EXIT",
+            Op::Addi => "ADDI",
+            Op::Lli => "LLI",
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
@@ -1,21 +1,87 @@
-use crate::token::Token;
-use crate::lexer::Lexer;
+use crate::token::{token_name, Class, Token, Type};
+use crate::dce;
+use crate::diagnostic::Diagnostic;
+use crate::host::HostFunctions;
+use crate::lexer::{Lexer, Span};
+use crate::memory::DATA_BASE;
+use crate::opcode::Op;
+use crate::vm::SYSCALLS;
+use std::collections::HashMap;
+use std::ops::Range;
 
-// The parser reads tokens from the lexer and turns them into instructions
+// Parsing gives back the first syntax error it finds
+pub type ParseResult<T = ()> = Result<T, Diagnostic>;
+
+// What the parser knows about a name (like C4's id[Class], id[Type] and id[Val])
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub class: Class, // What kind of name this is
+    pub ty: Type,     // The type of value it gives
+    pub val: i64,     // Enum value, system call number, function address, global address or local slot
+}
+
+// The parser reads tokens from the lexer and turns them into instructions, the way C4 does:
+// there is no syntax tree, code is emitted as soon as each piece of the program is recognised.
 pub struct Parser<'a> {
     pub lexer: Lexer<'a>,              // Where we get tokens from
     pub current_token: Option<Token>,  // The current token we're looking at
-    pub instructions: Vec<String>,     // The list of instructions we will generate
+    pub text: Vec<i64>,                // The text segment: instruction words and their operands
+    pub spans: Vec<Span>,              // Where in the source each word of text was emitted
+    pub data: Vec<u8>,                 // The data segment: globals and string literals
+    pub strings: Vec<i64>,             // The address of every string literal in data
+    pub symbols: HashMap<String, Symbol>, // Every name we know about
+    pub locals: HashMap<String, Vec<(String, Symbol)>>, // Each function's parameters and locals, val is the LEA offset from bp
+    shadowed: Vec<(String, Option<Symbol>)>, // Names hidden by the current function's locals
+    ty: Type,                          // The type of the expression we just parsed
+    loc: i64,                          // The slot number where the current function's locals start
+    last: Option<usize>,               // Where the last emitted instruction starts in text
+    barrier: usize,                    // The latest place in text that something jumps to
+    pub optimize: bool,                // -O: fold constant expressions as they are compiled
+    pub warnings: Vec<Diagnostic>,     // Things worth knowing that don't stop the compile
+    statements: Vec<(usize, Span)>,    // Where each statement's code starts, and its first token
+    definitions: HashMap<String, Span>, // Where each function's name is
 }
 
 impl<'a> Parser<'a> {
     // Make a new parser and get the first token ready
     pub fn new(mut lexer: Lexer<'a>) -> Self {
         let current_token = lexer.next_token();
+
+        // Enter the system calls into the symbol table, just like C4 does at startup
+        let mut symbols = HashMap::new();
+        for (name, op) in SYSCALLS {
+            symbols.insert(
+                name.to_string(),
+                Symbol { class: Class::Sys, ty: Type::Int, val: op.code() },
+            );
+        }
+
         Parser {
             lexer,
             current_token,
-            instructions: Vec::new(),
+            text: Vec::new(),
+            spans: Vec::new(),
+            data: Vec::new(),
+            strings: Vec::new(),
+            symbols,
+            locals: HashMap::new(),
+            shadowed: Vec::new(),
+            ty: Type::Int,
+            loc: 0,
+            last: None,
+            barrier: 0,
+            optimize: false,
+            warnings: Vec::new(),
+            statements: Vec::new(),
+            definitions: HashMap::new(),
+        }
+    }
+
+    // Let the program call the embedder's host functions, like C4's built-in system calls.
+    // This has to happen before parse_program.
+    pub fn declare_host_functions(&mut self, host: &HostFunctions) {
+        for (name, code) in host.names() {
+            self.symbols.insert(name.to_string(), Symbol { class: Class::Sys, ty: Type::Int, val: code });
         }
     }
 
@@ -24,116 +90,778 @@ impl<'a> Parser<'a> {
         self.current_token = self.lexer.next_token();
     }
 
-    // Start parsing the whole program (loop through all statements)
-    pub fn parse_program(&mut self) {
-        while self.current_token != Some(Token::Eof) {
-            self.parse_statement(); // parse one statement at a time
+    // A syntax error that says where in the source it happened.
+    // Parsing stops at the first error, like in C4.
+    fn error<T>(&self, message: &str) -> ParseResult<T> {
+        let found = match &self.current_token {
+            Some(token) => token_name(token),
+            None => "nothing",
+        };
+        let span = Span { line: self.lexer.line, col: self.lexer.col };
+        Err(Diagnostic::error(format!("{} (found '{}')", message, found), span))
+    }
+
+    // Is the current token this one?
+    fn at(&self, token: Token) -> bool {
+        self.current_token == Some(token)
+    }
+
+    // Make sure the current token is the one we expect, then skip it
+    fn expect(&mut self, token: Token, message: &str) -> ParseResult {
+        if !self.at(token) {
+            return self.error(message);
         }
+        self.advance();
+        Ok(())
     }
 
-    // Parse an expression like "2 + 3" or "x * y"
-    pub fn parse_expression(&mut self, min_prec: u8) {
-        // First, handle numbers or variables
-        match &self.current_token {
-            Some(Token::Num(val)) => {
-                self.instructions.push(format!("IMM {}", val)); // Push the number to instructions
-                self.advance(); // Go to next token
-            }
+    // Skip the current token if it is an identifier and give back its name
+    fn take_id(&mut self, message: &str) -> ParseResult<String> {
+        match self.current_token.clone() {
             Some(Token::Id(name)) => {
-                self.instructions.push(format!("IMM {}", name)); // Placeholder for variables
                 self.advance();
+                Ok(name)
             }
-            Some(t) => {
-                println!("Unexpected token: {:?}", t); // If it's something weird
+            _ => self.error(message),
+        }
+    }
+
+    // Add one word to the text, remembering the token it was made for (for the listings)
+    fn push_word(&mut self, word: i64) {
+        self.text.push(word);
+        self.spans.push(self.lexer.start);
+    }
+
+    // Add an instruction without an operand
+    fn emit(&mut self, op: Op) {
+        self.emit_code(op.code());
+    }
+
+    // Add an instruction by its number (host functions have numbers but no Op)
+    fn emit_code(&mut self, code: i64) {
+        self.last = Some(self.text.len());
+        self.push_word(code);
+    }
+
+    // Add an instruction followed by its operand
+    fn emit_with(&mut self, op: Op, operand: i64) {
+        self.emit(op);
+        self.push_word(operand);
+    }
+
+    // Add a jump whose target isn't known yet. Gives back where to patch the target in later.
+    fn emit_jump(&mut self, op: Op) -> usize {
+        self.emit_with(op, 0);
+        self.text.len() - 1
+    }
+
+    // Point the jump whose target is at `at` to target. Nothing is folded across a jump target.
+    fn patch_jump(&mut self, at: usize, target: usize) {
+        self.text[at] = target as i64;
+        self.barrier = self.barrier.max(target);
+    }
+
+    // Push the left side of a binary operator. Gives back where it starts if it is a constant
+    // (a lone IMM), so emit_operator can fold it.
+    fn push_left(&mut self) -> Option<usize> {
+        let constant = self.last.filter(|&i| i + 2 == self.text.len() && self.text[i] == Op::Imm.code());
+        self.emit(Op::Psh);
+        constant
+    }
+
+    // Emit a binary operator after its right side. With -O, "IMM a; PSH; IMM b; op" becomes
+    // "IMM (a op b)", worked out the way the VM would. Division by zero is never folded,
+    // it is left for the VM to report and we warn about it now.
+    fn emit_operator(&mut self, op: Op, left: Option<usize>) {
+        let n = self.text.len();
+        // The right side is only a constant if nothing jumps past its IMM, as a ?: or || would
+        let right = (self.last == Some(n - 2) && self.text[n - 2] == Op::Imm.code() && self.barrier <= n - 2).then(|| self.text[n - 1]);
+        if right == Some(0) && matches!(op, Op::Div | Op::Mod) {
+            self.warnings.push(Diagnostic::warning("division by zero".to_string(), self.spans[n - 2]));
+        }
+        // Both sides must be right next to each other, with no jump landing in between
+        if let (Some(start), Some(right)) = (left, right)
+            && self.optimize
+            && n == start + 5
+            && self.barrier <= start
+            && let Some(value) = op.apply(self.text[start + 1], right)
+        {
+            let span = self.spans[start];
+            self.text.truncate(start);
+            self.spans.truncate(start);
+            self.emit_with(Op::Imm, value);
+            self.spans[start..].fill(span);
+            return;
+        }
+        self.emit(op);
+    }
+
+    // The last instruction we emitted
+    fn last_op(&self) -> Option<Op> {
+        self.last.and_then(|i| Op::from_code(self.text[i]))
+    }
+
+    // Swap the last instruction for a different one (C4's "*e = PSH")
+    fn replace_last(&mut self, op: Op) {
+        if let Some(i) = self.last {
+            self.text[i] = op.code();
+        }
+    }
+
+    // Emit the load for a value of the current type: chars are loaded with LC, everything else with LI
+    fn emit_load(&mut self) {
+        self.emit(if self.ty == Type::Char { Op::Lc } else { Op::Li });
+    }
+
+    // Emit the store for a value of type ty, the same way
+    fn emit_store(&mut self, ty: &Type) {
+        self.emit(if *ty == Type::Char { Op::Sc } else { Op::Si });
+    }
+
+    // Copy a string literal to the end of the data segment
+    fn add_string(&mut self, text: &str) {
+        self.strings.push(DATA_BASE + self.data.len() as i64);
+        self.data.extend_from_slice(text.as_bytes());
+        self.data.push(0); // C strings end with a zero byte
+
+        // Keep the next item 8-byte aligned, like C4 does
+        while !self.data.len().is_multiple_of(8) {
+            self.data.push(0);
+        }
+    }
+
+    // Read the stars after a base type: "char **" is a pointer to a pointer to char
+    fn parse_pointers(&mut self, mut ty: Type) -> Type {
+        while self.at(Token::Mul) {
+            self.advance();
+            ty = ty.ptr_to();
+        }
+        ty
+    }
+
+    // Where main() starts in the text segment, if the program has one
+    pub fn entry(&self) -> Option<usize> {
+        match self.symbols.get("main") {
+            Some(symbol) if symbol.class == Class::Fun => Some(symbol.val as usize),
+            _ => None,
+        }
+    }
+
+    // Every function, sorted by address, with the part of the text its code is in
+    pub fn functions(&self) -> Vec<(String, Range<usize>)> {
+        let mut starts: Vec<(usize, &String)> = self
+            .symbols
+            .iter()
+            .filter(|(_, symbol)| symbol.class == Class::Fun)
+            .map(|(name, symbol)| (symbol.val as usize, name))
+            .collect();
+        starts.sort();
+
+        // A function's code runs up to where the next one starts
+        let mut functions = Vec::new();
+        for (i, &(start, name)) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).map_or(self.text.len(), |&(next, _)| next);
+            functions.push((name.clone(), start..end));
+        }
+        functions
+    }
+
+    // Where the code of the function with this name is in the text
+    pub fn function_range(&self, name: &str) -> Option<Range<usize>> {
+        self.functions().into_iter().find(|(n, _)| n == name).map(|(_, range)| range)
+    }
+
+    // Parse the whole program: enums, global variables and functions
+    pub fn parse_program(&mut self) -> ParseResult {
+        while !self.at(Token::Eof) {
+            // The base type of this declaration
+            let mut base = Type::Int;
+            if self.at(Token::Int) {
+                self.advance();
+            } else if self.at(Token::Char) {
                 self.advance();
-                return;
+                base = Type::Char;
+            } else if self.at(Token::Enum) {
+                self.advance();
+                self.parse_enum()?;
+            }
+
+            while !self.at(Token::Semicolon) && !self.at(Token::RBrace) {
+                let ty = self.parse_pointers(base.clone());
+                let at = self.lexer.start;
+                let name = self.take_id("bad global declaration")?;
+                if self.symbols.contains_key(&name) {
+                    return self.error("duplicate global definition");
+                }
+
+                if self.at(Token::LParen) {
+                    self.definitions.insert(name.clone(), at);
+                    self.parse_function(name, ty)?;
+                } else {
+                    // Every global gets one 8-byte slot in the data segment
+                    let address = DATA_BASE + self.data.len() as i64;
+                    self.data.extend_from_slice(&[0; 8]);
+                    self.symbols.insert(name, Symbol { class: Class::Glo, ty, val: address });
+                }
+
+                if self.at(Token::Comma) {
+                    self.advance();
+                }
             }
-            None => return, // No more tokens
+            self.advance(); // Skip ';' (or the '}' that ended an enum)
         }
+        self.warn_unreachable();
+        Ok(())
+    }
 
-        // Handle operators like +, -, *, etc. based on precedence
-        while let Some(op) = &self.current_token {
-            let prec = get_precedence(op);
-            if prec < min_prec {
-                break;
+    // Warn about functions that are never called and statements that can never run
+    fn warn_unreachable(&mut self) {
+        let Some(entry) = self.entry() else {
+            return;
+        };
+        let Some(live) = dce::reachable(&self.text, entry) else {
+            return;
+        };
+        let starts = dce::instruction_starts(&self.text).unwrap_or_default();
+        for (name, range) in self.functions() {
+            if !live[range.start] {
+                let message = format!("function '{}' is never used", name);
+                self.warnings.push(Diagnostic::warning(message, self.definitions[&name]));
+                continue;
+            }
+            // Each run of dead code gets one warning, at the first statement in it (the innermost
+            // one, if a block starts there too). Runs without a statement, like the LEV after
+            // a return, are the compiler's own.
+            let mut reported = false;
+            for &pc in starts.iter().filter(|&&pc| range.contains(&pc)) {
+                if live[pc] {
+                    reported = false;
+                    continue;
+                }
+                if !reported && let Some(&(_, span)) = self.statements.iter().rfind(|(start, _)| *start == pc) {
+                    self.warnings.push(Diagnostic::warning("unreachable code".to_string(), span));
+                    reported = true;
+                }
             }
+        }
+    }
 
-            let operator = op.clone(); // Save the operator
-            println!("Operator: {:?} (prec {})", op, prec);
-            self.advance(); // Move past operator
-            self.parse_expression(prec + 1); // Recursively parse next part
+    // Parse "enum [name] { A, B = 5, C }" after the enum keyword
+    fn parse_enum(&mut self) -> ParseResult {
+        if !self.at(Token::LBrace) {
+            self.advance(); // The enum's name, which C4 ignores
+        }
+        if !self.at(Token::LBrace) {
+            return Ok(());
+        }
+        self.advance();
 
-            // Print the kind of operation we just handled
-            match operator {
-                Token::Add => println!("ADD"),
-                Token::Sub => println!("SUB"),
-                Token::Mul => println!("MUL"),
-                Token::Div => println!("DIV"),
-                Token::Mod => println!("MOD"),
-                _ => println!("Unknown binary operator"),
+        let mut value = 0;
+        while !self.at(Token::RBrace) {
+            let name = self.take_id("bad enum identifier")?;
+            if self.at(Token::Assign) {
+                self.advance();
+                match self.current_token {
+                    Some(Token::Num(n)) => value = n,
+                    _ => return self.error("bad enum initializer"),
+                }
+                self.advance();
+            }
+            self.symbols.insert(name, Symbol { class: Class::Num, ty: Type::Int, val: value });
+            value += 1;
+            if self.at(Token::Comma) {
+                self.advance();
             }
         }
+        self.advance(); // Skip '}'
+        Ok(())
     }
 
-    // Handle full statements like printf(...) or return ...
-    pub fn parse_statement(&mut self) {
-        if let Some(Token::Printf) = self.current_token {
-            println!("Found printf");
-            self.advance(); // Move past 'printf'
+    // Make a name local to the current function, remembering what it meant before
+    fn declare_local(&mut self, name: String, ty: Type, slot: i64, what: &str) -> ParseResult {
+        if self.symbols.get(&name).is_some_and(|symbol| symbol.class == Class::Loc) {
+            return self.error(&format!("duplicate {} definition", what));
+        }
+        let previous = self.symbols.insert(name.clone(), Symbol { class: Class::Loc, ty, val: slot });
+        self.shadowed.push((name, previous));
+        Ok(())
+    }
+
+    // Parse a function definition, starting at the '(' after its name
+    fn parse_function(&mut self, name: String, ty: Type) -> ParseResult {
+        let address = self.text.len() as i64;
+        self.symbols.insert(name.clone(), Symbol { class: Class::Fun, ty, val: address });
+        self.advance(); // Skip '('
 
-            if self.current_token != Some(Token::LParen) {
-                panic!("Expected '(' after printf");
+        // Parameters get slots 0, 1, 2, ... in the order they are written
+        let mut slot = 0;
+        while !self.at(Token::RParen) {
+            let mut ty = Type::Int;
+            if self.at(Token::Int) {
+                self.advance();
+            } else if self.at(Token::Char) {
+                self.advance();
+                ty = Type::Char;
             }
-            self.advance(); // Skip '('
+            let ty = self.parse_pointers(ty);
+            let name = self.take_id("bad parameter declaration")?;
+            self.declare_local(name, ty, slot, "parameter")?;
+            slot += 1;
+            if self.at(Token::Comma) {
+                self.advance();
+            }
+        }
+        self.advance(); // Skip ')'
 
-            println!("Parsing expression inside printf:");
-            self.parse_expression(1); // Get the value to print
+        if !self.at(Token::LBrace) {
+            return self.error("bad function definition");
+        }
+        slot += 1;
+        self.loc = slot;
+        self.advance(); // Skip '{'
 
-            println!("PRTF"); // Simulate a print instruction
+        // Local variable declarations come first, like "int i, *p; char c;"
+        while self.at(Token::Int) || self.at(Token::Char) {
+            let base = if self.at(Token::Int) { Type::Int } else { Type::Char };
+            self.advance();
+            while !self.at(Token::Semicolon) {
+                let ty = self.parse_pointers(base.clone());
+                let name = self.take_id("bad local declaration")?;
+                slot += 1;
+                self.declare_local(name, ty, slot, "local")?;
+                if self.at(Token::Comma) {
+                    self.advance();
+                }
+            }
+            self.advance(); // Skip ';'
+        }
+
+        self.emit_with(Op::Ent, slot - self.loc);
+        while !self.at(Token::RBrace) {
+            self.parse_statement()?;
+        }
+        self.emit(Op::Lev);
+
+        // Keep where the locals live, so a debugger can find them later
+        let mut locals = Vec::new();
+        for (local, _) in &self.shadowed {
+            let symbol = &self.symbols[local];
+            locals.push((local.clone(), Symbol { class: Class::Loc, ty: symbol.ty.clone(), val: self.loc - symbol.val }));
+        }
+        self.locals.insert(name, locals);
 
-            if self.current_token != Some(Token::RParen) {
-                panic!("Expected ')' after printf");
+        // Put back what the local names meant outside this function
+        while let Some((name, previous)) = self.shadowed.pop() {
+            match previous {
+                Some(symbol) => self.symbols.insert(name, symbol),
+                None => self.symbols.remove(&name),
+            };
+        }
+        Ok(())
+    }
+
+    // Parse an expression whose operators all have at least min_prec precedence.
+    // The value ends up in the accumulator and its type in self.ty.
+    pub fn parse_expression(&mut self, min_prec: u8) -> ParseResult {
+        self.parse_unary()?;
+
+        // Handle operators like +, -, *, etc. based on precedence ("precedence climbing")
+        while let Some(op) = self.current_token.clone() {
+            let prec = get_precedence(&op);
+            if prec == 0 || prec < min_prec {
+                break;
             }
-            self.advance(); // Skip ')'
+            let t = self.ty.clone(); // The type of the left-hand side
 
-            if self.current_token != Some(Token::Semicolon) {
-                panic!("Expected ';' after printf()");
+            match op {
+                Token::Assign => {
+                    self.advance();
+                    if matches!(self.last_op(), Some(Op::Lc | Op::Li)) {
+                        self.replace_last(Op::Psh); // keep the address instead of loading from it
+                    } else {
+                        return self.error("bad lvalue in assignment");
+                    }
+                    self.parse_expression(get_precedence(&Token::Assign))?;
+                    self.emit_store(&t);
+                    self.ty = t;
+                }
+                Token::Cond => {
+                    self.advance();
+                    let to_else = self.emit_jump(Op::Bz);
+                    self.parse_expression(get_precedence(&Token::Assign))?;
+                    self.expect(Token::Colon, "conditional missing colon")?;
+                    self.patch_jump(to_else, self.text.len() + 2);
+                    let to_end = self.emit_jump(Op::Jmp);
+                    self.parse_expression(get_precedence(&Token::Cond))?;
+                    self.patch_jump(to_end, self.text.len());
+                }
+                Token::Lor | Token::Lan => {
+                    // Skip the right-hand side when the left already decides the answer
+                    self.advance();
+                    let to_end = self.emit_jump(if op == Token::Lor { Op::Bnz } else { Op::Bz });
+                    self.parse_expression(prec + 1)?;
+                    self.patch_jump(to_end, self.text.len());
+                    self.ty = Type::Int;
+                }
+                Token::Add => {
+                    self.advance();
+                    let left = self.push_left();
+                    self.parse_expression(prec + 1)?;
+                    self.ty = t;
+                    if self.ty.scale() > 1 {
+                        self.emit_scale(Op::Mul);
+                    }
+                    self.emit_operator(Op::Add, left);
+                }
+                Token::Sub => {
+                    self.advance();
+                    let left = self.push_left();
+                    self.parse_expression(prec + 1)?;
+                    if t.scale() > 1 && t == self.ty {
+                        // Pointer minus pointer: the number of elements between them
+                        self.emit_operator(Op::Sub, left);
+                        self.emit_scale(Op::Div);
+                        self.ty = Type::Int;
+                    } else {
+                        self.ty = t;
+                        if self.ty.scale() > 1 {
+                            self.emit_scale(Op::Mul);
+                        }
+                        self.emit_operator(Op::Sub, left);
+                    }
+                }
+                Token::Inc | Token::Dec => {
+                    // Post-increment: store the new value, then undo the step for the result
+                    self.emit_increment(&op, "bad lvalue in post-increment")?;
+                    let step = if self.ty.scale() > 1 { 8 } else { 1 };
+                    self.emit(Op::Psh);
+                    self.emit_with(Op::Imm, step);
+                    self.emit(if op == Token::Inc { Op::Sub } else { Op::Add });
+                    self.advance();
+                }
+                Token::Brak => {
+                    self.advance();
+                    let left = self.push_left();
+                    self.parse_expression(get_precedence(&Token::Assign))?;
+                    self.expect(Token::RBrak, "close bracket expected")?;
+                    if !t.is_ptr() {
+                        return self.error("pointer type expected");
+                    }
+                    if t.scale() > 1 {
+                        self.emit_scale(Op::Mul);
+                    }
+                    self.emit_operator(Op::Add, left);
+                    self.ty = t.deref().unwrap();
+                    self.emit_load();
+                }
+                _ => {
+                    // The simple binary operators: push the left side, compute the right, combine
+                    let instruction = match op {
+                        Token::Or => Op::Or,
+                        Token::Xor => Op::Xor,
+                        Token::And => Op::And,
+                        Token::Eq => Op::Eq,
+                        Token::Ne => Op::Ne,
+                        Token::Lt => Op::Lt,
+                        Token::Gt => Op::Gt,
+                        Token::Le => Op::Le,
+                        Token::Ge => Op::Ge,
+                        Token::Shl => Op::Shl,
+                        Token::Shr => Op::Shr,
+                        Token::Mul => Op::Mul,
+                        Token::Div => Op::Div,
+                        _ => Op::Mod,
+                    };
+                    self.advance();
+                    let left = self.push_left();
+                    self.parse_expression(prec + 1)?;
+                    self.emit_operator(instruction, left);
+                    self.ty = Type::Int;
+                }
             }
-            self.advance(); // Skip ';'
-        } else if let Some(Token::Return) = self.current_token {
-            println!("Found return");
-            self.advance(); // Move past 'return'
+        }
+        Ok(())
+    }
+
+    // Multiply or divide the accumulator by the size of an int, for pointer arithmetic
+    fn emit_scale(&mut self, op: Op) {
+        let left = self.push_left();
+        self.emit_with(Op::Imm, 8);
+        self.emit_operator(op, left);
+    }
 
-            self.parse_expression(1); // Get the value to return
+    // Emit ++ or -- on the lvalue whose load was just emitted, leaving the new value in a
+    fn emit_increment(&mut self, op: &Token, message: &str) -> ParseResult {
+        let load = match self.last_op() {
+            Some(load @ (Op::Lc | Op::Li)) => load,
+            _ => return self.error(message),
+        };
+        self.replace_last(Op::Psh); // keep the address for the store
+        self.emit(load);
+        self.emit(Op::Psh);
+        self.emit_with(Op::Imm, if self.ty.scale() > 1 { 8 } else { 1 });
+        self.emit(if *op == Token::Inc { Op::Add } else { Op::Sub });
+        let ty = self.ty.clone();
+        self.emit_store(&ty);
+        Ok(())
+    }
 
-            println!("LEV"); // Simulate function return
+    // Parse the start of an expression: a number, string, name, call, cast or prefix operator
+    fn parse_unary(&mut self) -> ParseResult {
+        let inc = get_precedence(&Token::Inc);
+        match self.current_token.clone() {
+            Some(Token::Num(val)) => {
+                self.emit_with(Op::Imm, val); // Put the number in the accumulator
+                self.advance(); // Go to next token
+                self.ty = Type::Int;
+            }
+            Some(Token::Str(text)) => {
+                // Put the string's address in the accumulator. Like C4 this is emitted at the
+                // first piece, so the -s listing shows it on the line where the string starts.
+                self.emit_with(Op::Imm, DATA_BASE + self.data.len() as i64);
 
-            if self.current_token != Some(Token::Semicolon) {
-                panic!("Expected ';' after return");
+                // Strings written next to each other are joined: "ab" "cd" is "abcd"
+                let mut text = text;
+                self.advance();
+                while let Some(Token::Str(more)) = &self.current_token {
+                    text.push_str(more);
+                    self.advance();
+                }
+                self.add_string(&text);
+                self.ty = Type::Char.ptr_to();
             }
-            self.advance(); // Skip ';'
-        } else {
-            // We don’t support other statements yet
-            panic!("Unsupported statement: {:?}", self.current_token);
+            Some(Token::Sizeof) => {
+                self.advance();
+                self.expect(Token::LParen, "open paren expected in sizeof")?;
+                let mut ty = Type::Int;
+                if self.at(Token::Int) {
+                    self.advance();
+                } else if self.at(Token::Char) {
+                    self.advance();
+                    ty = Type::Char;
+                }
+                let ty = self.parse_pointers(ty);
+                self.expect(Token::RParen, "close paren expected in sizeof")?;
+                self.emit_with(Op::Imm, ty.size());
+                self.ty = Type::Int;
+            }
+            Some(Token::Id(name)) => {
+                self.advance();
+                let symbol = self.symbols.get(&name).cloned();
+                if self.at(Token::LParen) {
+                    self.parse_call(symbol)?;
+                } else {
+                    match symbol {
+                        Some(Symbol { class: Class::Num, val, .. }) => {
+                            self.emit_with(Op::Imm, val);
+                            self.ty = Type::Int;
+                        }
+                        Some(Symbol { class: Class::Loc, ty, val }) => {
+                            self.emit_with(Op::Lea, self.loc - val);
+                            self.ty = ty;
+                            self.emit_load();
+                        }
+                        Some(Symbol { class: Class::Glo, ty, val }) => {
+                            self.emit_with(Op::Imm, val);
+                            self.ty = ty;
+                            self.emit_load();
+                        }
+                        _ => return self.error("undefined variable"),
+                    }
+                }
+            }
+            Some(Token::LParen) => {
+                self.advance();
+                if self.at(Token::Int) || self.at(Token::Char) {
+                    // A cast like (char *)p only changes the type
+                    let base = if self.at(Token::Int) { Type::Int } else { Type::Char };
+                    self.advance();
+                    let ty = self.parse_pointers(base);
+                    self.expect(Token::RParen, "bad cast")?;
+                    self.parse_expression(inc)?;
+                    self.ty = ty;
+                } else {
+                    self.parse_expression(get_precedence(&Token::Assign))?;
+                    self.expect(Token::RParen, "close paren expected")?;
+                }
+            }
+            Some(Token::Mul) => {
+                self.advance();
+                self.parse_expression(inc)?;
+                match self.ty.deref() {
+                    Some(ty) => self.ty = ty,
+                    None => return self.error("bad dereference"),
+                }
+                self.emit_load();
+            }
+            Some(Token::And) => {
+                self.advance();
+                self.parse_expression(inc)?;
+                if matches!(self.last_op(), Some(Op::Lc | Op::Li)) {
+                    self.text.pop(); // the address is what we want, so don't load from it
+                    self.spans.pop();
+                    self.last = None;
+                } else {
+                    return self.error("bad address-of");
+                }
+                self.ty = self.ty.clone().ptr_to();
+            }
+            Some(Token::Not) => {
+                self.advance();
+                self.parse_expression(inc)?;
+                let left = self.push_left();
+                self.emit_with(Op::Imm, 0);
+                self.emit_operator(Op::Eq, left);
+                self.ty = Type::Int;
+            }
+            Some(Token::Tilde) => {
+                self.advance();
+                self.parse_expression(inc)?;
+                let left = self.push_left();
+                self.emit_with(Op::Imm, -1);
+                self.emit_operator(Op::Xor, left);
+                self.ty = Type::Int;
+            }
+            Some(Token::Add) => {
+                self.advance();
+                self.parse_expression(inc)?;
+                self.ty = Type::Int;
+            }
+            Some(Token::Sub) => {
+                self.advance();
+                if let Some(Token::Num(val)) = self.current_token {
+                    self.emit_with(Op::Imm, val.wrapping_neg());
+                    self.advance();
+                } else {
+                    self.emit_with(Op::Imm, -1);
+                    let left = self.push_left();
+                    self.parse_expression(inc)?;
+                    self.emit_operator(Op::Mul, left);
+                }
+                self.ty = Type::Int;
+            }
+            Some(op @ (Token::Inc | Token::Dec)) => {
+                // Pre-increment: the result is the new value
+                self.advance();
+                self.parse_expression(inc)?;
+                self.emit_increment(&op, "bad lvalue in pre-increment")?;
+            }
+            Some(Token::Eof) | None => return self.error("unexpected eof in expression"),
+            Some(_) => return self.error("bad expression"),
         }
+        Ok(())
+    }
+
+    // Parse a call like f(x, y) or printf("%d\n", x). Arguments are pushed from left to right.
+    fn parse_call(&mut self, symbol: Option<Symbol>) -> ParseResult {
+        self.advance(); // Skip '('
+        let mut argc = 0;
+        while !self.at(Token::RParen) {
+            self.parse_expression(get_precedence(&Token::Assign))?;
+            self.emit(Op::Psh);
+            argc += 1;
+            if self.at(Token::Comma) {
+                self.advance();
+            } else if !self.at(Token::RParen) {
+                return self.error("close paren expected in function call");
+            }
+        }
+        self.advance(); // Skip ')'
+
+        match symbol {
+            Some(Symbol { class: Class::Sys, val, ty }) => {
+                self.emit_code(val); // a system call, or a host function after LLI
+                self.ty = ty;
+            }
+            Some(Symbol { class: Class::Fun, val, ty }) => {
+                self.emit_with(Op::Jsr, val);
+                self.ty = ty;
+            }
+            _ => return self.error("bad function call"),
+        }
+
+        // The caller removes the arguments from the stack afterwards
+        if argc > 0 {
+            self.emit_with(Op::Adj, argc);
+        }
+        Ok(())
+    }
+
+    // Handle one statement: if, while, return, a { block }, an empty ';' or an expression
+    pub fn parse_statement(&mut self) -> ParseResult {
+        self.statements.push((self.text.len(), self.lexer.start));
+        match self.current_token {
+            Some(Token::If) => {
+                self.advance();
+                self.expect(Token::LParen, "open paren expected")?;
+                self.parse_expression(get_precedence(&Token::Assign))?;
+                self.expect(Token::RParen, "close paren expected")?;
+                let mut to_end = self.emit_jump(Op::Bz);
+                self.parse_statement()?;
+                if self.at(Token::Else) {
+                    self.patch_jump(to_end, self.text.len() + 2);
+                    to_end = self.emit_jump(Op::Jmp);
+                    self.advance();
+                    self.parse_statement()?;
+                }
+                self.patch_jump(to_end, self.text.len());
+            }
+            Some(Token::While) => {
+                self.advance();
+                let start = self.text.len();
+                self.barrier = start; // the loop jumps back here
+                self.expect(Token::LParen, "open paren expected")?;
+                self.parse_expression(get_precedence(&Token::Assign))?;
+                self.expect(Token::RParen, "close paren expected")?;
+                let to_end = self.emit_jump(Op::Bz);
+                self.parse_statement()?;
+                self.emit_with(Op::Jmp, start as i64);
+                self.patch_jump(to_end, self.text.len());
+            }
+            Some(Token::Return) => {
+                self.advance(); // Move past 'return'
+                if !self.at(Token::Semicolon) {
+                    self.parse_expression(get_precedence(&Token::Assign))?; // Get the value to return
+                }
+                self.emit(Op::Lev); // Return from the function
+                self.expect(Token::Semicolon, "semicolon expected")?;
+            }
+            Some(Token::LBrace) => {
+                self.advance();
+                while !self.at(Token::RBrace) {
+                    self.parse_statement()?;
+                }
+                self.advance();
+            }
+            Some(Token::Semicolon) => self.advance(),
+            _ => {
+                // Anything else is an expression whose value we don't need, like printf(...)
+                self.parse_expression(get_precedence(&Token::Assign))?;
+                self.expect(Token::Semicolon, "semicolon expected")?;
+            }
+        }
+        Ok(())
     }
 }
 
 // This gives each operator a priority (higher number = stronger)
 // For example, * and / come before + and -
-fn get_precedence(token: &Token) -> u8 {
+pub(crate) fn get_precedence(token: &Token) -> u8 {
     match token {
         Token::Assign => 1,
-        Token::Lor => 2,
-        Token::Lan => 3,
-        Token::Or => 4,
-        Token::Xor => 5,
-        Token::And => 6,
-        Token::Eq | Token::Ne => 7,
-        Token::Lt | Token::Gt | Token::Le | Token::Ge => 8,
-        Token::Shl | Token::Shr => 9,
-        Token::Add | Token::Sub => 10,
-        Token::Mul | Token::Div | Token::Mod => 11,
+        Token::Cond => 2,
+        Token::Lor => 3,
+        Token::Lan => 4,
+        Token::Or => 5,
+        Token::Xor => 6,
+        Token::And => 7,
+        Token::Eq | Token::Ne => 8,
+        Token::Lt | Token::Gt | Token::Le | Token::Ge => 9,
+        Token::Shl | Token::Shr => 10,
+        Token::Add | Token::Sub => 11,
+        Token::Mul | Token::Div | Token::Mod => 12,
+        Token::Inc | Token::Dec => 13,
+        Token::Brak => 14,
         _ => 0,
     }
 }
@@ -149,18 +877,62 @@ mod tests {
         let lexer = Lexer::new(input);
         let mut parser = Parser::new(lexer);
 
-        parser.parse_statement(); // should handle printf
-        parser.parse_statement(); // should handle return
+        parser.parse_statement().unwrap(); // should handle printf
+        parser.parse_statement().unwrap(); // should handle return
     }
 
     #[test]
-    #[should_panic(expected = "Syntax Error")]
     fn test_error_reporting_missing_paren() {
         let input = "printf(2 + 3;";
         let lexer = Lexer::new(input);
         let mut parser = Parser::new(lexer);
-        parser.parse_statement(); // should panic here
+        let error = parser.parse_statement().unwrap_err(); // should fail here
+        assert_eq!(
+            error.to_string(),
+            "Syntax Error: close paren expected in function call (found ';') at line 1, col 13"
+        );
+    }
+
+    // Compile one expression statement with -O on
+    fn fold(input: &str) -> Parser<'_> {
+        let mut parser = Parser::new(Lexer::new(input));
+        parser.optimize = true;
+        parser.parse_statement().unwrap();
+        parser
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let imm = Op::Imm.code();
+        assert_eq!(fold("2 + 3 * 4;").text, vec![imm, 14]);
+        assert_eq!(fold("-7 / 2 + (-7 % 2) * 100;").text, vec![imm, -103]); // C rounds toward zero
+        assert_eq!(fold("(1 << 4) >> 2 | 3 == 3;").text, vec![imm, 5]);
+        assert_eq!(fold("!0 + ~5 + -(2 < 1);").text, vec![imm, -5]);
+        assert_eq!(fold("sizeof(int) * 2;").text, vec![imm, 16]);
+
+        // A conditional's result isn't a constant, even when both arms are
+        let parser = fold("(1 ? 2 : 3) + 4;");
+        assert_eq!(&parser.text[parser.text.len() - 4..], &[Op::Psh.code(), imm, 4, Op::Add.code()]);
+
+        // Without -O the code is C4's
+        let mut parser = Parser::new(Lexer::new("2 + 3;"));
+        parser.parse_statement().unwrap();
+        assert_eq!(parser.text, vec![imm, 2, Op::Psh.code(), imm, 3, Op::Add.code()]);
     }
-}
 
+    #[test]
+    fn test_division_by_zero_is_not_folded() {
+        let parser = fold("6 / 0;");
+        assert_eq!(parser.text, vec![Op::Imm.code(), 6, Op::Psh.code(), Op::Imm.code(), 0, Op::Div.code()]);
+        assert_eq!(parser.warnings.len(), 1);
+        assert_eq!(parser.warnings[0].to_string(), "Warning: division by zero at line 1, col 5");
 
+        // A 0 at the end of a ?: or || is only one of the values the right side can have
+        for source in ["6 / (1 ? 2 : 0);", "6 % (1 || 0);"] {
+            assert!(fold(source).warnings.is_empty(), "{}", source);
+            let mut parser = Parser::new(Lexer::new(source));
+            parser.parse_statement().unwrap();
+            assert!(parser.warnings.is_empty(), "{}", source);
+        }
+    }
+}
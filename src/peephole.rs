@@ -0,0 +1,293 @@
+// The peephole optimiser (-O). It looks at short runs of instructions in a compiled program
+// and swaps them for fewer or faster instructions that do the same thing. Each rule in RULES
+// names a pattern of instructions and how to rewrite it; the rules are tried at every
+// instruction, again and again, until none of them applies any more.
+use crate::lexer::Span;
+use crate::opcode::Op;
+use crate::Program;
+use std::collections::{HashMap, HashSet};
+
+// One instruction while the optimiser works on it. Jumps and calls hold the index of the
+// instruction they go to rather than its address, so instructions can come and go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub code: i64,            // The instruction's number (an Op, or a host function)
+    pub operand: Option<i64>, // The operand, for instructions up to ADJ and the new ones
+    pub span: Span,           // Where in the source it came from
+}
+
+impl Instruction {
+    fn op(&self) -> Option<Op> {
+        Op::from_code(self.code)
+    }
+
+    // Another instruction from the same place in the source
+    fn with(&self, op: Op, operand: i64) -> Instruction {
+        Instruction { code: op.code(), operand: Some(operand), span: self.span }
+    }
+
+    fn operand(&self) -> i64 {
+        self.operand.unwrap_or(0)
+    }
+}
+
+// A rule: when instructions match the pattern, rewrite gives back what to put in their place
+// (maybe nothing), or None if the rule doesn't apply after all. It also gets the index of the
+// first instruction, which is what a jump to the next instruction would hold.
+pub struct Rule {
+    pub name: &'static str,
+    pub pattern: &'static [Op],
+    pub rewrite: fn(at: usize, window: &[Instruction]) -> Option<Vec<Instruction>>,
+}
+
+pub const RULES: &[Rule] = &[
+    // x + n: "PSH; IMM n; ADD" adds a constant without going through the stack
+    Rule { name: "add-immediate", pattern: &[Op::Psh, Op::Imm, Op::Add], rewrite: |_, w| Some(vec![w[0].with(Op::Addi, w[1].operand())]) },
+    // x - n is x + -n
+    Rule { name: "subtract-immediate", pattern: &[Op::Psh, Op::Imm, Op::Sub], rewrite: |_, w| Some(vec![w[0].with(Op::Addi, w[1].operand().wrapping_neg())]) },
+    // Adding 0 does nothing
+    Rule { name: "add-zero", pattern: &[Op::Addi], rewrite: |_, w| (w[0].operand() == 0).then(Vec::new) },
+    // Two constants added one after the other are one constant
+    Rule { name: "merge-add-immediate", pattern: &[Op::Addi, Op::Addi], rewrite: |_, w| Some(vec![w[0].with(Op::Addi, w[0].operand().wrapping_add(w[1].operand()))]) },
+    // Adding a constant to a constant
+    Rule { name: "fold-immediate-add", pattern: &[Op::Imm, Op::Addi], rewrite: |_, w| Some(vec![w[0].with(Op::Imm, w[0].operand().wrapping_add(w[1].operand()))]) },
+    // Reading a local variable: "LEA n; LI" becomes one instruction
+    Rule { name: "load-local", pattern: &[Op::Lea, Op::Li], rewrite: |_, w| Some(vec![w[0].with(Op::Lli, w[0].operand())]) },
+    // A jump to the instruction right after it (like an empty else) goes nowhere
+    Rule { name: "jump-to-next", pattern: &[Op::Jmp], rewrite: |at, w| (w[0].operand() == at as i64 + 1).then(Vec::new) },
+    // Pushing a word and dropping it straight away leaves the stack as it was
+    Rule {
+        name: "push-drop",
+        pattern: &[Op::Psh, Op::Adj],
+        rewrite: |_, w| match w[1].operand() {
+            1 => Some(Vec::new()),
+            n if n > 1 => Some(vec![w[1].with(Op::Adj, n - 1)]),
+            _ => None,
+        },
+    },
+];
+
+fn is_jump(op: Option<Op>) -> bool {
+    matches!(op, Some(Op::Jmp | Op::Jsr | Op::Bz | Op::Bnz))
+}
+
+// The program as optimiser instructions, with the entry point and functions as indices
+struct Code {
+    instructions: Vec<Instruction>,
+    entry: usize,
+    functions: Vec<(String, usize, usize)>,
+}
+
+// Split the text into instructions. Gives back None if a jump doesn't land on an instruction,
+// which only hand-made code does; such programs are left as they are.
+fn decode(program: &Program) -> Option<Code> {
+    let text = &program.text;
+    let mut instructions = Vec::new();
+    let mut index = HashMap::new(); // Address to instruction index
+    let mut pc = 0;
+    while pc < text.len() {
+        index.insert(pc, instructions.len());
+        let span = program.spans.get(pc).copied().unwrap_or_default();
+        let code = text[pc];
+        let operand = match Op::from_code(code) {
+            Some(op) if op.has_operand() => Some(*text.get(pc + 1)?),
+            _ => None,
+        };
+        instructions.push(Instruction { code, operand, span });
+        pc += if operand.is_some() { 2 } else { 1 };
+    }
+    index.insert(text.len(), instructions.len());
+
+    for instruction in &mut instructions {
+        if is_jump(instruction.op()) {
+            let target = usize::try_from(instruction.operand()).ok()?;
+            instruction.operand = Some(*index.get(&target)? as i64);
+        }
+    }
+    let entry = *index.get(&program.entry)?;
+    let mut functions = Vec::new();
+    for (name, range) in &program.functions {
+        functions.push((name.clone(), *index.get(&range.start)?, *index.get(&range.end)?));
+    }
+    Some(Code { instructions, entry, functions })
+}
+
+// Try every rule once at each instruction. Gives back whether anything changed.
+fn pass(code: &mut Code, used: &mut HashMap<&'static str, usize>) -> bool {
+    let old = &code.instructions;
+
+    // A rule may not swallow an instruction that something jumps to, only start at one
+    let mut targets: HashSet<usize> = old.iter().filter(|i| is_jump(i.op())).map(|i| i.operand() as usize).collect();
+    targets.insert(code.entry);
+    targets.extend(code.functions.iter().map(|&(_, start, _)| start));
+
+    let mut new = Vec::new();
+    let mut moved = vec![0; old.len() + 1]; // Where each old instruction's code is now
+    let mut i = 0;
+    while i < old.len() {
+        let found = RULES.iter().find_map(|rule| {
+            let window = old.get(i..i + rule.pattern.len())?;
+            let matches = window.iter().zip(rule.pattern).all(|(instruction, &op)| instruction.op() == Some(op));
+            if !matches || (i + 1..i + window.len()).any(|j| targets.contains(&j)) {
+                return None;
+            }
+            Some((rule, (rule.rewrite)(i, window)?))
+        });
+        match found {
+            Some((rule, replacement)) => {
+                moved[i..i + rule.pattern.len()].fill(new.len());
+                new.extend(replacement);
+                *used.entry(rule.name).or_default() += 1;
+                i += rule.pattern.len();
+            }
+            None => {
+                moved[i] = new.len();
+                new.push(old[i]);
+                i += 1;
+            }
+        }
+    }
+    moved[old.len()] = new.len();
+    if new.len() == old.len() && new == *old {
+        return false;
+    }
+
+    for instruction in &mut new {
+        if is_jump(instruction.op()) {
+            instruction.operand = Some(moved[instruction.operand() as usize] as i64);
+        }
+    }
+    code.entry = moved[code.entry];
+    for function in &mut code.functions {
+        function.1 = moved[function.1];
+        function.2 = moved[function.2];
+    }
+    code.instructions = new;
+    true
+}
+
+// Optimise the program in place. Gives back how many times each rule was used.
+pub fn optimize(program: &mut Program) -> HashMap<&'static str, usize> {
+    let mut used = HashMap::new();
+    let Some(mut code) = decode(program) else {
+        return used;
+    };
+    while pass(&mut code, &mut used) {}
+
+    // Lay the instructions out as words again, now that their addresses are known
+    let mut addresses = Vec::with_capacity(code.instructions.len() + 1);
+    let mut address = 0;
+    for instruction in &code.instructions {
+        addresses.push(address);
+        address += if instruction.operand.is_some() { 2 } else { 1 };
+    }
+    addresses.push(address);
+
+    program.text.clear();
+    program.spans.clear();
+    for instruction in &code.instructions {
+        program.text.push(instruction.code);
+        program.spans.push(instruction.span);
+        if let Some(operand) = instruction.operand {
+            let operand = if is_jump(instruction.op()) { addresses[operand as usize] as i64 } else { operand };
+            program.text.push(operand);
+            program.spans.push(instruction.span);
+        }
+    }
+    program.entry = addresses[code.entry];
+    program.functions = code.functions.into_iter().map(|(name, start, end)| (name, addresses[start]..addresses[end])).collect();
+    used
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::compile;
+    use crate::host::SharedBuffer;
+    use crate::RunConfig;
+
+    // Run a program and give back what it printed and its exit code
+    fn run(program: &Program) -> (String, i64) {
+        let output = SharedBuffer::new();
+        let config = RunConfig { output: Some(Box::new(output.clone())), ..RunConfig::default() };
+        let status = program.run(config).unwrap();
+        (output.text(), status.code)
+    }
+
+    // Optimise the program, check that rule was used and that the program still does the same
+    fn check(rule: &str, program: Program) {
+        let before = run(&program);
+        let mut optimized = program.clone();
+        let used = optimize(&mut optimized);
+        assert!(used.get(rule).is_some_and(|&n| n > 0), "{} was not used: {:?}", rule, used);
+        assert!(optimized.text.len() < program.text.len());
+        assert_eq!(optimized.spans.len(), optimized.text.len());
+        assert_eq!(run(&optimized), before, "{} changed what the program does", rule);
+    }
+
+    #[test]
+    fn test_add_and_subtract_immediate() {
+        let program = compile("int main() { int x; x = 40; printf(\"%d\\n\", x + 5); return x - 3; }").unwrap();
+        check("add-immediate", program.clone());
+        check("subtract-immediate", program);
+    }
+
+    #[test]
+    fn test_add_zero_and_merge() {
+        check("add-zero", compile("int main() { int x; x = 7; return x + 0; }").unwrap());
+        check("merge-add-immediate", compile("int main() { int x; x = 7; return x + 1 + 2 - 10; }").unwrap());
+        check("fold-immediate-add", compile("int main() { return 2 + 3; }").unwrap());
+    }
+
+    #[test]
+    fn test_load_local() {
+        let program = compile("int sq(int n) { return n * n; } int main() { int i; i = 9; return sq(i); }").unwrap();
+        check("load-local", program);
+    }
+
+    #[test]
+    fn test_jump_to_next() {
+        let source = "int main() { int x; x = 0; if (x) x = 1; else ; while (x < 3) x++; return x; }";
+        check("jump-to-next", compile(source).unwrap());
+    }
+
+    #[test]
+    fn test_push_drop() {
+        let source = "
+            main:
+                ENT  0
+                IMM  5
+                PSH
+                PSH
+                ADJ  1      ; a push that is dropped at once
+                PSH
+                PSH
+                ADJ  2      ; and one dropped with the word under it
+                LEV";
+        let program = assemble(source).unwrap();
+        check("push-drop", program);
+    }
+
+    #[test]
+    fn test_whole_program() {
+        let source = "
+            int count; char *name;
+            int sq(int x) { return x * x; }
+            int main() {
+                int i; char *p;
+                name = \"peephole\";
+                i = 0;
+                while (i < 10) { if (i & 1) count = count + sq(i); else count = count - 1; i++; }
+                p = name; while (*p) p = p + 1;
+                printf(\"%s %d %d\\n\", name, count, p - name);
+                return count > 100 ? count : -1;
+            }";
+        let program = compile(source).unwrap();
+        let mut optimized = program.clone();
+        optimize(&mut optimized);
+        assert_eq!(run(&optimized), run(&program));
+        assert_eq!(run(&program), ("peephole 160 8\n".to_string(), 160));
+        assert_eq!(optimized.function_range("sq").unwrap().start, 0);
+    }
+}
@@ -0,0 +1,518 @@
+// A register VM (--register-vm). The stack VM works everything out in its accumulator and
+// pushes and pops to get at more than one value; this one has three-address instructions
+// over a file of registers, like "r5 = add r3, r4", so there is no stack traffic for
+// expressions at all.
+//
+// Its code is made from the SSA IR (ir.rs): every value gets its own register, and each phi
+// becomes copies at the end of the blocks that lead to it. Calls still lay out their frame in
+// memory the way C4 does (arguments, return address, saved bp, then the locals), so locals
+// that live in memory and pointers to them work the same. Memory, system calls and limits are
+// the stack VM's own, so a program does the same thing on either VM.
+use crate::host::FIRST_HOST_CODE;
+use crate::ir::{BlockId, Function, Inst, Module, Terminator, ValueId};
+use crate::ir_passes;
+use crate::opcode::Op;
+use crate::vm::{VmError, VmErrorKind, VM};
+use crate::{ExitStatus, Program, RunConfig};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+
+// A register number, counted from the start of the running function's registers
+pub type Reg = u32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegOp {
+    Const { dst: Reg, value: i64 },                   // dst = value
+    Local { dst: Reg, offset: i64 },                  // dst = bp + offset words (LEA)
+    LoadLocal { dst: Reg, offset: i64 },              // dst = the word at bp + offset words (an argument)
+    Move { dst: Reg, src: Reg },                      // dst = src
+    Binary { op: Op, dst: Reg, left: Reg, right: Reg }, // dst = left op right
+    Load { dst: Reg, address: Reg, char: bool },      // dst = *address
+    Store { dst: Reg, address: Reg, value: Reg, char: bool }, // *address = value, dst = what SI/SC leave in a
+    Call { dst: Reg, function: usize, args: Vec<Reg> }, // dst = functions[function](args...)
+    Syscall { dst: Reg, code: i64, args: Vec<Reg> },  // dst = a system call or host function
+    Jump { target: usize },                           // Go to code[target]
+    Bz { cond: Reg, target: usize },                  // Go to code[target] if cond is 0
+    Bnz { cond: Reg, target: usize },                 // Go to code[target] if cond isn't 0
+    Return { src: Reg },                              // Give src back to the caller
+    Exit { src: Reg },                                // Stop the program with src as its exit code
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegFunction {
+    pub name: String,
+    pub start: usize,     // Where its code starts
+    pub frame: i64,       // How many words of locals it keeps in memory
+    pub registers: usize, // How many registers it uses
+}
+
+// A program compiled for the register VM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegProgram {
+    pub code: Vec<RegOp>,
+    pub functions: Vec<RegFunction>,
+    pub entry: usize,                // Which function is main
+    pub origins: Vec<(usize, i64)>,  // For each instruction, the stack VM instruction it came from (address and word), for faults
+}
+
+impl RegProgram {
+    // Compile a program for the register VM, through the IR (optimised if optimize is set).
+    // Gives back a message if the program can't be turned into IR.
+    pub fn from_program(program: &Program, optimize: bool) -> Result<RegProgram, String> {
+        let mut module = Module::from_program(program)?;
+        if optimize {
+            ir_passes::optimize(&mut module);
+        }
+        let mut out = RegProgram { code: Vec::new(), functions: Vec::new(), entry: 0, origins: Vec::new() };
+        let index: HashMap<usize, usize> = module.functions.iter().enumerate().map(|(i, f)| (f.address, i)).collect();
+        for function in &module.functions {
+            Codegen::new(function, &index, program, &mut out).emit()?;
+        }
+        out.entry = *index.get(&module.entry).ok_or("main() is not a function")?;
+        Ok(out)
+    }
+}
+
+// Makes the code for one function
+struct Codegen<'a> {
+    function: &'a Function,
+    index: &'a HashMap<usize, usize>, // Function address to function number
+    program: &'a Program,
+    out: &'a mut RegProgram,
+    registers: Vec<Option<Reg>>,      // The register of each value
+    scratch: Reg,                     // A spare register for copies that go round in a cycle
+    block_starts: Vec<usize>,
+    fixups: Vec<usize>,               // Jumps whose target is still a block number
+}
+
+impl<'a> Codegen<'a> {
+    fn new(function: &'a Function, index: &'a HashMap<usize, usize>, program: &'a Program, out: &'a mut RegProgram) -> Self {
+        let mut registers = vec![None; function.values.len()];
+        let mut count = 0;
+        for block in &function.blocks {
+            for &id in &block.insts {
+                registers[id] = Some(count);
+                count += 1;
+            }
+        }
+        let blocks = function.blocks.len();
+        Codegen { function, index, program, out, registers, scratch: count, block_starts: vec![0; blocks], fixups: Vec::new() }
+    }
+
+    fn reg(&self, value: ValueId) -> Reg {
+        self.registers[value].expect("a value used outside the blocks")
+    }
+
+    fn push(&mut self, op: RegOp, address: usize) {
+        self.out.code.push(op);
+        self.out.origins.push((address, self.program.text.get(address).copied().unwrap_or(0)));
+    }
+
+    // A jump to a block, filled in once every block has its place
+    fn jump_to(&mut self, op: RegOp, address: usize) {
+        self.fixups.push(self.out.code.len());
+        self.push(op, address);
+    }
+
+    fn emit(mut self) -> Result<(), String> {
+        let f = self.function;
+        self.out.functions.push(RegFunction { name: f.name.clone(), start: self.out.code.len(), frame: f.frame, registers: self.scratch as usize + 1 });
+        for b in 0..f.blocks.len() {
+            self.block_starts[b] = self.out.code.len();
+            let block = &f.blocks[b];
+            for &id in &block.insts {
+                let dst = self.reg(id);
+                let address = f.addresses[id];
+                let op = match &f.values[id] {
+                    Inst::Phi(_) => continue, // Set by the blocks that come here
+                    Inst::Undef => RegOp::Const { dst, value: 0 },
+                    &Inst::Const(value) => RegOp::Const { dst, value },
+                    &Inst::Param(offset) => RegOp::LoadLocal { dst, offset },
+                    &Inst::LocalAddr(offset) => RegOp::Local { dst, offset },
+                    &Inst::Copy(src) => RegOp::Move { dst, src: self.reg(src) },
+                    &Inst::Binary(op, left, right) => RegOp::Binary { op, dst, left: self.reg(left), right: self.reg(right) },
+                    &Inst::Load { char, address } => RegOp::Load { dst, address: self.reg(address), char },
+                    &Inst::Store { char, address, value } => RegOp::Store { dst, address: self.reg(address), value: self.reg(value), char },
+                    Inst::Call { target, args } => {
+                        let function = *self.index.get(target).ok_or_else(|| format!("{}: call to {}, which is not a function", f.name, target))?;
+                        RegOp::Call { dst, function, args: args.iter().map(|&v| self.reg(v)).collect() }
+                    }
+                    Inst::Syscall { code, args } => RegOp::Syscall { dst, code: *code, args: args.iter().map(|&v| self.reg(v)).collect() },
+                };
+                self.push(op, address);
+            }
+
+            let address = block.address;
+            match block.term {
+                Terminator::Jump(target) => self.goto(b, target, address, true),
+                Terminator::Branch { cond, nonzero, zero } => {
+                    let cond = self.reg(cond);
+                    if self.copies(b, nonzero).is_empty() {
+                        self.jump_to(RegOp::Bnz { cond, target: nonzero }, address);
+                        self.goto(b, zero, address, true);
+                    } else if self.copies(b, zero).is_empty() {
+                        self.jump_to(RegOp::Bz { cond, target: zero }, address);
+                        self.goto(b, nonzero, address, true);
+                    } else {
+                        // Each way needs its own copies: go round the nonzero ones when cond is 0
+                        let skip = self.out.code.len();
+                        self.push(RegOp::Bz { cond, target: 0 }, address);
+                        self.goto(b, nonzero, address, false);
+                        let here = self.out.code.len();
+                        self.out.code[skip] = RegOp::Bz { cond, target: here };
+                        self.goto(b, zero, address, true);
+                    }
+                }
+                Terminator::Return(v) => self.push(RegOp::Return { src: self.reg(v) }, address),
+                Terminator::Exit(v) => self.push(RegOp::Exit { src: self.reg(v) }, address),
+            }
+        }
+
+        for at in std::mem::take(&mut self.fixups) {
+            match &mut self.out.code[at] {
+                RegOp::Jump { target } | RegOp::Bz { target, .. } | RegOp::Bnz { target, .. } => *target = self.block_starts[*target],
+                _ => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    // The copies going from block b to target: (phi register, incoming register)
+    fn copies(&self, b: BlockId, target: BlockId) -> Vec<(Reg, Reg)> {
+        let mut copies = Vec::new();
+        for &id in &self.function.blocks[target].insts {
+            if let Inst::Phi(incoming) = &self.function.values[id] {
+                for &(from, value) in incoming {
+                    if from == b && self.reg(value) != self.reg(id) {
+                        copies.push((self.reg(id), self.reg(value)));
+                    }
+                }
+            }
+        }
+        copies
+    }
+
+    // Do the copies for going from b to target, then go there. If it is the next block and
+    // fall is set, just carry on into it.
+    fn goto(&mut self, b: BlockId, target: BlockId, address: usize, fall: bool) {
+        // The phis all take their values at once, so a copy can't overwrite a register
+        // another copy still has to read. Do the ones that are safe first; if only a cycle
+        // is left (like swapping two values), save one value in the scratch register.
+        let mut pending = self.copies(b, target);
+        while !pending.is_empty() {
+            let safe = pending.iter().position(|&(dst, _)| pending.iter().all(|&(_, src)| src != dst));
+            match safe {
+                Some(i) => {
+                    let (dst, src) = pending.remove(i);
+                    self.push(RegOp::Move { dst, src }, address);
+                }
+                None => {
+                    let (_, src) = pending[0];
+                    self.push(RegOp::Move { dst: self.scratch, src }, address);
+                    for copy in &mut pending {
+                        if copy.1 == src {
+                            copy.1 = self.scratch;
+                        }
+                    }
+                }
+            }
+        }
+        if !(fall && target == b + 1) {
+            self.jump_to(RegOp::Jump { target }, address);
+        }
+    }
+}
+
+// A call that hasn't returned yet
+struct Frame {
+    return_pc: usize, // Where to go on in the caller
+    base: usize,      // Where the caller's registers start
+    dst: Reg,         // The caller's register for the result
+    args: usize,      // How many argument words to drop from the stack
+    function: usize,  // The caller
+}
+
+// The register VM. It keeps a stack VM for the memory, the system calls, the I/O and the limits.
+pub struct RegisterVM {
+    pub vm: VM,
+    pub program: RegProgram,
+    pub cycle: u64, // How many register instructions have run
+}
+
+impl RegisterVM {
+    // Make a register VM ready to run program, compiled as code, with the given configuration
+    pub fn new(program: &Program, code: RegProgram, config: RunConfig) -> Result<RegisterVM, VmError> {
+        Ok(RegisterVM { vm: program.vm(config)?, program: code, cycle: 0 })
+    }
+
+    // Run the program until it exits and give back its exit value
+    pub fn run(&mut self) -> Result<i64, VmError> {
+        let mut pc = 0;
+        let result = self.execute(&mut pc);
+        self.vm.output.flush().ok();
+        result.map_err(|kind| {
+            let (address, code) = self.program.origins.get(pc).copied().unwrap_or_default();
+            VmError { kind, pc: address, code: Some(code) }
+        })
+    }
+
+    // Make a call's frame like ENT does: save bp, point it here and make room for the locals
+    fn enter(vm: &mut VM, function: &RegFunction) -> Result<usize, VmErrorKind> {
+        vm.push(vm.bp)?;
+        vm.bp = vm.sp;
+        vm.move_sp(-function.frame)?;
+        Ok(function.start)
+    }
+
+    fn execute(&mut self, pc: &mut usize) -> Result<i64, VmErrorKind> {
+        let main = self.program.entry;
+        let mut regs = vec![0; self.program.functions[main].registers];
+        let mut base = 0;
+        let mut current = main; // The function that is running
+        let mut frames: Vec<Frame> = Vec::new();
+        let mut values = Vec::new(); // A call's argument values
+        *pc = Self::enter(&mut self.vm, &self.program.functions[main])?;
+        loop {
+            if let Some(limit) = self.vm.limits.max_cycles
+                && self.cycle >= limit
+            {
+                return Err(VmErrorKind::CycleLimit { limit });
+            }
+            self.cycle += 1;
+            let r = |reg: &Reg| base + *reg as usize;
+            match &self.program.code[*pc] {
+                RegOp::Const { dst, value } => regs[r(dst)] = *value,
+                RegOp::Local { dst, offset } => regs[r(dst)] = self.vm.bp.wrapping_add(offset.wrapping_mul(8)),
+                RegOp::LoadLocal { dst, offset } => {
+                    regs[r(dst)] = self.vm.memory.load_i64(self.vm.bp.wrapping_add(offset.wrapping_mul(8)))?;
+                }
+                RegOp::Move { dst, src } => regs[r(dst)] = regs[r(src)],
+                RegOp::Binary { op, dst, left, right } => {
+                    regs[r(dst)] = op.apply(regs[r(left)], regs[r(right)]).ok_or(VmErrorKind::DivisionByZero)?;
+                }
+                RegOp::Load { dst, address, char } => {
+                    let address = regs[r(address)];
+                    regs[r(dst)] = if *char {
+                        self.vm.memory.load_u8(address)? as i8 as i64
+                    } else {
+                        self.vm.memory.load_i64(address)?
+                    };
+                }
+                RegOp::Store { dst, address, value, char } => {
+                    let (address, value) = (regs[r(address)], regs[r(value)]);
+                    regs[r(dst)] = if *char {
+                        self.vm.memory.store_u8(address, value as u8)?;
+                        value as u8 as i8 as i64
+                    } else {
+                        self.vm.memory.store_i64(address, value)?;
+                        value
+                    };
+                }
+                RegOp::Call { dst, function, args } => {
+                    if let Some(limit) = self.vm.limits.max_call_depth
+                        && frames.len() >= limit
+                    {
+                        return Err(VmErrorKind::CallDepthLimit { limit });
+                    }
+                    // The arguments and return address go on the stack like the stack VM's JSR
+                    for arg in args {
+                        self.vm.push(regs[r(arg)])?;
+                    }
+                    self.vm.push(*pc as i64 + 1)?;
+                    frames.push(Frame { return_pc: *pc + 1, base, dst: *dst, args: args.len(), function: current });
+                    // The callee's registers go after the caller's
+                    base += self.program.functions[current].registers;
+                    current = *function;
+                    let needed = base + self.program.functions[*function].registers;
+                    if regs.len() < needed {
+                        regs.resize(needed, 0);
+                    }
+                    *pc = Self::enter(&mut self.vm, &self.program.functions[*function])?;
+                    continue;
+                }
+                RegOp::Syscall { dst, code, args } => {
+                    values.clear();
+                    values.extend(args.iter().map(|arg| regs[r(arg)]));
+                    regs[r(dst)] = Self::syscall(&mut self.vm, *code, &values)?;
+                }
+                RegOp::Jump { target } => {
+                    *pc = *target;
+                    continue;
+                }
+                RegOp::Bz { cond, target } => {
+                    if regs[r(cond)] == 0 {
+                        *pc = *target;
+                        continue;
+                    }
+                }
+                RegOp::Bnz { cond, target } => {
+                    if regs[r(cond)] != 0 {
+                        *pc = *target;
+                        continue;
+                    }
+                }
+                RegOp::Return { src } => {
+                    // Leave like LEV, then drop the arguments like the ADJ after a call
+                    let value = regs[r(src)];
+                    let vm = &mut self.vm;
+                    vm.sp = vm.bp;
+                    vm.bp = vm.pop()?;
+                    vm.pop()?;
+                    let Some(frame) = frames.pop() else {
+                        return Ok(value); // main returned
+                    };
+                    vm.move_sp(frame.args as i64)?;
+                    base = frame.base;
+                    current = frame.function;
+                    regs[base + frame.dst as usize] = value;
+                    *pc = frame.return_pc;
+                    continue;
+                }
+                RegOp::Exit { src } => return Ok(regs[r(src)]),
+            }
+            *pc += 1;
+        }
+    }
+
+    // Run a system call or host function. Like the stack VM it takes the arguments it needs
+    // from the top of the stack, which are the last ones pushed.
+    fn syscall(vm: &mut VM, code: i64, args: &[i64]) -> Result<i64, VmErrorKind> {
+        let wanted = match Op::from_code(code) {
+            Some(Op::Open) => 2,
+            Some(Op::Read | Op::Mset | Op::Mcmp) => 3,
+            Some(Op::Prtf) => args.len().max(1),
+            Some(_) => 1,
+            None => match vm.host_functions.arity(code) {
+                Some(arity) => arity,
+                None if code >= FIRST_HOST_CODE => return Err(VmErrorKind::UnknownSyscall),
+                None => return Err(VmErrorKind::UnknownInstruction),
+            },
+        };
+        let mut args = args[args.len().saturating_sub(wanted)..].to_vec();
+        while args.len() < wanted {
+            args.insert(0, 0);
+        }
+        match Op::from_code(code) {
+            Some(op) => vm.syscall(op, &args),
+            None => Ok(vm.host_functions.call(code, &mut vm.memory, &args)?),
+        }
+    }
+}
+
+// Run a program on the register VM, through the optimised IR
+pub fn run(program: &Program, config: RunConfig) -> Result<ExitStatus, String> {
+    let code = RegProgram::from_program(program, true)?;
+    let mut vm = RegisterVM::new(program, code, config).map_err(|err| err.to_string())?;
+    let code = vm.run().map_err(|err| err.to_string())?;
+    Ok(ExitStatus { code, cycles: vm.cycle })
+}
+
+// The register code as text, one function after another
+impl fmt::Display for RegProgram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let list = |args: &[Reg]| args.iter().map(|r| format!("r{}", r)).collect::<Vec<_>>().join(", ");
+        for (i, op) in self.code.iter().enumerate() {
+            if let Some(function) = self.functions.iter().find(|function| function.start == i) {
+                writeln!(f, "{}: ; {} registers, frame {}", function.name, function.registers, function.frame)?;
+            }
+            write!(f, "{:>6}  ", i)?;
+            match op {
+                RegOp::Const { dst, value } => writeln!(f, "r{} = {}", dst, value)?,
+                RegOp::Local { dst, offset } => writeln!(f, "r{} = local {}", dst, offset)?,
+                RegOp::LoadLocal { dst, offset } => writeln!(f, "r{} = [local {}]", dst, offset)?,
+                RegOp::Move { dst, src } => writeln!(f, "r{} = r{}", dst, src)?,
+                RegOp::Binary { op, dst, left, right } => writeln!(f, "r{} = {} r{}, r{}", dst, op.name().to_lowercase(), left, right)?,
+                RegOp::Load { dst, address, char } => writeln!(f, "r{} = load{} [r{}]", dst, if *char { ".c" } else { "" }, address)?,
+                RegOp::Store { dst, address, value, char } => {
+                    writeln!(f, "r{} = store{} [r{}], r{}", dst, if *char { ".c" } else { "" }, address, value)?
+                }
+                RegOp::Call { dst, function, args } => writeln!(f, "r{} = call {}({})", dst, self.functions[*function].name, list(args))?,
+                RegOp::Syscall { dst, code, args } => match Op::from_code(*code) {
+                    Some(op) => writeln!(f, "r{} = {}({})", dst, op.name().to_lowercase(), list(args))?,
+                    None => writeln!(f, "r{} = host {}({})", dst, code, list(args))?,
+                },
+                RegOp::Jump { target } => writeln!(f, "jmp {}", target)?,
+                RegOp::Bz { cond, target } => writeln!(f, "bz r{}, {}", cond, target)?,
+                RegOp::Bnz { cond, target } => writeln!(f, "bnz r{}, {}", cond, target)?,
+                RegOp::Return { src } => writeln!(f, "ret r{}", src)?,
+                RegOp::Exit { src } => writeln!(f, "exit r{}", src)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile;
+    use crate::host::SharedBuffer;
+    use crate::vm::Limits;
+
+    // Run a program on both VMs and give back what each printed and its exit code or error
+    fn both(source: &str, limits: Limits) -> [(String, Result<i64, String>); 2] {
+        let program = compile(source).unwrap();
+        let config = |output: &SharedBuffer| RunConfig { output: Some(Box::new(output.clone())), limits, ..RunConfig::default() };
+
+        let output = SharedBuffer::new();
+        let stack = program.run(config(&output)).map(|status| status.code).map_err(|err| err.to_string());
+        let stack = (output.text(), stack);
+
+        let output = SharedBuffer::new();
+        let code = RegProgram::from_program(&program, true).unwrap();
+        let mut vm = RegisterVM::new(&program, code, config(&output)).unwrap();
+        let registers = vm.run().map_err(|err| err.to_string());
+        [stack, (output.text(), registers)]
+    }
+
+    #[test]
+    fn test_same_as_stack_vm() {
+        let sources = [
+            "int fib(int n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } int main() { return fib(15); }",
+            "int main() { int i; int s; i = 0; s = 0; while (i < 10) { if (i & 1) s = s + i; i++; } printf(\"%d\\n\", s); return s; }",
+            // A local whose address is taken, chars and pointers
+            "int set(int *p, int v) { *p = v; return v; }
+             int main() { int x; char *s; char *t; set(&x, 42); s = \"hello\"; t = malloc(8);
+                          while ((*t++ = *s++)) ; printf(\"%d %s\\n\", x, t - 6); return x; }",
+            // main's arguments, and exit() in the middle of a function
+            "int main(int argc, char **argv) { if (argc == 0) exit(3); return 1; }",
+        ];
+        for source in sources {
+            let [stack, registers] = both(source, Limits::default());
+            assert_eq!(registers, stack, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_swapping_phis() {
+        // a and b swap each time round, so their phis need a spare register
+        let source = "int main() { int a; int b; int t; int n; a = 1; b = 2; n = 5;
+                      while (n) { t = a; a = b; b = t; n--; } return a * 10 + b; }";
+        let [stack, registers] = both(source, Limits::default());
+        assert_eq!(stack.1, Ok(21));
+        assert_eq!(registers, stack);
+    }
+
+    #[test]
+    fn test_faults_and_limits() {
+        let [stack, registers] = both("int main() { int x; x = 0; return 7 / x; }", Limits::default());
+        assert_eq!(registers, stack); // The same error, at the same pc
+        assert!(stack.1.unwrap_err().starts_with("division by zero"));
+
+        let deep = "int f(int n) { return f(n + 1); } int main() { return f(0); }";
+        let [_, registers] = both(deep, Limits { max_call_depth: Some(50), ..Limits::default() });
+        assert!(registers.1.unwrap_err().starts_with("call depth limit of 50"));
+        let [_, registers] = both(deep, Limits::default());
+        assert!(registers.1.unwrap_err().starts_with("stack overflow"));
+    }
+
+    #[test]
+    fn test_fewer_cycles() {
+        let program = compile("int fib(int n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } int main() { return fib(12); }").unwrap();
+        let stack = program.run(RunConfig::default()).unwrap();
+        let registers = run(&program, RunConfig::default()).unwrap();
+        assert_eq!(registers.code, stack.code);
+        assert!(registers.cycles < stack.cycles, "{} vs {}", registers.cycles, stack.cycles);
+    }
+}
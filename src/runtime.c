@@ -0,0 +1,575 @@
+// The runtime for programs translated to C by csource.rs.
+//
+// It is the VM written out in C: the three segments live in byte arrays and every address the
+// program uses is a VM address, so pointers, the stack frames C4 builds and what printf("%p")
+// shows are the same as in the VM. Words are stored little-endian a byte at a time and the
+// arithmetic wraps like the VM's, so the program doesn't depend on the C compiler's choices.
+// A fault prints why to stderr and exits with 255, like the command line does.
+//
+// csource.rs puts DATA_BASE, HEAP_BASE, STACK_TOP, the segment sizes and the data segment
+// (data[]) in front of this file, and the program's functions after it.
+
+#include <fcntl.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+#define STACK_BASE (STACK_TOP - STACK_SIZE)
+
+static int64_t a, sp, bp; // The VM's registers. a holds the last statement's value, like in the VM.
+
+static unsigned char heap[HEAP_SIZE];
+static unsigned char stack[STACK_SIZE];
+static int64_t heap_used; // Bytes of the heap malloc has handed out, headers included
+
+// Blocks given back with free(), as offsets in the heap and sizes
+static int64_t (*free_blocks)[2];
+static int64_t free_count, free_room;
+
+// The files the program opened: files[fd - 3] is the host's descriptor, or -1 once closed
+static int *files;
+static int64_t file_count;
+
+// Stop the program the way the VM stops on a fault
+static _Noreturn void fault(const char *message) {
+    fflush(stdout);
+    fprintf(stderr, "Runtime error: %s\n", message);
+    exit(255);
+}
+
+static _Noreturn void memory_fault(int64_t address, int64_t len) {
+    char message[96];
+    snprintf(message, sizeof message, "invalid memory access at address 0x%llx (%lld bytes)",
+             (unsigned long long)address, (long long)len);
+    fault(message);
+}
+
+// Where len bytes at a VM address are, or a fault if they aren't all in one segment
+static unsigned char *locate(int64_t address, int64_t len) {
+    unsigned char *segment;
+    int64_t base, size;
+    if (address >= STACK_BASE) {
+        segment = stack, base = STACK_BASE, size = STACK_SIZE;
+    } else if (address >= HEAP_BASE) {
+        segment = heap, base = HEAP_BASE, size = heap_used;
+    } else if (address >= DATA_BASE) {
+        segment = data, base = DATA_BASE, size = DATA_SIZE;
+    } else {
+        memory_fault(address, len);
+    }
+    if (address - base > size || len > size - (address - base)) {
+        memory_fault(address, len);
+    }
+    return segment + (address - base);
+}
+
+static int64_t load_int(int64_t address) {
+    unsigned char *bytes = locate(address, 8);
+    uint64_t value = 0;
+    for (int i = 7; i >= 0; i--) {
+        value = value << 8 | bytes[i];
+    }
+    return (int64_t)value;
+}
+
+// Stores give back the value stored, which is the value of an assignment
+static int64_t store_int(int64_t address, int64_t value) {
+    unsigned char *bytes = locate(address, 8);
+    for (int i = 0; i < 8; i++) {
+        bytes[i] = (unsigned char)((uint64_t)value >> (8 * i));
+    }
+    return value;
+}
+
+// Chars are signed, like in C4
+static int64_t load_char(int64_t address) {
+    unsigned char byte = *locate(address, 1);
+    return byte < 128 ? byte : byte - 256;
+}
+
+// Like SC, this gives back the char that was stored, sign-extended
+static int64_t store_char(int64_t address, int64_t value) {
+    unsigned char byte = (unsigned char)value;
+    *locate(address, 1) = byte;
+    return byte < 128 ? byte : byte - 256;
+}
+
+static void push(int64_t value) {
+    if (sp - 8 < STACK_BASE) {
+        fault("stack overflow");
+    }
+    sp -= 8;
+    store_int(sp, value);
+}
+
+static int64_t pop(void) {
+    if (sp >= STACK_TOP) {
+        fault("stack underflow");
+    }
+    int64_t value = load_int(sp);
+    sp += 8;
+    return value;
+}
+
+// Move sp by a number of words, like ADJ (and ENT, with a negative count)
+static void move_sp(int64_t words) {
+    int64_t new_sp = (int64_t)((uint64_t)sp + (uint64_t)words * 8);
+    if (new_sp < STACK_BASE) {
+        fault("stack overflow");
+    }
+    if (new_sp > STACK_TOP) {
+        fault("stack underflow");
+    }
+    sp = new_sp;
+}
+
+// ENT: save bp, point it at this frame and make room for the locals
+static void enter(int64_t locals) {
+    push(bp);
+    bp = sp;
+    move_sp(-locals);
+}
+
+// LEV: throw the frame away and drop the return address. The function gives back a.
+static int64_t leave(void) {
+    sp = bp;
+    bp = pop();
+    pop();
+    return a;
+}
+
+// JSR: push a return address and call a function. There is no text segment here, so the
+// return address is always 0; the frame is the same size as the VM's.
+static int64_t call(int64_t (*function)(void)) {
+    push(0);
+    return function();
+}
+
+// ADJ after a call: drop its arguments and give back what it gave back
+static int64_t drop(int64_t words, int64_t value) {
+    move_sp(words);
+    return value;
+}
+
+// The operators, with 64-bit wrapping arithmetic and C's division like Op::apply
+static int64_t add(int64_t left, int64_t right) {
+    return (int64_t)((uint64_t)left + (uint64_t)right);
+}
+
+static int64_t sub(int64_t left, int64_t right) {
+    return (int64_t)((uint64_t)left - (uint64_t)right);
+}
+
+static int64_t mul(int64_t left, int64_t right) {
+    return (int64_t)((uint64_t)left * (uint64_t)right);
+}
+
+static int64_t divide(int64_t left, int64_t right) {
+    if (right == 0) {
+        fault("division by zero");
+    }
+    return right == -1 ? sub(0, left) : left / right;
+}
+
+static int64_t modulo(int64_t left, int64_t right) {
+    if (right == 0) {
+        fault("division by zero");
+    }
+    return right == -1 ? 0 : left % right;
+}
+
+// Only the low 6 bits of the count are used, and >> keeps the sign
+static int64_t shift_left(int64_t left, int64_t right) {
+    return (int64_t)((uint64_t)left << (right & 63));
+}
+
+static int64_t shift_right(int64_t left, int64_t right) {
+    int shift = (int)(right & 63);
+    return left < 0 ? (int64_t)~(~(uint64_t)left >> shift) : (int64_t)((uint64_t)left >> shift);
+}
+
+// The top argc words of the stack, the deepest first, like the VM's stack_args
+static void stack_args(int64_t *args, int64_t argc) {
+    for (int64_t i = 0; i < argc; i++) {
+        args[i] = load_int(sp + (argc - 1 - i) * 8);
+    }
+}
+
+// The string at a VM address; faults if it runs off its segment before the zero byte
+static const char *string_at(int64_t address, int64_t *len) {
+    int64_t n = 0;
+    while (*locate(address + n, 1) != 0) {
+        n++;
+    }
+    *len = n;
+    return (const char *)locate(address, n + 1);
+}
+
+// open(path, flags), with the VM's flag numbers turned into the host's
+static int64_t sys_open(void) {
+    int64_t args[2], len;
+    stack_args(args, 2);
+    const char *path = string_at(args[0], &len);
+    int flags = O_RDONLY;
+    if ((args[1] & 3) == 1) {
+        flags = O_WRONLY;
+    } else if ((args[1] & 3) == 2) {
+        flags = O_RDWR;
+    }
+    flags |= (args[1] & 0100 ? O_CREAT : 0) | (args[1] & 01000 ? O_TRUNC : 0) | (args[1] & 02000 ? O_APPEND : 0);
+    int fd = open(path, flags, 0666);
+    if (fd < 0) {
+        return -1;
+    }
+    int *grown = realloc(files, (size_t)(file_count + 1) * sizeof *files);
+    if (grown == NULL) {
+        close(fd);
+        return -1;
+    }
+    files = grown;
+    files[file_count++] = fd;
+    return file_count + 2;
+}
+
+// The host's descriptor for one of the program's, or -1
+static int host_fd(int64_t fd) {
+    if (fd == 0) {
+        return 0;
+    }
+    return fd >= 3 && fd - 3 < file_count ? files[fd - 3] : -1;
+}
+
+// read(fd, buf, n)
+static int64_t sys_read(void) {
+    int64_t args[3];
+    stack_args(args, 3);
+    int64_t n = args[2] < 0 ? 0 : args[2];
+    unsigned char *buffer = locate(args[1], n);
+    int fd = host_fd(args[0]);
+    if (fd < 0) {
+        return -1;
+    }
+    ssize_t count = read(fd, buffer, (size_t)n);
+    return count < 0 ? -1 : count;
+}
+
+// close(fd)
+static int64_t sys_close(void) {
+    int64_t fd = load_int(sp);
+    if (fd < 3 || host_fd(fd) < 0) {
+        return -1;
+    }
+    close(files[fd - 3]);
+    files[fd - 3] = -1;
+    return 0;
+}
+
+// printf's output, built up before it is written
+static char *out;
+static int64_t out_length, out_room;
+
+static void put(const char *bytes, int64_t n) {
+    if (out_length + n > out_room) {
+        out_room = (out_length + n) * 2;
+        out = realloc(out, (size_t)out_room);
+        if (out == NULL) {
+            fault("out of memory");
+        }
+    }
+    memcpy(out + out_length, bytes, (size_t)n);
+    out_length += n;
+}
+
+static void put_repeated(char c, int64_t n) {
+    for (; n > 0; n--) {
+        put(&c, 1);
+    }
+}
+
+// The flags, width and precision of one printf conversion, like the VM's FormatSpec
+struct spec {
+    int left, zero, plus, space, alternate;
+    int64_t width;
+    int64_t precision; // -1 if there is none
+};
+
+// Pad text with spaces up to the width
+static void pad(const struct spec *spec, const char *text, int64_t len) {
+    int64_t padding = spec->width > len ? spec->width - len : 0;
+    if (!spec->left) {
+        put_repeated(' ', padding);
+    }
+    put(text, len);
+    if (spec->left) {
+        put_repeated(' ', padding);
+    }
+}
+
+// Write a number made of a sign, a prefix like 0x and its digits
+static void number(const struct spec *spec, const char *sign, const char *prefix, const char *digits) {
+    int64_t n = (int64_t)strlen(digits), zeros = 0;
+    if (spec->precision >= 0) {
+        if (spec->precision == 0 && strcmp(digits, "0") == 0) {
+            n = 0; // printf("%.0d", 0) prints nothing
+        }
+        zeros = spec->precision > n ? spec->precision - n : 0;
+    }
+    int64_t len = (int64_t)(strlen(sign) + strlen(prefix)) + zeros + n;
+    int64_t padding = spec->width > len ? spec->width - len : 0;
+    if (spec->zero && !spec->left && spec->precision < 0) {
+        zeros += padding;
+        padding = 0;
+    }
+    if (!spec->left) {
+        put_repeated(' ', padding);
+    }
+    put(sign, (int64_t)strlen(sign));
+    put(prefix, (int64_t)strlen(prefix));
+    put_repeated('0', zeros);
+    put(digits, n);
+    if (spec->left) {
+        put_repeated(' ', padding);
+    }
+}
+
+// The digits of value in a base, upper or lower case
+static const char *digits_of(uint64_t value, unsigned base, int upper, char *buffer) {
+    const char *names = upper ? "0123456789ABCDEF" : "0123456789abcdef";
+    char *p = buffer + 64;
+    *--p = 0;
+    do {
+        *--p = names[value % base];
+        value /= base;
+    } while (value != 0);
+    return p;
+}
+
+// printf(format, ...), the same as VM::format_printf. argc comes from the ADJ after the call.
+static int64_t sys_printf(int64_t argc) {
+    int64_t *args = malloc((size_t)argc * sizeof *args);
+    if (args == NULL) {
+        fault("out of memory");
+    }
+    stack_args(args, argc);
+    int64_t next = 1, len;
+#define NEXT_ARG() (next < argc ? args[next++] : 0)
+    const char *format = string_at(args[0], &len);
+    char buffer[64];
+    out_length = 0;
+
+    for (int64_t i = 0; i < len;) {
+        char c = format[i++];
+        if (c != '%') {
+            put(&c, 1);
+            continue;
+        }
+
+        // Flags like %-5d or %05d
+        struct spec spec = {0, 0, 0, 0, 0, 0, -1};
+        for (;; i++) {
+            if (format[i] == '-') {
+                spec.left = 1;
+            } else if (format[i] == '0') {
+                spec.zero = 1;
+            } else if (format[i] == '+') {
+                spec.plus = 1;
+            } else if (format[i] == ' ') {
+                spec.space = 1;
+            } else if (format[i] == '#') {
+                spec.alternate = 1;
+            } else {
+                break;
+            }
+        }
+
+        // Width, either written out or taken from the arguments with *
+        if (format[i] == '*') {
+            i++;
+            int64_t width = NEXT_ARG();
+            spec.left |= width < 0;
+            spec.width = width < 0 ? (int64_t)(0 - (uint64_t)width) : width;
+        } else {
+            while (format[i] >= '0' && format[i] <= '9') {
+                spec.width = spec.width * 10 + (format[i++] - '0');
+            }
+        }
+
+        // Precision, like %.3s or %.*s
+        if (format[i] == '.') {
+            i++;
+            if (format[i] == '*') {
+                i++;
+                int64_t precision = NEXT_ARG();
+                spec.precision = precision < 0 ? -1 : precision;
+            } else {
+                spec.precision = 0;
+                while (format[i] >= '0' && format[i] <= '9') {
+                    spec.precision = spec.precision * 10 + (format[i++] - '0');
+                }
+            }
+        }
+
+        // Every value is 64 bits, so size modifiers like %ld change nothing
+        while (format[i] != 0 && strchr("lhzjtqL", format[i]) != NULL) {
+            i++;
+        }
+
+        if (i >= len) {
+            put("%", 1);
+            break;
+        }
+        char conversion = format[i++];
+        if (conversion == 'd' || conversion == 'i') {
+            int64_t value = NEXT_ARG();
+            const char *sign = value < 0 ? "-" : spec.plus ? "+" : spec.space ? " " : "";
+            uint64_t magnitude = value < 0 ? 0 - (uint64_t)value : (uint64_t)value;
+            number(&spec, sign, "", digits_of(magnitude, 10, 0, buffer));
+        } else if (conversion == 'u') {
+            number(&spec, "", "", digits_of((uint64_t)NEXT_ARG(), 10, 0, buffer));
+        } else if (conversion == 'x' || conversion == 'X' || conversion == 'p') {
+            uint64_t value = (uint64_t)NEXT_ARG();
+            const char *prefix = conversion == 'p' || (spec.alternate && value != 0) ? "0x" : "";
+            if (conversion == 'X' && prefix[0] != 0) {
+                prefix = "0X";
+            }
+            number(&spec, "", prefix, digits_of(value, 16, conversion == 'X', buffer));
+        } else if (conversion == 'o') {
+            uint64_t value = (uint64_t)NEXT_ARG();
+            number(&spec, "", spec.alternate && value != 0 ? "0" : "", digits_of(value, 8, 0, buffer));
+        } else if (conversion == 'c') {
+            char value = (char)NEXT_ARG();
+            pad(&spec, &value, 1);
+        } else if (conversion == 's') {
+            int64_t n;
+            const char *text = string_at(NEXT_ARG(), &n);
+            if (spec.precision >= 0 && spec.precision < n) {
+                n = spec.precision;
+            }
+            pad(&spec, text, n);
+        } else if (conversion == '%') {
+            put("%", 1);
+        } else {
+            // Anything else is printed the way it was written
+            put("%", 1);
+            put(&conversion, 1);
+        }
+    }
+#undef NEXT_ARG
+    free(args);
+    if (out_length == 0) {
+        return 0;
+    }
+    return fwrite(out, 1, (size_t)out_length, stdout) == (size_t)out_length ? out_length : -1;
+}
+
+// malloc(n), the same as Memory::malloc
+static int64_t sys_malloc(void) {
+    int64_t size = load_int(sp);
+    if (size < 0) {
+        return 0;
+    }
+    size = (int64_t)(((uint64_t)size + 7) & ~(uint64_t)7);
+
+    // Reuse the first freed block that is big enough
+    for (int64_t i = 0; i < free_count; i++) {
+        if (free_blocks[i][1] >= size) {
+            int64_t offset = free_blocks[i][0];
+            memmove(free_blocks[i], free_blocks[i + 1], (size_t)(free_count - i - 1) * sizeof *free_blocks);
+            free_count--;
+            return HEAP_BASE + offset;
+        }
+    }
+
+    int64_t offset = heap_used + 8;
+    if (size > HEAP_SIZE - offset) {
+        return 0;
+    }
+    heap_used = offset + size;
+    store_int(HEAP_BASE + offset - 8, size);
+    return HEAP_BASE + offset;
+}
+
+// free(p): give a heap block back so malloc can use it again. Faults if p is not a heap block.
+static int64_t sys_free(void) {
+    int64_t address = load_int(sp);
+    if (address == 0) {
+        return 0;
+    }
+    // Its header must be readable, and the stack is as readable as the heap
+    if (address < HEAP_BASE + 8 || address >= STACK_TOP || (address - 8 < STACK_BASE && address > HEAP_BASE + heap_used)) {
+        memory_fault(address, 0);
+    }
+    int64_t size = load_int(address - 8);
+    if (free_count == free_room) {
+        free_room = free_room * 2 + 16;
+        free_blocks = realloc(free_blocks, (size_t)free_room * sizeof *free_blocks);
+        if (free_blocks == NULL) {
+            fault("out of memory");
+        }
+    }
+    free_blocks[free_count][0] = address - HEAP_BASE;
+    free_blocks[free_count][1] = size;
+    free_count++;
+    return 0;
+}
+
+// memset(p, c, n)
+static int64_t sys_memset(void) {
+    int64_t args[3];
+    stack_args(args, 3);
+    int64_t n = args[2] < 0 ? 0 : args[2];
+    memset(locate(args[0], n), (unsigned char)args[1], (size_t)n);
+    return args[0];
+}
+
+// memcmp(a, b, n): the difference of the first two bytes that don't match
+static int64_t sys_memcmp(void) {
+    int64_t args[3];
+    stack_args(args, 3);
+    int64_t n = args[2] < 0 ? 0 : args[2];
+    unsigned char *left = locate(args[0], n), *right = locate(args[1], n);
+    for (int64_t i = 0; i < n; i++) {
+        if (left[i] != right[i]) {
+            return (int64_t)left[i] - right[i];
+        }
+    }
+    return 0;
+}
+
+// exit(code)
+static _Noreturn void sys_exit(void) {
+    int64_t code = load_int(sp);
+    fflush(stdout);
+    exit((int)(code & 255));
+}
+
+// Put the arguments on the stack the way VM::set_args does, ready for main
+static void start(int argc, char **argv) {
+    int64_t *pointers = malloc((size_t)(argc + 1) * sizeof *pointers);
+    if (pointers == NULL) {
+        fault("out of memory");
+    }
+    sp = STACK_TOP;
+    for (int i = 0; i < argc; i++) {
+        int64_t len = (int64_t)strlen(argv[i]) + 1;
+        if (sp - len < STACK_BASE) {
+            fault("stack overflow");
+        }
+        sp -= len;
+        memcpy(locate(sp, len), argv[i], (size_t)len);
+        pointers[i] = sp;
+    }
+    sp = (int64_t)((uint64_t)sp & ~(uint64_t)7);
+    push(0);
+    for (int i = argc - 1; i >= 0; i--) {
+        push(pointers[i]);
+    }
+    free(pointers);
+    int64_t argv_address = sp;
+    bp = sp;
+    push(argc);
+    push(argv_address);
+    push(0); // Where main returns to, the exit stub in the VM
+}
@@ -0,0 +1,771 @@
+# The runtime for programs compiled to x86-64 by native.rs.
+#
+# It does what the VM does around the program: it maps the heap and the stack at the same
+# addresses as the VM's segments, puts argc and argv on the stack the way VM::set_args does,
+# calls main, and gives C4's system calls (open, read, close, printf, malloc, free, memset,
+# memcmp and exit) on top of Linux system calls. There is no libc.
+#
+# The compiled code keeps a in %rax, sp in %rsp and bp in %rbp. A system call's arguments are
+# on the stack above the return address, the last one on top, and its result goes back in %rax.
+# Runtime functions can use every other register.
+#
+# native.rs puts the sizes of the segments in front of this file as C4_HEAP_SIZE and
+# C4_STACK_SIZE, and the address of main as c4_main.
+
+    .set HEAP_BASE, 0x10000000
+    .set STACK_TOP, 0x7fff0000
+    .set STACK_BASE, STACK_TOP - C4_STACK_SIZE
+    .set GUARD_SIZE, 0x10000                    # Faults this far below the stack are overflows
+    .set MAX_FREE_BLOCKS, 65536
+    .set OUT_SIZE, 4096
+
+    .set SYS_READ, 0
+    .set SYS_WRITE, 1
+    .set SYS_OPEN, 2
+    .set SYS_CLOSE, 3
+    .set SYS_MMAP, 9
+    .set SYS_RT_SIGACTION, 13
+    .set SYS_SIGALTSTACK, 131
+    .set SYS_EXIT_GROUP, 231
+
+    .text
+    .globl _start
+_start:
+    mov (%rsp), %r12                            # argc
+    lea 8(%rsp), %r13                           # argv
+
+    # The heap and the stack, where the VM has them
+    mov $HEAP_BASE, %rdi
+    mov $C4_HEAP_SIZE, %rsi
+    call map
+    mov $STACK_BASE, %rdi
+    mov $C4_STACK_SIZE, %rsi
+    call map
+
+    # A memory fault (or running off the stack) says so and exits like the VM does
+    mov $SYS_SIGALTSTACK, %eax
+    lea signal_stack_info(%rip), %rdi
+    xor %esi, %esi
+    syscall
+    mov $11, %edi                               # SIGSEGV
+    call catch
+    mov $7, %edi                                # SIGBUS
+    call catch
+
+    # The argument strings go at the top of the stack, then the argv array, like VM::set_args
+    mov $STACK_TOP, %rsi
+    xor %ecx, %ecx
+1:  cmp %r12, %rcx
+    jge 3f
+    mov (%r13,%rcx,8), %rdi                     # Copy the string, with its zero byte
+    call strlen
+    inc %rax
+    sub %rax, %rsi
+    cmp $STACK_BASE, %rsi
+    jb stack_overflow
+    xor %edx, %edx
+2:  mov (%rdi,%rdx), %r8b
+    mov %r8b, (%rsi,%rdx)
+    inc %rdx
+    cmp %rax, %rdx
+    jb 2b
+    mov %rsi, (%r13,%rcx,8)                     # Remember where it went in place of the host's
+    inc %rcx
+    jmp 1b
+3:  and $-8, %rsi
+    mov %rsi, %rsp
+    push $0
+    mov %r12, %rcx
+4:  dec %rcx
+    js 5f
+    push (%r13,%rcx,8)
+    jmp 4b
+5:  mov %rsp, %rax
+    mov %rsp, %rbp
+    push %r12                                   # argc
+    push %rax                                   # argv
+    call c4_main
+
+    # main returned: exit with what it gave back
+    push %rax
+    call c4_exit
+
+# Map size bytes of memory at address %rdi, or exit if we can't
+map:
+    mov $SYS_MMAP, %eax
+    mov $3, %edx                                # PROT_READ | PROT_WRITE
+    mov $0x32, %r10d                            # MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED
+    mov $-1, %r8
+    xor %r9d, %r9d
+    syscall
+    cmp %rdi, %rax
+    jne out_of_memory
+    ret
+
+# Catch signal %edi with the fault handler, on the signal stack
+catch:
+    mov $SYS_RT_SIGACTION, %eax
+    lea fault_action(%rip), %rsi
+    xor %edx, %edx
+    mov $8, %r10d
+    syscall
+    ret
+
+# The fault handler: siginfo is in %rsi, and the address that faulted is in it at 16
+fault:
+    mov 16(%rsi), %rax
+    cmp $STACK_BASE, %rax
+    jae 1f
+    cmp $STACK_BASE - GUARD_SIZE, %rax
+    jae stack_overflow
+1:  lea memory_fault_message(%rip), %rdi
+    jmp die
+restore:
+    ret
+
+stack_overflow:
+    lea stack_overflow_message(%rip), %rdi
+    jmp die
+out_of_memory:
+    lea out_of_memory_message(%rip), %rdi
+    jmp die
+division_by_zero:
+    lea division_by_zero_message(%rip), %rdi
+
+# Print the message at %rdi to stderr and exit with 255, the VM's -1
+die:
+    call strlen
+    mov %rax, %rdx
+    mov %rdi, %rsi
+    mov $2, %edi
+    mov $SYS_WRITE, %eax
+    syscall
+    mov $255, %edi
+    mov $SYS_EXIT_GROUP, %eax
+    syscall
+
+# The length of the string at %rdi in %rax. Keeps every other register.
+strlen:
+    xor %eax, %eax
+1:  cmpb $0, (%rdi,%rax)
+    je 2f
+    inc %rax
+    jmp 1b
+2:  ret
+
+# The DIV and MOD instructions: the left side is in %rcx and the right side in %rax.
+# Division rounds toward zero and wraps like the VM's.
+    .globl c4_div, c4_mod
+c4_div:
+    test %rax, %rax
+    jz division_by_zero
+    cmp $-1, %rax
+    je 1f
+    xchg %rax, %rcx
+    cqo
+    idiv %rcx
+    ret
+1:  mov %rcx, %rax                              # x / -1 is -x, even for the smallest int
+    neg %rax
+    ret
+c4_mod:
+    test %rax, %rax
+    jz division_by_zero
+    cmp $-1, %rax
+    je 1f
+    xchg %rax, %rcx
+    cqo
+    idiv %rcx
+    mov %rdx, %rax
+    ret
+1:  xor %eax, %eax
+    ret
+
+# A Linux result in %rax, with every error turned into -1
+fail_negative:
+    test %rax, %rax
+    jns 1f
+    mov $-1, %rax
+1:  ret
+
+# open(path, flags)
+    .globl c4_open
+c4_open:
+    mov 16(%rsp), %rdi
+    mov 8(%rsp), %rsi
+    mov $0666, %edx
+    mov $SYS_OPEN, %eax
+    syscall
+    jmp fail_negative
+
+# read(fd, buf, n)
+    .globl c4_read
+c4_read:
+    mov 24(%rsp), %rdi
+    mov 16(%rsp), %rsi
+    mov 8(%rsp), %rdx
+    test %rdx, %rdx
+    jns 1f
+    xor %edx, %edx
+1:  mov $SYS_READ, %eax
+    syscall
+    jmp fail_negative
+
+# close(fd)
+    .globl c4_close
+c4_close:
+    mov 8(%rsp), %rdi
+    mov $SYS_CLOSE, %eax
+    syscall
+    test %rax, %rax
+    jz 1f
+    mov $-1, %rax
+1:  ret
+
+# exit(code)
+    .globl c4_exit
+c4_exit:
+    mov 8(%rsp), %rdi
+    mov $SYS_EXIT_GROUP, %eax
+    syscall
+
+# malloc(n), the same as Memory::malloc: reuse the first freed block that is big enough,
+# or put a new one on the end of the heap behind a header holding its size
+    .globl c4_malloc
+c4_malloc:
+    mov 8(%rsp), %rax
+    test %rax, %rax
+    js 5f
+    add $7, %rax
+    and $-8, %rax
+    lea free_blocks(%rip), %rsi
+    mov free_count(%rip), %rcx
+    xor %edx, %edx
+1:  cmp %rcx, %rdx
+    jge 3f
+    mov %rdx, %rdi
+    shl $4, %rdi
+    cmp 8(%rsi,%rdi), %rax
+    jbe 2f
+    inc %rdx
+    jmp 1b
+2:  mov (%rsi,%rdi), %rax                       # Found one: take it out of the list
+    mov %rcx, %r9
+    shl $4, %r9
+    add %rsi, %r9                               # The end of the list
+    dec %rcx
+    mov %rcx, free_count(%rip)
+    lea 16(%rsi,%rdi), %r8
+7:  cmp %r9, %r8
+    jae 8f
+    mov (%r8), %r10
+    mov %r10, -16(%r8)
+    mov 8(%r8), %r10
+    mov %r10, -8(%r8)
+    add $16, %r8
+    jmp 7b
+8:  add $HEAP_BASE, %rax
+    ret
+3:  mov heap_used(%rip), %rdx
+    lea 8(%rdx), %rcx                           # The new block's offset, after its header
+    lea (%rcx,%rax), %r8
+    cmp $C4_HEAP_SIZE, %r8
+    ja 5f
+    mov %rax, HEAP_BASE(%rdx)
+    mov %r8, heap_used(%rip)
+    lea HEAP_BASE(%rcx), %rax
+    ret
+5:  xor %eax, %eax
+    ret
+
+# free(p): remember the block for malloc. Faults if p isn't in the heap.
+    .globl c4_free
+c4_free:
+    mov 8(%rsp), %rax
+    test %rax, %rax
+    jz 1f
+    cmp $HEAP_BASE + 8, %rax
+    jb 2f
+    mov heap_used(%rip), %rdx
+    add $HEAP_BASE, %rdx
+    cmp %rdx, %rax
+    ja 2f
+    mov free_count(%rip), %rcx
+    cmp $MAX_FREE_BLOCKS, %rcx
+    jae 1f                                      # No room to remember it: the block is lost
+    mov %rcx, %rdi
+    shl $4, %rdi
+    lea free_blocks(%rip), %rsi
+    mov -8(%rax), %rdx
+    sub $HEAP_BASE, %rax
+    mov %rax, (%rsi,%rdi)
+    mov %rdx, 8(%rsi,%rdi)
+    inc %rcx
+    mov %rcx, free_count(%rip)
+1:  xor %eax, %eax
+    ret
+2:  lea memory_fault_message(%rip), %rdi
+    jmp die
+
+# memset(p, c, n)
+    .globl c4_memset
+c4_memset:
+    mov 24(%rsp), %rdi
+    mov 16(%rsp), %rax
+    mov 8(%rsp), %rcx
+    test %rcx, %rcx
+    jle 1f
+    rep stosb
+1:  mov 24(%rsp), %rax
+    ret
+
+# memcmp(a, b, n): the difference of the first two bytes that don't match
+    .globl c4_memcmp
+c4_memcmp:
+    mov 24(%rsp), %rsi
+    mov 16(%rsp), %rdi
+    mov 8(%rsp), %rcx
+    xor %edx, %edx
+1:  cmp %rcx, %rdx
+    jge 2f
+    movzbq (%rsi,%rdx), %rax
+    movzbq (%rdi,%rdx), %r8
+    sub %r8, %rax
+    jnz 3f
+    inc %rdx
+    jmp 1b
+2:  xor %eax, %eax
+3:  ret
+
+# printf(format, ...), the same as VM::format_printf. %edi is the number of arguments, which
+# the compiler takes from the ADJ after the call like the VM does. The output is buffered
+# and written before printf returns.
+#
+#   %r12  the next argument's slot (they go down the stack)
+#   %r13  how many arguments are left
+#   %r14  how many bytes were printed
+#   %r15  the next byte of the format
+    .globl c4_printf
+c4_printf:
+    lea (%rsp,%rdi,8), %r12
+    mov (%r12), %r15
+    sub $8, %r12
+    lea -1(%rdi), %r13
+    xor %r14d, %r14d
+next_byte:
+    movzbl (%r15), %eax
+    test %al, %al
+    jz printf_done
+    inc %r15
+    cmp $'%', %al
+    je conversion
+    call put
+    jmp next_byte
+
+conversion:
+    movq $0, flags(%rip)                        # left, zero, plus, space and alternate
+    movq $0, width(%rip)
+    movq $-1, precision(%rip)                   # -1 is no precision
+1:  movzbl (%r15), %eax
+    lea flags(%rip), %rdx
+    cmp $'-', %al
+    je 2f
+    inc %rdx
+    cmp $'0', %al
+    je 2f
+    inc %rdx
+    cmp $'+', %al
+    je 2f
+    inc %rdx
+    cmp $' ', %al
+    je 2f
+    inc %rdx
+    cmp $'#', %al
+    jne 3f
+2:  movb $1, (%rdx)
+    inc %r15
+    jmp 1b
+
+    # Width, written out or taken from the arguments with *
+3:  cmpb $'*', (%r15)
+    jne 4f
+    inc %r15
+    call next_arg
+    test %rax, %rax
+    jns 5f
+    movb $1, flags(%rip)
+    neg %rax
+5:  mov %rax, width(%rip)
+    jmp 6f
+4:  call number
+    mov %rax, width(%rip)
+
+    # Precision, written out or taken from the arguments with *
+6:  cmpb $'.', (%r15)
+    jne 8f
+    inc %r15
+    cmpb $'*', (%r15)
+    jne 7f
+    inc %r15
+    call next_arg
+    test %rax, %rax
+    jns 9f
+    mov $-1, %rax
+9:  mov %rax, precision(%rip)
+    jmp 8f
+7:  call number
+    mov %rax, precision(%rip)
+
+    # Size modifiers change nothing, every value is 64 bits
+8:  movzbl (%r15), %eax
+    lea modifiers(%rip), %rdi
+1:  movzbl (%rdi), %edx
+    test %dl, %dl
+    jz 2f
+    inc %rdi
+    cmp %dl, %al
+    jne 1b
+    inc %r15
+    jmp 8b
+
+2:  test %al, %al
+    jnz 3f
+    mov $'%', %al                               # A % at the very end is printed as it is
+    call put
+    jmp printf_done
+3:  inc %r15
+    movb $0, sign(%rip)
+    movq $0, prefix_length(%rip)
+    cmp $'d', %al
+    je signed
+    cmp $'i', %al
+    je signed
+    cmp $'u', %al
+    je unsigned
+    cmp $'x', %al
+    je hex
+    cmp $'X', %al
+    je hex
+    cmp $'p', %al
+    je hex
+    cmp $'o', %al
+    je octal
+    cmp $'c', %al
+    je character
+    cmp $'s', %al
+    je string
+    cmp $'%', %al
+    je 4f
+    mov %eax, %ebx                              # Anything else is printed as it was written
+    mov $'%', %al
+    call put
+    mov %ebx, %eax
+4:  call put
+    jmp next_byte
+
+signed:
+    call next_arg
+    test %rax, %rax
+    jns 1f
+    movb $'-', sign(%rip)
+    neg %rax
+    jmp 3f
+1:  cmpb $0, flags+2(%rip)
+    je 2f
+    movb $'+', sign(%rip)
+    jmp 3f
+2:  cmpb $0, flags+3(%rip)
+    je 3f
+    movb $' ', sign(%rip)
+3:  mov $10, %ecx
+    jmp digits
+
+unsigned:
+    call next_arg
+    mov $10, %ecx
+    jmp digits
+
+hex:
+    mov %eax, %ebx
+    call next_arg
+    lea hex_prefix(%rip), %rdx
+    cmp $'X', %bl
+    jne 1f
+    lea upper_hex_prefix(%rip), %rdx
+1:  mov %rdx, prefix(%rip)
+    cmp $'p', %bl
+    je 2f
+    cmpb $0, flags+4(%rip)
+    je 3f
+    test %rax, %rax
+    jz 3f
+2:  movq $2, prefix_length(%rip)
+3:  mov $16, %ecx
+    cmp $'X', %bl
+    jne digits
+    mov $-16, %ecx                              # A negative base means upper case digits
+    jmp digits
+
+octal:
+    call next_arg
+    lea octal_prefix(%rip), %rdx
+    mov %rdx, prefix(%rip)
+    cmpb $0, flags+4(%rip)
+    je 1f
+    test %rax, %rax
+    jz 1f
+    movq $1, prefix_length(%rip)
+1:  mov $8, %ecx
+    jmp digits
+
+character:
+    call next_arg
+    mov %al, char_buffer(%rip)
+    lea char_buffer(%rip), %rsi
+    mov $1, %ecx
+    call pad
+    jmp next_byte
+
+string:
+    call next_arg
+    mov %rax, %rdi
+    call strlen
+    mov precision(%rip), %rcx
+    test %rcx, %rcx
+    js 1f
+    cmp %rax, %rcx
+    jae 1f
+    mov %rcx, %rax
+1:  mov %rdi, %rsi
+    mov %rax, %rcx
+    call pad
+    jmp next_byte
+
+# Write the number in %rax (unsigned) in base %ecx, with the sign and prefix set above.
+# Like FormatSpec::number.
+digits:
+    lea digit_buffer+64(%rip), %rsi
+    lea lower_digits(%rip), %r8
+    test %ecx, %ecx
+    jns 1f
+    neg %ecx
+    lea upper_digits(%rip), %r8
+1:  mov %rax, %r9                               # Keep the value to check for %.0d of 0
+2:  xor %edx, %edx
+    div %rcx
+    movzbl (%r8,%rdx), %edx
+    dec %rsi
+    mov %dl, (%rsi)
+    test %rax, %rax
+    jnz 2b
+    lea digit_buffer+64(%rip), %rbx
+    sub %rsi, %rbx                              # %rsi, %rbx: the digits
+    xor %r10d, %r10d                            # %r10: zeros to put in front of them
+    mov precision(%rip), %rax
+    test %rax, %rax
+    js 4f
+    jnz 3f
+    test %r9, %r9
+    jnz 3f
+    xor %ebx, %ebx                              # printf("%.0d", 0) prints nothing
+3:  cmp %rbx, %rax
+    jbe 4f
+    mov %rax, %r10
+    sub %rbx, %r10
+4:  xor %eax, %eax                              # %rax: the whole length
+    cmpb $0, sign(%rip)
+    je 5f
+    inc %rax
+5:  add prefix_length(%rip), %rax
+    add %r10, %rax
+    add %rbx, %rax
+    mov width(%rip), %r11
+    sub %rax, %r11                              # %r11: the padding
+    jbe 6f
+    cmpb $0, flags+1(%rip)                      # Zero padding goes between the prefix and the digits
+    je 7f
+    cmpb $0, flags(%rip)
+    jne 7f
+    cmpq $0, precision(%rip)
+    jns 7f
+    add %r11, %r10
+6:  xor %r11d, %r11d
+7:  cmpb $0, flags(%rip)
+    jne 8f
+    call spaces
+8:  movzbl sign(%rip), %eax
+    test %al, %al
+    jz 9f
+    call put
+9:  mov prefix(%rip), %rdi
+    mov prefix_length(%rip), %rcx
+    call put_bytes
+1:  test %r10, %r10
+    jz 2f
+    mov $'0', %al
+    call put
+    dec %r10
+    jmp 1b
+2:  mov %rsi, %rdi
+    mov %rbx, %rcx
+    call put_bytes
+    cmpb $0, flags(%rip)
+    je next_byte
+    call spaces
+    jmp next_byte
+
+# Write %rcx bytes from %rsi padded with spaces up to the width, like FormatSpec::pad
+pad:
+    mov %rsi, %rdi
+    mov width(%rip), %r11
+    sub %rcx, %r11
+    ja 1f
+    xor %r11d, %r11d
+1:  cmpb $0, flags(%rip)
+    jne 2f
+    call spaces
+2:  call put_bytes
+    cmpb $0, flags(%rip)
+    je 3f
+    call spaces
+3:  ret
+
+# Write %r11 spaces
+spaces:
+    test %r11, %r11
+    jz 1f
+    mov $' ', %al
+    call put
+    dec %r11
+    jmp spaces
+1:  ret
+
+# Write %rcx bytes from %rdi
+put_bytes:
+    test %rcx, %rcx
+    jz 1f
+    mov (%rdi), %al
+    call put
+    inc %rdi
+    dec %rcx
+    jmp put_bytes
+1:  ret
+
+# The next argument in %rax, or 0 if there are no more
+next_arg:
+    xor %eax, %eax
+    test %r13, %r13
+    jz 1f
+    mov (%r12), %rax
+    sub $8, %r12
+    dec %r13
+1:  ret
+
+# Read the decimal number at %r15 (0 if there are no digits) into %rax
+number:
+    xor %eax, %eax
+1:  movzbl (%r15), %edx
+    sub $'0', %edx
+    cmp $9, %edx
+    ja 2f
+    imul $10, %rax
+    add %rdx, %rax
+    inc %r15
+    jmp 1b
+2:  ret
+
+# Put the byte in %al into the output buffer, writing the buffer out when it is full.
+# Keeps every register but %rax.
+put:
+    push %rdx
+    mov out_length(%rip), %rdx
+    push %rcx
+    lea out_buffer(%rip), %rcx
+    mov %al, (%rcx,%rdx)
+    pop %rcx
+    inc %rdx
+    mov %rdx, out_length(%rip)
+    inc %r14
+    pop %rdx
+    cmpq $OUT_SIZE, out_length(%rip)
+    jb flush_done
+
+# Write out the output buffer. Keeps every register but %rax.
+flush:
+    push %rdi
+    push %rsi
+    push %rdx
+    push %rcx
+    push %r11
+    lea out_buffer(%rip), %rsi
+    mov out_length(%rip), %rdx
+1:  test %rdx, %rdx
+    jz 2f
+    mov $1, %edi
+    mov $SYS_WRITE, %eax
+    syscall
+    test %rax, %rax
+    js 3f
+    add %rax, %rsi
+    sub %rax, %rdx
+    jmp 1b
+3:  movq $-1, write_failed(%rip)
+2:  movq $0, out_length(%rip)
+    pop %r11
+    pop %rcx
+    pop %rdx
+    pop %rsi
+    pop %rdi
+flush_done:
+    ret
+
+printf_done:
+    call flush
+    mov %r14, %rax
+    cmpq $0, write_failed(%rip)                 # A failed write makes printf give back -1
+    je 1f
+    movq $0, write_failed(%rip)
+    mov $-1, %rax
+1:  ret
+
+    .section .rodata
+modifiers:                  .asciz "lhzjtqL"
+lower_digits:               .ascii "0123456789abcdef"
+upper_digits:               .ascii "0123456789ABCDEF"
+hex_prefix:                 .ascii "0x"
+upper_hex_prefix:           .ascii "0X"
+octal_prefix:               .ascii "0"
+memory_fault_message:       .asciz "Runtime error: invalid memory access\n"
+stack_overflow_message:     .asciz "Runtime error: stack overflow\n"
+division_by_zero_message:   .asciz "Runtime error: division by zero\n"
+out_of_memory_message:      .asciz "Runtime error: can't map the VM's memory\n"
+
+    .data
+    .balign 8
+fault_action:
+    .quad fault
+    .quad 0x0c000004                            # SA_ONSTACK | SA_RESTORER | SA_SIGINFO
+    .quad restore
+    .quad 0
+signal_stack_info:
+    .quad signal_stack
+    .quad 0
+    .quad 16384
+
+    .bss
+    .balign 8
+heap_used:      .skip 8             # Bytes of the heap malloc has handed out, headers included
+free_count:     .skip 8
+free_blocks:    .skip 16 * MAX_FREE_BLOCKS  # The freed blocks: offset in the heap and size
+out_length:     .skip 8
+write_failed:   .skip 8
+width:          .skip 8
+precision:      .skip 8
+prefix:         .skip 8
+prefix_length:  .skip 8
+flags:          .skip 8             # '-', '0', '+', ' ' and '#', a byte each
+sign:           .skip 1
+char_buffer:    .skip 1
+digit_buffer:   .skip 64
+out_buffer:     .skip OUT_SIZE
+signal_stack:   .skip 16384
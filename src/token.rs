@@ -3,11 +3,9 @@
 pub enum Token {
     // Literal values
     Num(i64),            // Number, like 42
+    Str(String),         // String literal, like "hello\n"
     Id(String),          // Identifier, like variable or function name
 
-    // Classes or scopes
-    Fun, Sys, Glo, Loc,  // Function, System, Global, Local
-
     // Keywords
     Char, Else, Enum, If, Int, Return, Sizeof, While,
 
@@ -21,14 +19,17 @@ pub enum Token {
     Shl, Shr,   // << and >>
     Add, Sub, Mul, Div, Mod,
     Inc, Dec,   // ++ and --
+    Not, Tilde, // ! and ~
 
     Brak,       // [
+    RBrak,      // ]
     LParen,     // (
     RParen,     // )
+    LBrace,     // {
+    RBrace,     // }
     Semicolon,  // ;
-
-    // Function-like token
-    Printf,
+    Comma,      // ,
+    Colon,      // :
 
     // Any unknown or unsupported character
     Unknown(char),
@@ -38,33 +39,66 @@ pub enum Token {
 }
 
 // This enum represents the data types in our language
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Type {
-    Char,   // character type
-    Int,    // integer type
-    Ptr,    // pointer type
+    Char,           // character type
+    Int,            // integer type
+    Ptr(Box<Type>), // pointer type, like char * or int **
+}
+
+impl Type {
+    // The type of a pointer to this type (C4's "ty + PTR")
+    pub fn ptr_to(self) -> Type {
+        Type::Ptr(Box::new(self))
+    }
+
+    // The type this pointer points to (C4's "ty - PTR"), or None if it isn't a pointer
+    pub fn deref(&self) -> Option<Type> {
+        match self {
+            Type::Ptr(inner) => Some((**inner).clone()),
+            _ => None,
+        }
+    }
+
+    // Is this a pointer type?
+    pub fn is_ptr(&self) -> bool {
+        matches!(self, Type::Ptr(_))
+    }
+
+    // How many bytes a value of this type takes: chars are 1, ints and pointers are 8
+    pub fn size(&self) -> i64 {
+        match self {
+            Type::Char => 1,
+            _ => 8,
+        }
+    }
+
+    // How far pointer arithmetic moves for each step: the size of the pointed-to type.
+    // C4 only scales pointers to ints and pointers (its "ty > PTR"), so char * moves by 1.
+    pub fn scale(&self) -> i64 {
+        match self {
+            Type::Ptr(inner) => inner.size(),
+            _ => 1,
+        }
+    }
 }
 
 // This enum tells us the role or kind of a symbol (like a variable or function)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Class {
-    Num,    // constant number
+    Num,    // constant number (an enum value)
     Fun,    // function
     Sys,    // system function
     Glo,    // global variable
     Loc,    // local variable
-    None,   // not set
 }
 
 // Helper function to return the name of a token as a string
 pub fn token_name(token: &Token) -> &'static str {
     match token {
         Token::Num(_) => "Num",               // Number
+        Token::Str(_) => "Str",               // String literal
         Token::Id(_) => "Id",                 // Identifier
-        Token::Fun => "Fun",
-        Token::Sys => "Sys",
-        Token::Glo => "Glo",
-        Token::Loc => "Loc",
         Token::Char => "Char",
         Token::Else => "Else",
         Token::Enum => "Enum",
@@ -97,12 +131,18 @@ pub fn token_name(token: &Token) -> &'static str {
         Token::Mod => "%",
         Token::Inc => "++",
         Token::Dec => "--",
+        Token::Not => "!",
+        Token::Tilde => "~",
         Token::Brak => "[",
+        Token::RBrak => "]",
         Token::LParen => "(",
         Token::RParen => ")",
+        Token::LBrace => "{",
+        Token::RBrace => "}",
         Token::Semicolon => ";",
+        Token::Comma => ",",
+        Token::Colon => ":",
 
-        Token::Printf => "printf",
         Token::Unknown(_) => "Unknown",
         Token::Eof => "EOF",
     }
@@ -0,0 +1,67 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+
+// Where --trace sends its log, and which instructions it logs.
+// Each line shows the VM before the instruction runs:
+//   cycle, pc, instruction and operand, a, sp, bp and the words on top of the stack.
+pub struct Trace {
+    out: Box<dyn Write>,
+    pub only: Option<Range<usize>>, // Only log instructions whose pc is in here (a function or a pc range)
+    pub stack_slots: usize,         // How many stack words to show, starting at sp
+}
+
+impl Trace {
+    // Log every instruction to out
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Trace { out, only: None, stack_slots: 4 }
+    }
+
+    // Log to standard error, so the program's own output on stdout stays clean
+    pub fn to_stderr() -> Self {
+        Trace::new(Box::new(io::stderr()))
+    }
+
+    // Log to a file, replacing it if it already exists
+    pub fn to_file(path: &str) -> io::Result<Self> {
+        Ok(Trace::new(Box::new(BufWriter::new(File::create(path)?))))
+    }
+
+    // Should the instruction at pc be logged?
+    pub fn wants(&self, pc: usize) -> bool {
+        self.only.as_ref().is_none_or(|range| range.contains(&pc))
+    }
+
+    // Add one line to the log
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.out, "{}", line)
+    }
+
+    // Make sure everything logged so far has been written out
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// Turn a --trace-only argument into a pc range: either "start..end" or a function name,
+// which is looked up with function (giving back where that function's code is).
+pub fn parse_range(arg: &str, function: impl Fn(&str) -> Option<Range<usize>>) -> Option<Range<usize>> {
+    match arg.split_once("..") {
+        Some((start, end)) => Some(start.trim().parse().ok()?..end.trim().parse().ok()?),
+        None => function(arg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        let function = |name: &str| if name == "main" { Some(10..20) } else { None };
+        assert_eq!(parse_range("3..8", function), Some(3..8));
+        assert_eq!(parse_range("main", function), Some(10..20));
+        assert_eq!(parse_range("nothing", function), None);
+        assert_eq!(parse_range("3..x", function), None);
+    }
+}
@@ -1,78 +1,1132 @@
-// This is a simple virtual machine.
-// It runs instructions and uses a stack to do math.
-pub struct VM {
-    pub instructions: Vec<String>, // List of instructions (like ADD, IMM 5, etc.)
-    pub stack: Vec<i64>,           // Stack to store numbers while doing calculations
-}
-
-impl VM {
-    // Makes a new VM with some instructions and an empty stack
-    pub fn new(instructions: Vec<String>) -> Self {
-        VM {
-            instructions,
-            stack: Vec::new(),
-        }
-    }
-
-    // This runs the instructions one by one
-    pub fn run(&mut self) {
-        for inst in &self.instructions {
-            match inst.as_str() {
-                // Add the top two numbers from the stack
-                "ADD" => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a + b);
-                }
-
-                // Subtract the top number from the second top number
-                "SUB" => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a - b);
-                }
-
-                // Multiply the top two numbers
-                "MUL" => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a * b);
-                }
-
-                // Divide the second top number by the top number
-                "DIV" => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a / b);
-                }
-
-                // Get the remainder after division
-                "MOD" => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a % b);
-                }
-
-                // Print the top number on the stack
-                "PRTF" => {
-                    let value = self.stack.pop().unwrap();
-                    println!("{}", value);
-                }
-
-                // Push a number to the stack (like IMM 5 pushes 5)
-                s if s.starts_with("IMM") => {
-                    let value: i64 = s[4..].trim().parse().unwrap();
-                    self.stack.push(value);
-                }
-
-                // "LEV" means "leave" — stop running instructions
-                "LEV" => {
-                    break;
-                }
-
-                // If the instruction is unknown, show an error
-                _ => panic!("Unknown instruction: {}", inst),
-            }
-        }
-    }
-}
+use crate::host::{FileSystem, HostFileSystem, HostFunctions, FIRST_HOST_CODE};
+use crate::memory::{Memory, MemoryConfig, MemoryError, MemoryFault, STACK_TOP};
+use crate::opcode::Op;
+use crate::trace::Trace;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+// The system calls C4 programs can use: the C name and the VM instruction for it
+pub const SYSCALLS: [(&str, Op); 9] = [
+    ("open", Op::Open),
+    ("read", Op::Read),
+    ("close", Op::Clos),
+    ("printf", Op::Prtf),
+    ("malloc", Op::Malc),
+    ("free", Op::Free),
+    ("memset", Op::Mset),
+    ("memcmp", Op::Mcmp),
+    ("exit", Op::Exit),
+];
+
+// What went wrong when the VM had to stop a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmErrorKind {
+    StackUnderflow,                                // popped more than was pushed
+    StackOverflow,                                 // pushed past the bottom of the stack segment
+    DivisionByZero,                                // DIV or MOD with 0 in the accumulator
+    InvalidMemoryAccess { address: i64, len: usize }, // touched memory outside every segment
+    BadJump { target: i64 },                       // jumped, called or returned outside the text
+    UnknownInstruction,                            // a text word that isn't an instruction
+    UnknownSyscall,                                // an instruction number past the last one (reserved for host functions)
+    CycleLimit { limit: u64 },                     // ran more instructions than Limits allows
+    CallDepthLimit { limit: usize },               // nested more calls than Limits allows
+    HeapLimit { limit: usize },                    // malloc'd more bytes than Limits allows
+    OpenFileLimit { limit: usize },                // opened more files at once than Limits allows
+    Memory(MemoryError),                           // the memory couldn't be made, so the program never started
+    HostFunctionMissing { code: i64 },             // an import isn't registered, so the program never started
+}
+
+impl From<MemoryFault> for VmErrorKind {
+    fn from(fault: MemoryFault) -> Self {
+        VmErrorKind::InvalidMemoryAccess { address: fault.address, len: fault.len }
+    }
+}
+
+// A runtime fault, with the program counter and instruction where it happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmError {
+    pub kind: VmErrorKind,
+    pub pc: usize,         // Where the faulting instruction starts in the text
+    pub code: Option<i64>, // The faulting instruction's word (None if pc ran off the end of the text)
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The program never ran, so no instruction is to blame
+        match self.kind {
+            VmErrorKind::Memory(error) => return write!(f, "{}", error),
+            VmErrorKind::HostFunctionMissing { code } => {
+                return write!(f, "host function {} that the program was compiled with isn't registered", code)
+            }
+            _ => {}
+        }
+        match self.kind {
+            VmErrorKind::StackUnderflow => write!(f, "stack underflow")?,
+            VmErrorKind::StackOverflow => write!(f, "stack overflow")?,
+            VmErrorKind::DivisionByZero => write!(f, "division by zero")?,
+            VmErrorKind::InvalidMemoryAccess { address, len } => {
+                write!(f, "{}", MemoryFault { address, len })?
+            }
+            VmErrorKind::BadJump { target } => write!(f, "bad jump target {}", target)?,
+            VmErrorKind::UnknownInstruction => write!(f, "unknown instruction")?,
+            VmErrorKind::UnknownSyscall => write!(f, "unknown system call")?,
+            VmErrorKind::CycleLimit { limit } => write!(f, "cycle limit of {} instructions reached", limit)?,
+            VmErrorKind::CallDepthLimit { limit } => write!(f, "call depth limit of {} reached", limit)?,
+            VmErrorKind::HeapLimit { limit } => write!(f, "heap limit of {} bytes reached", limit)?,
+            VmErrorKind::OpenFileLimit { limit } => write!(f, "open file limit of {} reached", limit)?,
+            VmErrorKind::Memory(_) | VmErrorKind::HostFunctionMissing { .. } => unreachable!("written above"),
+        }
+        write!(f, " at pc {}", self.pc)?;
+        match self.code {
+            Some(code) => match Op::from_code(code) {
+                Some(op) => write!(f, " ({})", op),
+                None => write!(f, " (instruction {})", code),
+            },
+            None => write!(f, " (past the end of the text)"),
+        }
+    }
+}
+
+// Limits for running programs we don't trust, so they can't run forever or use up the machine.
+// None means no limit. Going over a limit stops the program with its own VmErrorKind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limits {
+    pub max_cycles: Option<u64>,       // How many instructions may run
+    pub max_call_depth: Option<usize>, // How many calls may be nested inside main
+    pub max_heap_bytes: Option<usize>, // How much of the heap malloc may use (block headers included)
+    pub max_open_files: Option<usize>, // How many files may be open at once
+}
+
+// Where a program is, as seen from outside the VM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmState {
+    Running,         // It can run another instruction
+    Halted(i64),     // It exited with this value
+    Faulted(VmError), // It was stopped by a runtime fault
+    WaitingForInput, // It is reading from an empty input queue; call feed_input or close_input to go on
+}
+
+// This is the C4 virtual machine. Like C4 it has four registers:
+// the accumulator a holds the value being computed, sp and bp point into the stack
+// (bp at the current function's frame) and pc says which instruction runs next.
+pub struct VM {
+    pub text: Vec<i64>,        // The text segment: instruction words and their operands
+    pub memory: Memory,        // Data, heap and stack segments
+    pub a: i64,                // Accumulator
+    pub sp: i64,               // Stack pointer: address of the top value on the stack
+    pub bp: i64,               // Base pointer: where the current function's frame is
+    pub pc: usize,             // Program counter: index of the next word in text
+    pub cycle: u64,            // How many instructions have run so far
+    pub debug: bool,           // Print each instruction as it runs, like C4's -d
+    pub trace: Option<Trace>,  // Log each instruction with the registers and top of the stack (--trace)
+    pub limits: Limits,        // How far the program may go before it is stopped
+    pub(crate) depth: usize,   // How many calls deep the program is (main is 0)
+    exit_stub: usize,          // Where main returns to: a PSH and EXIT at the end of the text
+    state: VmState,            // What the last step left the program doing
+    input_queue: Option<VecDeque<u8>>, // Bytes given with feed_input, read by read(0, ...) in place of input
+    input_closed: bool,        // No more bytes will be fed, so an empty queue means end of file
+    pub output: Box<dyn Write>, // Where printf (and -d) writes, stdout unless changed
+    pub input: Box<dyn Read>,   // Where read(0, ...) reads from, stdin unless changed
+    pub file_system: Box<dyn FileSystem>, // Where open() finds files, the host's own unless changed
+    pub host_functions: HostFunctions, // Native functions the embedder lets programs call
+    files: HashMap<i64, Box<dyn Read>>, // Files the program opened, by file descriptor
+    next_fd: i64,              // The file descriptor the next open() will get
+}
+
+impl VM {
+    // Makes a new VM that will start running at entry (the address of main)
+    pub fn new(text: Vec<i64>, data: Vec<u8>, entry: usize) -> Result<Self, VmError> {
+        Self::with_config(text, data, entry, MemoryConfig::default())
+    }
+
+    // Makes a new VM whose memory segments have the given sizes.
+    // Fails if the segments don't fit in the address space or the data doesn't fit in its segment.
+    pub fn with_config(mut text: Vec<i64>, data: Vec<u8>, entry: usize, config: MemoryConfig) -> Result<Self, VmError> {
+        // When main returns it lands here, which calls exit() with main's return value.
+        // C4 keeps these two instructions on its stack, we keep them at the end of the text.
+        let exit_stub = text.len();
+        text.push(Op::Psh.code());
+        text.push(Op::Exit.code());
+        let memory = Memory::new(config, &data)
+            .map_err(|error| VmError { kind: VmErrorKind::Memory(error), pc: entry, code: text.get(entry).copied() })?;
+
+        let mut vm = VM {
+            text,
+            memory,
+            a: 0,
+            sp: STACK_TOP,
+            bp: STACK_TOP,
+            pc: entry,
+            cycle: 0,
+            debug: false,
+            trace: None,
+            limits: Limits::default(),
+            depth: 0,
+            exit_stub,
+            state: VmState::Running,
+            input_queue: None,
+            input_closed: false,
+            output: Box::new(io::stdout()),
+            input: Box::new(io::stdin()),
+            file_system: Box::new(HostFileSystem),
+            host_functions: HostFunctions::new(),
+            files: HashMap::new(),
+            next_fd: 3, // 0, 1 and 2 are stdin, stdout and stderr
+        };
+        vm.set_args(&[])?;
+        Ok(vm)
+    }
+
+    // Give main(argc, argv) its command-line arguments (argv[0] is the source file, like in C4).
+    // The strings and the argv array are copied to the top of the stack segment,
+    // below them go argc, argv and the return address, the way C4 sets up its stack.
+    // Call this before running; it starts the stack over.
+    pub fn set_args(&mut self, args: &[String]) -> Result<(), VmError> {
+        let overflow = VmError { kind: VmErrorKind::StackOverflow, pc: self.pc, code: self.text.get(self.pc).copied() };
+        self.sp = STACK_TOP;
+
+        // The strings themselves, each ending with a zero byte
+        let mut pointers = Vec::new();
+        for arg in args {
+            let len = arg.len() as i64 + 1;
+            if self.sp - len < self.memory.stack_base() {
+                return Err(overflow);
+            }
+            self.sp -= len;
+            let bytes = self.memory.bytes_mut(self.sp, len as usize).unwrap();
+            bytes[..arg.len()].copy_from_slice(arg.as_bytes());
+            bytes[arg.len()] = 0;
+            pointers.push(self.sp);
+        }
+        self.sp &= !7; // line the stack back up on 8 bytes
+
+        // The argv array, ending with a NULL pointer
+        self.push(0).map_err(|_| overflow)?;
+        for &pointer in pointers.iter().rev() {
+            self.push(pointer).map_err(|_| overflow)?;
+        }
+        let argv = self.sp;
+
+        self.bp = self.sp;
+        for value in [args.len() as i64, argv, self.exit_stub as i64] {
+            self.push(value).map_err(|_| overflow)?;
+        }
+        Ok(())
+    }
+
+    // Put a value on top of the stack
+    pub(crate) fn push(&mut self, value: i64) -> Result<(), VmErrorKind> {
+        if self.sp - 8 < self.memory.stack_base() {
+            return Err(VmErrorKind::StackOverflow);
+        }
+        self.sp -= 8;
+        self.memory.store_i64(self.sp, value)?;
+        Ok(())
+    }
+
+    // Take the top value off the stack
+    pub(crate) fn pop(&mut self) -> Result<i64, VmErrorKind> {
+        if self.sp >= STACK_TOP {
+            return Err(VmErrorKind::StackUnderflow);
+        }
+        let value = self.memory.load_i64(self.sp)?;
+        self.sp += 8;
+        Ok(value)
+    }
+
+    // Move sp by a number of words (negative makes room, positive drops values)
+    pub(crate) fn move_sp(&mut self, words: i64) -> Result<(), VmErrorKind> {
+        let sp = self.sp.wrapping_add(words.wrapping_mul(8));
+        if sp < self.memory.stack_base() {
+            return Err(VmErrorKind::StackOverflow);
+        }
+        if sp > STACK_TOP {
+            return Err(VmErrorKind::StackUnderflow);
+        }
+        self.sp = sp;
+        Ok(())
+    }
+
+    // Log the instruction that was just fetched, with the registers as they are before it runs
+    fn write_trace(&mut self, name: &str, operand: Option<i64>) {
+        let pc = self.pc - 1;
+        let mut line = format!("{:>8} pc={:<6} {:<4}", self.cycle, pc, name);
+        match operand {
+            Some(operand) => line += &format!(" {:<12}", operand),
+            None => line += &format!(" {:<12}", ""),
+        }
+        line += &format!(" a={} sp={:#x} bp={:#x} stack=[", self.a, self.sp, self.bp);
+
+        let slots = self.trace.as_ref().map_or(0, |trace| trace.stack_slots);
+        let words: Vec<String> = (0..slots as i64)
+            .map(|i| self.sp + i * 8)
+            .take_while(|&address| address < STACK_TOP)
+            .filter_map(|address| self.memory.load_i64(address).ok())
+            .map(|word| word.to_string())
+            .collect();
+        line += &words.join(", ");
+        line.push(']');
+
+        let trace = self.trace.as_mut().unwrap();
+        if let Err(err) = trace.write_line(&line) {
+            eprintln!("trace stopped: {}", err);
+            self.trace = None;
+        }
+    }
+
+    // Read the word at pc and move past it
+    fn fetch(&mut self) -> Result<i64, VmErrorKind> {
+        let word = *self.text.get(self.pc).ok_or(VmErrorKind::BadJump { target: self.pc as i64 })?;
+        self.pc += 1;
+        Ok(word)
+    }
+
+    // Continue at target, which has to be inside the text
+    fn jump(&mut self, target: i64) -> Result<(), VmErrorKind> {
+        match usize::try_from(target) {
+            Ok(pc) if pc < self.text.len() => {
+                self.pc = pc;
+                Ok(())
+            }
+            _ => Err(VmErrorKind::BadJump { target }),
+        }
+    }
+
+    // This runs the program until it exits and gives back its exit value.
+    // A fault stops the VM before it can touch anything it shouldn't, and is given back as an error.
+    // Without -d or --trace it uses the fast loop in fast.rs, which gives the same results.
+    pub fn run(&mut self) -> Result<i64, VmError> {
+        loop {
+            let state = if self.debug || self.trace.is_some() { self.run_until(|_| false) } else { self.run_fast() };
+            match state {
+                VmState::Halted(code) => return Ok(code),
+                VmState::Faulted(err) => return Err(err),
+                // Nothing can feed more input while run is going, so the program sees end of file
+                _ => self.close_input(),
+            }
+        }
+    }
+
+    // Run a single instruction and say what state that left the program in.
+    // Once the program has halted or faulted this does nothing and gives back the same state.
+    pub fn step(&mut self) -> VmState {
+        if matches!(self.state, VmState::Halted(_) | VmState::Faulted(_)) {
+            return self.state;
+        }
+        let pc = self.pc;
+        self.state = match self.execute() {
+            Ok(state) => state,
+            Err(kind) => VmState::Faulted(VmError { kind, pc, code: self.text.get(pc).copied() }),
+        };
+        if self.state != VmState::Running {
+            self.output.flush().ok();
+            if let Some(trace) = &mut self.trace {
+                trace.flush().ok();
+            }
+        }
+        self.state
+    }
+
+    // Run at most this many instructions, stopping early if the program stops running
+    pub fn run_for(&mut self, cycles: u64) -> VmState {
+        let mut state = self.state;
+        for _ in 0..cycles {
+            state = self.step();
+            if state != VmState::Running {
+                break;
+            }
+        }
+        state
+    }
+
+    // Run until stop says so (it is asked after every instruction) or the program stops running
+    pub fn run_until(&mut self, mut stop: impl FnMut(&VM) -> bool) -> VmState {
+        loop {
+            let state = self.step();
+            if state != VmState::Running || stop(self) {
+                return state;
+            }
+        }
+    }
+
+    // What the last step left the program doing
+    pub fn state(&self) -> VmState {
+        self.state
+    }
+
+    // Give the program bytes to read from file descriptor 0. From the first call on,
+    // read(0, ...) takes its bytes from here instead of input, and a read
+    // with nothing left waits (VmState::WaitingForInput) instead of blocking.
+    pub fn feed_input(&mut self, bytes: &[u8]) {
+        self.input_queue.get_or_insert_with(VecDeque::new).extend(bytes);
+    }
+
+    // Say no more input is coming: once the queued bytes are used up, read(0, ...) gives 0
+    pub fn close_input(&mut self) {
+        self.input_queue.get_or_insert_with(VecDeque::new);
+        self.input_closed = true;
+    }
+
+    // Would a read from file descriptor 0 have to wait for more input?
+    fn input_pending(&self) -> bool {
+        self.input_queue.as_ref().is_some_and(|queue| queue.is_empty()) && !self.input_closed
+    }
+
+    // Run the instruction at pc. Gives back whether the program can go on.
+    fn execute(&mut self) -> Result<VmState, VmErrorKind> {
+        let code = self.fetch()?;
+        let op = match Op::from_code(code) {
+            Some(op) => Some(op),
+            None if self.host_functions.name(code).is_some() => None, // a host function
+            None if code >= FIRST_HOST_CODE => return Err(VmErrorKind::UnknownSyscall),
+            None => return Err(VmErrorKind::UnknownInstruction),
+        };
+
+        // Reading from an empty input queue doesn't run the READ yet, it waits to be fed
+        if op == Some(Op::Read) && self.syscall_args(Op::Read)?[0] == 0 && self.input_pending() {
+            self.pc -= 1;
+            return Ok(VmState::WaitingForInput);
+        }
+        if let Some(limit) = self.limits.max_cycles
+            && self.cycle >= limit
+        {
+            self.pc -= 1;
+            return Err(VmErrorKind::CycleLimit { limit });
+        }
+        self.cycle += 1;
+
+        // The instruction's name and operand, for -d and --trace
+        let name = match op {
+            Some(op) => op.name(),
+            None => self.host_functions.name(code).unwrap(),
+        };
+        let operand = match op {
+            Some(op) if op.has_operand() => self.text.get(self.pc).copied(),
+            _ => None,
+        };
+        if self.debug {
+            // The same format as C4: "cycle> NAME operand"
+            let line = match operand {
+                Some(operand) => format!("{}> {:<4} {}", self.cycle, name, operand),
+                None => format!("{}> {:<4}", self.cycle, name),
+            };
+            writeln!(self.output, "{}", line).ok();
+        }
+        if self.trace.as_ref().is_some_and(|trace| trace.wants(self.pc - 1)) {
+            let name = name.to_string();
+            self.write_trace(&name, operand);
+        }
+
+        // Host functions take their arguments from the stack like system calls do
+        let Some(op) = op else {
+            let arity = self.host_functions.arity(code).unwrap();
+            let args = self.stack_args(arity)?;
+            self.a = self.host_functions.call(code, &mut self.memory, &args)?;
+            return Ok(VmState::Running);
+        };
+
+        match op {
+            Op::Lea => self.a = self.bp.wrapping_add(self.fetch()?.wrapping_mul(8)), // address of a local or argument
+            Op::Imm => self.a = self.fetch()?, // load a number or global address
+            Op::Jmp => {
+                let target = self.fetch()?;
+                self.jump(target)?;
+            }
+            Op::Jsr => {
+                // Call: push the return address and jump to the function
+                let target = self.fetch()?;
+                if let Some(limit) = self.limits.max_call_depth
+                    && self.depth >= limit
+                {
+                    return Err(VmErrorKind::CallDepthLimit { limit });
+                }
+                self.push(self.pc as i64)?;
+                self.jump(target)?;
+                self.depth += 1;
+            }
+            Op::Bz => {
+                let target = self.fetch()?;
+                if self.a == 0 {
+                    self.jump(target)?;
+                }
+            }
+            Op::Bnz => {
+                let target = self.fetch()?;
+                if self.a != 0 {
+                    self.jump(target)?;
+                }
+            }
+            Op::Ent => {
+                // Enter a function: save bp, point it at this frame, make room for the locals
+                let locals = self.fetch()?;
+                self.push(self.bp)?;
+                self.bp = self.sp;
+                self.move_sp(-locals)?;
+            }
+            Op::Adj => {
+                let words = self.fetch()?;
+                self.move_sp(words)?; // drop the arguments after a call
+            }
+            Op::Lev => {
+                // Leave a function: throw away its frame and go back to the caller
+                self.sp = self.bp;
+                self.bp = self.pop()?;
+                let target = self.pop()?;
+                self.jump(target)?;
+                self.depth = self.depth.saturating_sub(1);
+            }
+            Op::Li => self.a = self.memory.load_i64(self.a)?,
+            Op::Lc => self.a = self.memory.load_u8(self.a)? as i8 as i64, // chars are signed, like in C4
+            Op::Si => {
+                let address = self.pop()?;
+                self.memory.store_i64(address, self.a)?;
+            }
+            Op::Sc => {
+                // What's left in a is the char that was stored
+                let address = self.pop()?;
+                self.memory.store_u8(address, self.a as u8)?;
+                self.a = self.a as u8 as i8 as i64;
+            }
+            Op::Psh => self.push(self.a)?,
+
+            // The binary operators: the left side was pushed, the right side is in a
+            op if op.is_binary() => {
+                let left = self.pop()?;
+                self.a = op.apply(left, self.a).ok_or(VmErrorKind::DivisionByZero)?;
+            }
+
+            // The peephole optimiser's instructions
+            Op::Addi => self.a = self.a.wrapping_add(self.fetch()?),
+            Op::Lli => {
+                let address = self.bp.wrapping_add(self.fetch()?.wrapping_mul(8));
+                self.a = self.memory.load_i64(address)?;
+            }
+
+            // System calls read their arguments from the stack, the caller drops them with ADJ
+            _ => {
+                let args = self.syscall_args(op)?;
+                if op == Op::Exit {
+                    return Ok(VmState::Halted(args[0]));
+                }
+                self.a = self.syscall(op, &args)?;
+            }
+        }
+        Ok(VmState::Running)
+    }
+
+    // Collect a system call's arguments from the stack, first argument first
+    fn syscall_args(&self, op: Op) -> Result<Vec<i64>, VmErrorKind> {
+        let argc = match op {
+            Op::Open => 2,
+            Op::Read | Op::Mset | Op::Mcmp => 3,
+            // printf can have any number of arguments; like C4, look at the ADJ after the call
+            Op::Prtf => match self.text.get(self.pc) {
+                Some(&code) if code == Op::Adj.code() => self.text.get(self.pc + 1).map_or(1, |&n| n.max(1)),
+                _ => 1,
+            },
+            _ => 1,
+        };
+        self.stack_args(argc as usize)
+    }
+
+    // The top argc words of the stack, the deepest first (so in the order they were pushed)
+    fn stack_args(&self, argc: usize) -> Result<Vec<i64>, VmErrorKind> {
+        (0..argc as i64)
+            .map(|i| Ok(self.memory.load_i64(self.sp + (argc as i64 - 1 - i) * 8)?))
+            .collect()
+    }
+
+    // Run a system call (anything but exit) and give back its result
+    pub(crate) fn syscall(&mut self, op: Op, args: &[i64]) -> Result<i64, VmErrorKind> {
+        Ok(match op {
+            // open(path, flags): open a host file and give back its file descriptor (or -1)
+            Op::Open => {
+                if let Some(limit) = self.limits.max_open_files
+                    && self.files.len() >= limit
+                {
+                    return Err(VmErrorKind::OpenFileLimit { limit });
+                }
+                self.open(args[0], args[1])?
+            }
+
+            // read(fd, buf, n): read up to n bytes into VM memory, give back how many were read
+            Op::Read => self.read(args[0], args[1], args[2])?,
+
+            // close(fd): forget an open file
+            Op::Clos => {
+                if self.files.remove(&args[0]).is_some() { 0 } else { -1 }
+            }
+
+            // printf(format, ...): give back how many bytes were printed
+            Op::Prtf => {
+                let text = self.format_printf(args[0], &args[1..])?;
+                match self.output.write_all(&text) {
+                    Ok(()) => text.len() as i64,
+                    Err(_) => -1,
+                }
+            }
+
+            // malloc(n): the address of a new heap block (or 0 if there is no room)
+            Op::Malc => {
+                let address = self.memory.malloc(args[0]);
+                if let Some(limit) = self.limits.max_heap_bytes
+                    && self.memory.heap_used() > limit
+                {
+                    return Err(VmErrorKind::HeapLimit { limit });
+                }
+                address
+            }
+
+            // free(p): give a heap block back so malloc can use it again
+            Op::Free => {
+                self.memory.free(args[0])?;
+                0
+            }
+
+            // memset(p, c, n): fill n bytes with c and give back p
+            Op::Mset => {
+                self.memory.bytes_mut(args[0], length(args[2]))?.fill(args[1] as u8);
+                args[0]
+            }
+
+            // memcmp(a, b, n): compare n bytes and give back the difference of the first mismatch
+            Op::Mcmp => {
+                let n = length(args[2]);
+                self.memory
+                    .bytes(args[0], n)?
+                    .iter()
+                    .zip(self.memory.bytes(args[1], n)?)
+                    .find(|(x, y)| x != y)
+                    .map_or(0, |(x, y)| *x as i64 - *y as i64)
+            }
+
+            _ => unreachable!("{} is not a system call", op),
+        })
+    }
+
+    // Open a file for the program through the file system
+    fn open(&mut self, path: i64, flags: i64) -> Result<i64, MemoryFault> {
+        let path = String::from_utf8_lossy(&self.memory.read_cstr(path)?).into_owned();
+        Ok(match self.file_system.open(&path, flags) {
+            Ok(file) => {
+                let fd = self.next_fd;
+                self.next_fd += 1;
+                self.files.insert(fd, file);
+                fd
+            }
+            Err(_) => -1,
+        })
+    }
+
+    // Read from stdin (fd 0) or an open file into VM memory
+    fn read(&mut self, fd: i64, buf: i64, n: i64) -> Result<i64, MemoryFault> {
+        let target = self.memory.bytes_mut(buf, length(n))?;
+        let result = if fd == 0 {
+            match &mut self.input_queue {
+                Some(queue) => {
+                    let count = target.len().min(queue.len());
+                    for (byte, queued) in target.iter_mut().zip(queue.drain(..count)) {
+                        *byte = queued;
+                    }
+                    Ok(count)
+                }
+                None => self.input.read(target),
+            }
+        } else {
+            match self.files.get_mut(&fd) {
+                Some(file) => file.read(target),
+                None => return Ok(-1),
+            }
+        };
+        Ok(result.map_or(-1, |count| count as i64))
+    }
+
+    // Build the text printf would print, reading the format string and any %s strings from VM memory
+    fn format_printf(&self, format: i64, args: &[i64]) -> Result<Vec<u8>, MemoryFault> {
+        let format = self.memory.read_cstr(format)?;
+        let mut args = args.iter().copied();
+        let mut out = Vec::new();
+        let mut i = 0;
+
+        while i < format.len() {
+            let c = format[i];
+            i += 1;
+            if c != b'%' {
+                out.push(c);
+                continue;
+            }
+
+            // Flags like %-5d or %05d
+            let mut spec = FormatSpec::default();
+            while let Some(&flag) = format.get(i) {
+                match flag {
+                    b'-' => spec.left = true,
+                    b'0' => spec.zero = true,
+                    b'+' => spec.plus = true,
+                    b' ' => spec.space = true,
+                    b'#' => spec.alternate = true,
+                    _ => break,
+                }
+                i += 1;
+            }
+
+            // Width, either written out or taken from the arguments with *
+            if format.get(i) == Some(&b'*') {
+                i += 1;
+                let width = args.next().unwrap_or(0);
+                spec.left |= width < 0;
+                spec.width = width.unsigned_abs() as usize;
+            } else {
+                while let Some(digit) = format.get(i).filter(|b| b.is_ascii_digit()) {
+                    spec.width = spec.width * 10 + (digit - b'0') as usize;
+                    i += 1;
+                }
+            }
+
+            // Precision, like %.3s or %.*s
+            if format.get(i) == Some(&b'.') {
+                i += 1;
+                if format.get(i) == Some(&b'*') {
+                    i += 1;
+                    let precision = args.next().unwrap_or(0);
+                    spec.precision = usize::try_from(precision).ok();
+                } else {
+                    let mut precision = 0;
+                    while let Some(digit) = format.get(i).filter(|b| b.is_ascii_digit()) {
+                        precision = precision * 10 + (digit - b'0') as usize;
+                        i += 1;
+                    }
+                    spec.precision = Some(precision);
+                }
+            }
+
+            // Every VM value is 64 bits, so size modifiers like %ld change nothing
+            while matches!(format.get(i), Some(b'l' | b'h' | b'z' | b'j' | b't' | b'q' | b'L')) {
+                i += 1;
+            }
+
+            let Some(&conversion) = format.get(i) else {
+                out.push(b'%');
+                break;
+            };
+            i += 1;
+
+            match conversion {
+                b'd' | b'i' => {
+                    let value = args.next().unwrap_or(0);
+                    let sign = if value < 0 {
+                        "-"
+                    } else if spec.plus {
+                        "+"
+                    } else if spec.space {
+                        " "
+                    } else {
+                        ""
+                    };
+                    spec.number(&mut out, sign, "", value.unsigned_abs().to_string());
+                }
+                b'u' => {
+                    let value = args.next().unwrap_or(0) as u64;
+                    spec.number(&mut out, "", "", value.to_string());
+                }
+                b'x' | b'X' | b'p' => {
+                    let value = args.next().unwrap_or(0) as u64;
+                    let prefix = if conversion == b'p' || (spec.alternate && value != 0) { "0x" } else { "" };
+                    let mut digits = format!("{:x}", value);
+                    if conversion == b'X' {
+                        digits.make_ascii_uppercase();
+                    }
+                    let prefix = if conversion == b'X' { prefix.to_uppercase() } else { prefix.to_string() };
+                    spec.number(&mut out, "", &prefix, digits);
+                }
+                b'o' => {
+                    let value = args.next().unwrap_or(0) as u64;
+                    let prefix = if spec.alternate && value != 0 { "0" } else { "" };
+                    spec.number(&mut out, "", prefix, format!("{:o}", value));
+                }
+                b'c' => {
+                    let value = args.next().unwrap_or(0) as u8;
+                    spec.precision = None;
+                    spec.pad(&mut out, &[value]);
+                }
+                b's' => {
+                    let mut text = self.memory.read_cstr(args.next().unwrap_or(0))?;
+                    if let Some(precision) = spec.precision {
+                        text.truncate(precision);
+                    }
+                    spec.pad(&mut out, &text);
+                }
+                b'%' => out.push(b'%'),
+
+                // Anything else is printed the way it was written
+                other => {
+                    out.push(b'%');
+                    out.push(other);
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+// The flags, width and precision of one printf conversion like %-8.4s
+#[derive(Default)]
+struct FormatSpec {
+    left: bool,               // '-': line up on the left
+    zero: bool,               // '0': pad numbers with zeros
+    plus: bool,               // '+': always show a sign
+    space: bool,              // ' ': a space where a '+' would go
+    alternate: bool,          // '#': 0x for hex, 0 for octal
+    width: usize,             // The minimum number of characters
+    precision: Option<usize>, // Minimum digits for numbers, maximum bytes for strings
+}
+
+impl FormatSpec {
+    // Pad text with spaces up to the width
+    fn pad(&self, out: &mut Vec<u8>, text: &[u8]) {
+        let padding = self.width.saturating_sub(text.len());
+        if !self.left {
+            out.resize(out.len() + padding, b' ');
+        }
+        out.extend_from_slice(text);
+        if self.left {
+            out.resize(out.len() + padding, b' ');
+        }
+    }
+
+    // Write a number made of a sign, a prefix like 0x and its digits
+    fn number(&self, out: &mut Vec<u8>, sign: &str, prefix: &str, mut digits: String) {
+        if let Some(precision) = self.precision {
+            if precision == 0 && digits == "0" {
+                digits.clear(); // printf("%.0d", 0) prints nothing
+            }
+            while digits.len() < precision {
+                digits.insert(0, '0');
+            }
+        }
+
+        let len = sign.len() + prefix.len() + digits.len();
+        if self.zero && !self.left && self.precision.is_none() && len < self.width {
+            let zeros = "0".repeat(self.width - len);
+            out.extend_from_slice(format!("{}{}{}{}", sign, prefix, zeros, digits).as_bytes());
+        } else {
+            self.pad(out, format!("{}{}{}", sign, prefix, digits).as_bytes());
+        }
+    }
+}
+
+// A byte count from the program; negative counts are treated as 0
+fn length(n: i64) -> usize {
+    n.max(0) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{MemoryFileSystem, SharedBuffer};
+    use crate::lexer::Lexer;
+    use crate::memory::DATA_BASE;
+    use crate::parser::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Put a format string and the word "hello" into a fresh VM's data segment.
+    // Gives back the VM and the two addresses.
+    fn vm_with_strings(format: &str) -> (VM, i64, i64) {
+        let mut data = b"hello\0".to_vec();
+        data.extend_from_slice(format.as_bytes());
+        data.push(0);
+        (VM::new(Vec::new(), data, 0).unwrap(), DATA_BASE + 6, DATA_BASE)
+    }
+
+    // Compile a C4 program and run it, giving back its exit value or the fault that stopped it
+    fn run_source(source: &str) -> Result<i64, VmError> {
+        let mut parser = Parser::new(Lexer::new(source));
+        parser.parse_program().unwrap();
+        let entry = parser.entry().expect("main() not defined");
+        VM::new(parser.text, parser.data, entry).unwrap().run()
+    }
+
+    // Run hand-written text starting at 0
+    fn run_text(text: &[i64]) -> Result<i64, VmError> {
+        VM::new(text.to_vec(), Vec::new(), 0).unwrap().run()
+    }
+
+    #[test]
+    fn test_printf_formats() {
+        let (vm, format, hello) = vm_with_strings("%d|%5d|%-4d|%05d|%x|%c|%s|%.2s|%8.4s|%%");
+        let out = vm.format_printf(format, &[-42, 7, 3, 12, 255, 'A' as i64, hello, hello, hello]).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "-42|    7|3   |00012|ff|A|hello|he|    hell|%");
+    }
+
+    #[test]
+    fn test_printf_star_precision() {
+        let (vm, format, hello) = vm_with_strings("%d: %.*s");
+        let out = vm.format_printf(format, &[7, 3, hello]).unwrap();
+        assert_eq!(out, b"7: hel");
+    }
+
+    #[test]
+    fn test_heap_syscalls() {
+        let source = "
+            int main() {
+                char *p, *q;
+                p = malloc(16);
+                q = malloc(16);
+                memset(q, 'x', 16);
+                return memcmp(p, q, 4); // p is still zeroed, q is all 'x'
+            }";
+        assert_eq!(run_source(source), Ok(-120));
+    }
+
+    #[test]
+    fn test_load_and_store() {
+        let source = "
+            int main() {
+                char *c; int *i;
+                c = malloc(8); i = malloc(8);
+                *i = -7;
+                *c = 300; // doesn't fit in a char
+                return *i + *c;
+            }";
+        assert_eq!(run_source(source), Ok(37));
+    }
+
+    #[test]
+    fn test_calls_and_locals() {
+        let source = "
+            int fib(int n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+            int main() { int i, sum; i = 0; sum = 0; while (i <= 10) sum = sum + fib(i++); return sum; }";
+        assert_eq!(run_source(source), Ok(143));
+    }
+
+    #[test]
+    fn test_trace_only_logs_the_chosen_function() {
+        let source = "int sq(int x) { return x * x; } int main() { return sq(3) + sq(4); }";
+        let mut parser = Parser::new(Lexer::new(source));
+        parser.parse_program().unwrap();
+        let path = std::env::temp_dir().join(format!("c4_trace_test_{}.txt", std::process::id()));
+        let mut trace = Trace::to_file(path.to_str().unwrap()).unwrap();
+        trace.only = parser.function_range("sq");
+        trace.stack_slots = 1;
+
+        let entry = parser.entry().unwrap();
+        let mut vm = VM::new(parser.text, parser.data, entry).unwrap();
+        vm.trace = Some(trace);
+        assert_eq!(vm.run(), Ok(25));
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 16); // sq is 8 instructions long and is called twice
+        assert!(lines[0].contains("pc=0      ENT  0"));
+        assert!(lines[6].contains("MUL") && lines[6].contains("a=3 ") && lines[6].ends_with("stack=[3]"));
+    }
+
+    #[test]
+    fn test_step_and_run_for() {
+        let mut parser = Parser::new(Lexer::new("int main() { int i; i = 0; while (i < 10) i++; return i; }"));
+        parser.parse_program().unwrap();
+        let entry = parser.entry().unwrap();
+        let mut vm = VM::new(parser.text, parser.data, entry).unwrap();
+
+        assert_eq!(vm.step(), VmState::Running);
+        assert_eq!(vm.cycle, 1);
+        assert_eq!(vm.run_for(5), VmState::Running);
+        assert_eq!(vm.cycle, 6);
+        assert_eq!(vm.run_until(|vm| vm.a == 5), VmState::Running);
+        assert_eq!(vm.a, 5);
+        assert_eq!(vm.run_for(1000), VmState::Halted(10));
+        assert_eq!(vm.step(), VmState::Halted(10)); // stays halted
+        assert_eq!(vm.state(), VmState::Halted(10));
+    }
+
+    #[test]
+    fn test_waiting_for_input() {
+        let source = "
+            int main() {
+                char *buf; int n, total;
+                buf = malloc(8); total = 0;
+                while ((n = read(0, buf, 8)) > 0) total = total + n;
+                return total;
+            }";
+        let mut parser = Parser::new(Lexer::new(source));
+        parser.parse_program().unwrap();
+        let entry = parser.entry().unwrap();
+        let mut vm = VM::new(parser.text, parser.data, entry).unwrap();
+
+        vm.feed_input(b"hello");
+        assert_eq!(vm.run_until(|_| false), VmState::WaitingForInput);
+        let cycle = vm.cycle;
+        assert_eq!(vm.step(), VmState::WaitingForInput); // nothing runs until more input comes
+        assert_eq!(vm.cycle, cycle);
+
+        vm.feed_input(b"world!!!!");
+        assert_eq!(vm.run_until(|_| false), VmState::WaitingForInput);
+        vm.close_input();
+        assert_eq!(vm.run_until(|_| false), VmState::Halted(14));
+    }
+
+    #[test]
+    fn test_faulted_state() {
+        let mut vm = VM::new(vec![Op::Imm.code(), 1, Op::Psh.code(), Op::Imm.code(), 0, Op::Div.code()], Vec::new(), 0).unwrap();
+        let state = vm.run_for(10);
+        assert!(matches!(state, VmState::Faulted(VmError { kind: VmErrorKind::DivisionByZero, pc: 5, .. })));
+        assert_eq!(vm.step(), state);
+    }
+
+    // Run source under limits and give back the error kind it stopped with.
+    // It sees a file system in memory that only has notes.txt.
+    fn run_limited(source: &str, limits: Limits) -> Result<i64, VmErrorKind> {
+        let mut parser = Parser::new(Lexer::new(source));
+        parser.parse_program().unwrap();
+        let entry = parser.entry().unwrap();
+        let mut vm = VM::new(parser.text, parser.data, entry).unwrap();
+        let files = MemoryFileSystem::new();
+        files.add_file("notes.txt", b"notes");
+        vm.file_system = Box::new(files);
+        vm.limits = limits;
+        vm.run().map_err(|err| err.kind)
+    }
+
+    #[test]
+    fn test_limits() {
+        let forever = "int main() { while (1); return 0; }";
+        let limits = Limits { max_cycles: Some(1000), ..Limits::default() };
+        assert_eq!(run_limited(forever, limits), Err(VmErrorKind::CycleLimit { limit: 1000 }));
+
+        let deep = "int f(int n) { if (n) return f(n - 1); return 0; } int main() { return f(50); }";
+        let limits = Limits { max_call_depth: Some(20), ..Limits::default() };
+        assert_eq!(run_limited(deep, limits), Err(VmErrorKind::CallDepthLimit { limit: 20 }));
+        let limits = Limits { max_call_depth: Some(51), ..Limits::default() };
+        assert_eq!(run_limited(deep, limits), Ok(0));
+
+        let greedy = "int main() { while (malloc(1000)); return 0; }";
+        let limits = Limits { max_heap_bytes: Some(10_000), ..Limits::default() };
+        assert_eq!(run_limited(greedy, limits), Err(VmErrorKind::HeapLimit { limit: 10_000 }));
+
+        let files = "int main() { while (open(\"notes.txt\", 0) >= 0); return 0; }";
+        let limits = Limits { max_open_files: Some(3), ..Limits::default() };
+        assert_eq!(run_limited(files, limits), Err(VmErrorKind::OpenFileLimit { limit: 3 }));
+    }
+
+    #[test]
+    fn test_redirected_io() {
+        let source = "
+            int main() {
+                char *buf; int fd, n;
+                buf = malloc(32);
+                fd = open(\"notes.txt\", 0);
+                n = read(fd, buf, 31); buf[n] = 0;
+                close(fd);
+                printf(\"file: %s\\n\", buf);
+                n = read(0, buf, 31); buf[n] = 0;
+                printf(\"stdin: %s\\n\", buf);
+                return open(\"missing.txt\", 0);
+            }";
+        let mut parser = Parser::new(Lexer::new(source));
+        parser.parse_program().unwrap();
+        let entry = parser.entry().unwrap();
+        let mut vm = VM::new(parser.text, parser.data, entry).unwrap();
+
+        let files = MemoryFileSystem::new();
+        files.add_file("notes.txt", b"from memory");
+        let output = SharedBuffer::new();
+        vm.file_system = Box::new(files);
+        vm.output = Box::new(output.clone());
+        vm.input = Box::new(&b"typed in"[..]);
+
+        assert_eq!(vm.run(), Ok(-1));
+        assert_eq!(output.text(), "file: from memory\nstdin: typed in\n");
+    }
+
+    #[test]
+    fn test_host_functions() {
+        let logged = Rc::new(RefCell::new(Vec::new()));
+        let mut host = HostFunctions::new();
+        let log = logged.clone();
+        host.register("log", 2, move |memory, args| {
+            let text = String::from_utf8_lossy(&memory.read_cstr(args[0])?).into_owned();
+            log.borrow_mut().push(format!("{} {}", text, args[1]));
+            Ok(0)
+        });
+        host.register("clamp", 3, |_, args| Ok(args[0].clamp(args[1], args[2])));
+
+        let source = "int main() { log(\"start\", 1); return clamp(500, 0, 255) + clamp(-4, 0, 255); }";
+        let mut parser = Parser::new(Lexer::new(source));
+        parser.declare_host_functions(&host);
+        parser.parse_program().unwrap();
+        let entry = parser.entry().unwrap();
+        let mut vm = VM::new(parser.text, parser.data, entry).unwrap();
+        vm.host_functions = host;
+
+        assert_eq!(vm.run(), Ok(255));
+        assert_eq!(*logged.borrow(), ["start 1"]);
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(run_source("int main() { return 42; }"), Ok(42));
+        let source = "int quit(int code) { exit(code); return 0; } int main() { quit(3); return 1; }";
+        assert_eq!(run_source(source), Ok(3));
+    }
+
+    #[test]
+    fn test_main_arguments() {
+        let source = "
+            int main(int argc, char **argv) {
+                char *s; s = argv[2];
+                while (*s) s++;
+                return argc * 100 + (s - argv[2]) * 10 + (argv[argc] == 0);
+            }";
+        let mut parser = Parser::new(Lexer::new(source));
+        parser.parse_program().unwrap();
+        let entry = parser.entry().unwrap();
+        let mut vm = VM::new(parser.text, parser.data, entry).unwrap();
+        let args: Vec<String> = ["prog.c", "x", "hello"].iter().map(|s| s.to_string()).collect();
+        vm.set_args(&args).unwrap();
+        assert_eq!(vm.run(), Ok(351)); // 3 arguments, "hello" is 5 long, argv ends with NULL
+    }
+
+    #[test]
+    fn test_stack_too_small_to_start() {
+        // argv's NULL, argc, argv and the return address need 32 bytes
+        let config = MemoryConfig { stack_size: 16, ..MemoryConfig::default() };
+        let err = VM::with_config(vec![Op::Lev.code()], Vec::new(), 0, config).err().unwrap();
+        assert_eq!(err.kind, VmErrorKind::StackOverflow);
+    }
+
+    #[test]
+    fn test_null_access_faults() {
+        let err = run_source("int main() { memset(0, 0, 8); return 0; }").unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::InvalidMemoryAccess { address: 0, len: 8 });
+        assert_eq!(err.code, Some(Op::Mset.code()));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let err = run_source("int main() { int z; z = 0; return 7 / z; }").unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::DivisionByZero);
+        assert_eq!(err.to_string(), format!("division by zero at pc {} (DIV)", err.pc));
+    }
+
+    #[test]
+    fn test_runaway_recursion_overflows_stack() {
+        let err = run_source("int f(int n) { return f(n + 1); } int main() { return f(0); }").unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::StackOverflow);
+    }
+
+    #[test]
+    fn test_bad_text() {
+        let lev = Op::Lev.code();
+        let underflow = run_text(&[lev, lev, lev]).unwrap_err(); // return from more calls than were made
+        assert_eq!(underflow.kind, VmErrorKind::StackUnderflow);
+
+        let jump = run_text(&[Op::Jmp.code(), 99]).unwrap_err();
+        assert_eq!((jump.kind, jump.pc), (VmErrorKind::BadJump { target: 99 }, 0));
+
+        assert_eq!(run_text(&[-3]).unwrap_err().kind, VmErrorKind::UnknownInstruction);
+        assert_eq!(run_text(&[200]).unwrap_err().kind, VmErrorKind::UnknownSyscall);
+    }
+}